@@ -298,6 +298,19 @@ fn main() {
         cmd.arg("--cfg").arg("parallel_queries");
     }
 
+    if env::var_os("RUSTC_STRICT_SYNC_CHECK").is_some() {
+        cmd.arg("--cfg").arg("strict_sync_check");
+        // The strict sync check adds `Send` and `Sync` impls which are unsound, so
+        // rustc_data_structures refuses to build with it unless only metadata is emitted
+        // (`x.py check`).
+        let metadata_only = args.iter().any(|arg| {
+            arg.to_str().map_or(false, |arg| arg.starts_with("--emit=") && !arg.contains("link"))
+        });
+        if metadata_only {
+            cmd.arg("--cfg").arg("strict_sync_check_metadata_only");
+        }
+    }
+
     if env::var_os("RUSTC_DENY_WARNINGS").is_some() && env::var_os("RUSTC_EXTERNAL_TOOL").is_none()
     {
         cmd.arg("-Dwarnings");
@@ -1143,6 +1143,10 @@ impl Step for Compiletest {
             cmd.arg("--quiet");
         }
 
+        if builder.config.rustc_parallel_queries {
+            cmd.arg("--parallel-queries");
+        }
+
         if builder.config.llvm_enabled {
             let llvm_config = builder.ensure(native::Llvm {
                 target: builder.config.build,
@@ -24,12 +24,18 @@
 #![feature(unboxed_closures)]
 #![feature(fn_traits)]
 #![feature(unsize)]
+#![feature(coerce_unsized)]
 #![feature(specialization)]
 #![feature(optin_builtin_traits)]
 #![feature(nll)]
 #![feature(allow_internal_unstable)]
 #![feature(vec_resize_with)]
 #![feature(hash_raw_entry)]
+#![feature(integer_atomics)]
+#![feature(atomic_min_max)]
+#![feature(const_vec_new)]
+#![feature(once_is_completed)]
+#![cfg_attr(feature = "lock-metrics", feature(core_intrinsics))]
 
 #![cfg_attr(unix, feature(libc))]
 #![cfg_attr(test, feature(test))]
@@ -42,6 +48,7 @@ extern crate serialize as rustc_serialize; // used by deriving
 #[cfg(unix)]
 extern crate libc;
 extern crate parking_lot;
+extern crate lock_api;
 #[macro_use]
 extern crate cfg_if;
 extern crate stable_deref_trait;
@@ -51,6 +58,15 @@ extern crate rustc_hash;
 extern crate serialize;
 extern crate graphviz;
 extern crate smallvec;
+#[cfg(feature = "lock-error-checking")]
+extern crate backtrace;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 // See librustc_cratesio_shim/Cargo.toml for a comment explaining this.
 #[allow(unused_extern_crates)]
@@ -58,7 +74,7 @@ extern crate rustc_cratesio_shim;
 
 pub use rustc_serialize::hex::ToHex;
 
-pub mod macros;
+#[macro_use] pub mod macros;
 pub mod svh;
 pub mod base_n;
 pub mod bit_set;
@@ -102,6 +118,21 @@ impl<F: Fn()> Drop for OnDrop<F> {
       }
 }
 
+/// Aborts the process if it is dropped while the thread is panicking. Keeping one alive
+/// during a critical section turns a panic which would leave some invariant half-repaired
+/// into an abort. The string describes the critical section in the message printed before
+/// aborting. Dropping it without panicking does nothing.
+pub struct AbortOnDrop(pub &'static str);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!("fatal error: panicked in the critical section `{}`, aborting", self.0);
+            std::process::abort();
+        }
+    }
+}
+
 // See comments in src/librustc/lib.rs
 #[doc(hidden)]
 pub fn __noop_fix_for_27438() {}
@@ -20,3 +20,27 @@ macro_rules! static_assert {
         static $name: () = [()][!($test: bool) as usize];
     }
 }
+
+/// Asserts at compile time that a type implements `sync::Send`, so without
+/// cfg!(parallel_queries) this is always true. Unlike `sync::assert_send_val` this
+/// can be used at item scope and doesn't depend on the code being executed.
+#[macro_export]
+#[allow_internal_unstable]
+macro_rules! static_assert_send {
+    ($ty:ty) => {
+        #[allow(dead_code)]
+        const _: fn() = $crate::sync::assert_send::<$ty>;
+    }
+}
+
+/// Asserts at compile time that a type implements `sync::Sync`, so without
+/// cfg!(parallel_queries) this is always true. Unlike `sync::assert_sync` this
+/// can be used at item scope.
+#[macro_export]
+#[allow_internal_unstable]
+macro_rules! static_assert_sync {
+    ($ty:ty) => {
+        #[allow(dead_code)]
+        const _: fn() = $crate::sync::assert_sync::<$ty>;
+    }
+}
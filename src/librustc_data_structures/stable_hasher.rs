@@ -353,6 +353,53 @@ impl<T: ?Sized + HashStable<CTX>, CTX> HashStable<CTX> for ::std::sync::Arc<T> {
     }
 }
 
+// The locks of the `sync` module hash the value they contain, never their own state, so
+// equal values hash the same no matter which lock instance holds them. The lock is held
+// while the value is hashed, so the value must not lead back to the same lock.
+
+impl<T: HashStable<CTX>, CTX> HashStable<CTX> for ::sync::Lock<T> {
+    #[inline]
+    fn hash_stable<W: StableHasherResult>(&self,
+                                          ctx: &mut CTX,
+                                          hasher: &mut StableHasher<W>) {
+        self.lock().hash_stable(ctx, hasher);
+    }
+}
+
+impl<T: HashStable<CTX>, CTX> HashStable<CTX> for ::sync::RwLock<T> {
+    #[inline]
+    fn hash_stable<W: StableHasherResult>(&self,
+                                          ctx: &mut CTX,
+                                          hasher: &mut StableHasher<W>) {
+        self.read().hash_stable(ctx, hasher);
+    }
+}
+
+impl<T: HashStable<CTX>, CTX> HashStable<CTX> for ::sync::MTLock<T> {
+    #[inline]
+    fn hash_stable<W: StableHasherResult>(&self,
+                                          ctx: &mut CTX,
+                                          hasher: &mut StableHasher<W>) {
+        (*self.lock()).hash_stable(ctx, hasher);
+    }
+}
+
+/// Hashes the same as `Option<T>`, where `None` is an uninitialized value.
+impl<T: HashStable<CTX>, CTX> HashStable<CTX> for ::sync::Once<T> {
+    #[inline]
+    fn hash_stable<W: StableHasherResult>(&self,
+                                          ctx: &mut CTX,
+                                          hasher: &mut StableHasher<W>) {
+        self.try_get().hash_stable(ctx, hasher);
+    }
+}
+
+// There is no impl for `sync::WorkerLocal`. A stable hash would have to hash the values of all
+// workers in worker-index order, but with parallel_queries rayon's `WorkerLocal` only hands out
+// the value of the current worker by reference. Hashing that value alone would make the hash
+// depend on which worker does the hashing. Hash the `Vec` returned by `into_inner` instead; it
+// is in worker-index order, so that hash is only stable for a fixed number of threads.
+
 impl<CTX> HashStable<CTX> for str {
     #[inline]
     fn hash_stable<W: StableHasherResult>(&self,
@@ -0,0 +1,379 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A cache of values which are computed at most once, with waiting for values which another
+//! thread is computing, like the active queries of the query engine.
+//!
+//! `ActiveCache::probe` returns the value of a key if it is cached. Otherwise the first caller
+//! claims the key and gets a `ClaimToken`, which publishes the value when it is completed. Other
+//! callers get a `Wait` instead, which blocks until the claimant is done. If the claimant
+//! panics or otherwise drops its token, the key is poisoned.
+//!
+//! Waiting threads are recorded in a waiter graph, so `Wait::wait` returns a `CycleError`
+//! instead of deadlocking if the claimant is waiting, possibly through other threads, for a key
+//! claimed by the current thread. Claims are owned by the thread which made them, even if the
+//! token is moved to another thread. Only cycles within one cache are detected.
+//!
+//! The entries are kept in a `ShardedHashMap`, so threads probing different keys rarely
+//! contend. Without cfg!(parallel_queries) there is a single shard and waiting for a key which
+//! isn't done is always a cycle, since the only thread is the claimant.
+
+use std::hash::Hash;
+use std::thread::{self, ThreadId};
+use fx::FxHashMap;
+use parking_lot::Mutex;
+#[cfg(parallel_queries)]
+use parking_lot::Condvar;
+use super::{Lock, Lrc, ShardedHashMap};
+
+/// The result of `ActiveCache::probe`.
+pub enum Probe<'a, K: 'a + Eq + Hash + Clone, V: 'a + Clone> {
+    /// The value was already computed.
+    Hit(V),
+    /// The caller has to compute the value and pass it to `ClaimToken::complete`.
+    Claimed(ClaimToken<'a, K, V>),
+    /// Another caller claimed the key and hasn't completed it yet.
+    InProgress(Wait<'a, K, V>),
+    /// The claimant of the key dropped its token without completing it.
+    Poisoned,
+}
+
+/// Returned by `Wait::wait` if waiting would deadlock. `keys` starts with the awaited key and
+/// each following key is awaited by the claimant of the previous one. The current thread
+/// claimed the last key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleError<K> {
+    pub keys: Vec<K>,
+}
+
+/// Returned by `Wait::wait` if the value will never be available.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WaitError<K> {
+    Cycle(CycleError<K>),
+    Poisoned,
+}
+
+enum JobState<V> {
+    Running,
+    Done(V),
+    Poisoned,
+}
+
+/// A claimed key, shared by the claimant and the waiters.
+struct Job<K, V> {
+    key: K,
+    owner: ThreadId,
+    state: Mutex<JobState<V>>,
+    #[cfg(parallel_queries)]
+    done: Condvar,
+}
+
+impl<K, V> Job<K, V> {
+    fn is_running(&self) -> bool {
+        match *self.state.lock() {
+            JobState::Running => true,
+            _ => false,
+        }
+    }
+
+    fn finish(&self, state: JobState<V>) {
+        *self.state.lock() = state;
+        #[cfg(parallel_queries)]
+        self.done.notify_all();
+    }
+}
+
+enum Entry<K, V> {
+    Running(Lrc<Job<K, V>>),
+    Done(V),
+    Poisoned,
+}
+
+pub struct ActiveCache<K, V> {
+    entries: ShardedHashMap<K, Entry<K, V>>,
+    /// The waiter graph. Maps each waiting thread to the job it waits for. The owners of the
+    /// jobs are the edges to the next threads.
+    waiting: Lock<FxHashMap<ThreadId, Lrc<Job<K, V>>>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> ActiveCache<K, V> {
+    pub fn new() -> Self {
+        ActiveCache {
+            entries: ShardedHashMap::named("ActiveCache::shards", FxHashMap::default),
+            waiting: Lock::named("ActiveCache::waiting", FxHashMap::default()),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Lock<FxHashMap<K, Entry<K, V>>> {
+        self.entries.get_shard_by_value(key)
+    }
+
+    /// Looks up `key`, claiming it if nobody did yet.
+    pub fn probe(&self, key: &K) -> Probe<K, V> {
+        let mut shard = self.shard(key).lock();
+        if let Some(entry) = shard.get(key) {
+            return match *entry {
+                Entry::Done(ref value) => Probe::Hit(value.clone()),
+                Entry::Running(ref job) => Probe::InProgress(Wait {
+                    cache: self,
+                    job: job.clone(),
+                }),
+                Entry::Poisoned => Probe::Poisoned,
+            };
+        }
+        let job = Lrc::new(Job {
+            key: key.clone(),
+            owner: thread::current().id(),
+            state: Mutex::new(JobState::Running),
+            #[cfg(parallel_queries)]
+            done: Condvar::new(),
+        });
+        shard.insert(key.clone(), Entry::Running(job.clone()));
+        Probe::Claimed(ClaimToken {
+            cache: self,
+            job: Some(job),
+        })
+    }
+
+    /// Returns the cycle which waiting for `job` in the current thread would close, if any.
+    /// `waiting` must be the locked waiter graph.
+    fn find_cycle(waiting: &FxHashMap<ThreadId, Lrc<Job<K, V>>>,
+                  job: &Job<K, V>) -> Option<CycleError<K>> {
+        let current = thread::current().id();
+        let mut keys = vec![job.key.clone()];
+        let mut owner = job.owner;
+        // The graph has no cycles, since we never add an edge closing one, so this terminates.
+        // Jobs which are done are ignored, since their waiters are about to wake up.
+        loop {
+            if owner == current {
+                return Some(CycleError { keys });
+            }
+            match waiting.get(&owner) {
+                Some(next) if next.is_running() => {
+                    keys.push(next.key.clone());
+                    owner = next.owner;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Default for ActiveCache<K, V> {
+    fn default() -> Self {
+        ActiveCache::new()
+    }
+}
+
+/// The claim of a key, which the owner has to complete. Dropping it poisons the key.
+pub struct ClaimToken<'a, K: 'a + Eq + Hash + Clone, V: 'a + Clone> {
+    cache: &'a ActiveCache<K, V>,
+    /// Only `None` once the token is completed.
+    job: Option<Lrc<Job<K, V>>>,
+}
+
+impl<'a, K: Eq + Hash + Clone, V: Clone> ClaimToken<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.job.as_ref().unwrap().key
+    }
+
+    /// Publishes the value of the key and wakes up the threads waiting for it.
+    pub fn complete(mut self, value: V) {
+        let job = self.job.take().unwrap();
+        self.cache.shard(&job.key).lock().insert(job.key.clone(), Entry::Done(value.clone()));
+        job.finish(JobState::Done(value));
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone, V: Clone> Drop for ClaimToken<'a, K, V> {
+    fn drop(&mut self) {
+        if let Some(job) = self.job.take() {
+            self.cache.shard(&job.key).lock().insert(job.key.clone(), Entry::Poisoned);
+            job.finish(JobState::Poisoned);
+        }
+    }
+}
+
+/// A key which another caller is computing.
+pub struct Wait<'a, K: 'a, V: 'a> {
+    cache: &'a ActiveCache<K, V>,
+    job: Lrc<Job<K, V>>,
+}
+
+impl<'a, K: Eq + Hash + Clone, V: Clone> Wait<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.job.key
+    }
+
+    /// Blocks until the claimant completes the key and returns its value.
+    pub fn wait(self) -> Result<V, WaitError<K>> {
+        {
+            let mut waiting = self.cache.waiting.lock();
+            // Checked with the graph locked, so no edges for finished jobs can be followed.
+            if self.job.is_running() {
+                if let Some(cycle) = ActiveCache::find_cycle(&waiting, &self.job) {
+                    return Err(WaitError::Cycle(cycle));
+                }
+                waiting.insert(thread::current().id(), self.job.clone());
+            }
+        }
+        let result = self.block();
+        self.cache.waiting.lock().remove(&thread::current().id());
+        result
+    }
+
+    #[cfg(parallel_queries)]
+    fn block(&self) -> Result<V, WaitError<K>> {
+        let mut state = self.job.state.lock();
+        loop {
+            match *state {
+                JobState::Running => self.job.done.wait(&mut state),
+                JobState::Done(ref value) => return Ok(value.clone()),
+                JobState::Poisoned => return Err(WaitError::Poisoned),
+            }
+        }
+    }
+
+    #[cfg(not(parallel_queries))]
+    fn block(&self) -> Result<V, WaitError<K>> {
+        match *self.job.state.lock() {
+            JobState::Running => unreachable!("waiting for a job in the thread running it"),
+            JobState::Done(ref value) => Ok(value.clone()),
+            JobState::Poisoned => Err(WaitError::Poisoned),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    fn claim<'a>(cache: &'a ActiveCache<u32, String>, key: u32) -> ClaimToken<'a, u32, String> {
+        match cache.probe(&key) {
+            Probe::Claimed(token) => token,
+            _ => panic!("{} was already claimed", key),
+        }
+    }
+
+    fn wait_for(cache: &ActiveCache<u32, String>, key: u32) -> Result<String, WaitError<u32>> {
+        match cache.probe(&key) {
+            Probe::Hit(value) => Ok(value),
+            Probe::InProgress(wait) => wait.wait(),
+            Probe::Claimed(_) => panic!("{} wasn't claimed", key),
+            Probe::Poisoned => Err(WaitError::Poisoned),
+        }
+    }
+
+    #[test]
+    fn test_claim_and_hit() {
+        let cache = ActiveCache::new();
+        let token = claim(&cache, 1);
+        assert_eq!(*token.key(), 1);
+        token.complete("one".to_string());
+        match cache.probe(&1) {
+            Probe::Hit(value) => assert_eq!(value, "one"),
+            _ => panic!("1 should be cached"),
+        }
+        // Other keys are independent.
+        claim(&cache, 2).complete("two".to_string());
+        assert_eq!(wait_for(&cache, 1), Ok("one".to_string()));
+    }
+
+    #[test]
+    fn test_wait_for_own_claim() {
+        let cache = ActiveCache::new();
+        let token = claim(&cache, 1);
+        assert_eq!(wait_for(&cache, 1), Err(WaitError::Cycle(CycleError { keys: vec![1] })));
+        token.complete("one".to_string());
+        assert_eq!(wait_for(&cache, 1), Ok("one".to_string()));
+    }
+
+    #[test]
+    fn test_poison() {
+        let cache: ActiveCache<u32, String> = ActiveCache::new();
+        let wait = match cache.probe(&1) {
+            Probe::Claimed(token) => {
+                let wait = match cache.probe(&1) {
+                    Probe::InProgress(wait) => wait,
+                    _ => panic!("1 should be in progress"),
+                };
+                drop(token);
+                wait
+            }
+            _ => panic!("1 should be claimed"),
+        };
+        assert_eq!(wait.wait(), Err(WaitError::Poisoned));
+        match cache.probe(&1) {
+            Probe::Poisoned => {}
+            _ => panic!("1 should be poisoned"),
+        };
+    }
+
+    #[test]
+    fn test_panicking_claimant_poisons() {
+        let cache = ActiveCache::new();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _token = claim(&cache, 1);
+            panic!("computing 1 failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(wait_for(&cache, 1), Err(WaitError::Poisoned));
+        // Only the key of the dropped token is poisoned.
+        claim(&cache, 2).complete("two".to_string());
+        assert_eq!(wait_for(&cache, 2), Ok("two".to_string()));
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_wait_for_other_thread() {
+        use std::time::Duration;
+
+        let cache = Lrc::new(ActiveCache::new());
+        let token = claim(&cache, 1);
+        let waiter = {
+            let cache = cache.clone();
+            thread::spawn(move || wait_for(&cache, 1))
+        };
+        thread::sleep(Duration::from_millis(20));
+        token.complete("one".to_string());
+        assert_eq!(waiter.join().unwrap(), Ok("one".to_string()));
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_cycle_between_threads() {
+        use std::sync::Barrier;
+
+        let cache = Lrc::new(ActiveCache::new());
+        let barrier = Lrc::new(Barrier::new(2));
+        let threads: Vec<_> = [(1, 2), (2, 1)].iter().map(|&(own, other)| {
+            let cache = cache.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let token = claim(&cache, own);
+                barrier.wait();
+                let result = wait_for(&cache, other);
+                // Completing our key wakes up the other thread if it is waiting for us.
+                token.complete(own.to_string());
+                result
+            })
+        }).collect();
+        let mut results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        results.sort_by_key(|result| result.is_ok());
+        match results[0] {
+            Err(WaitError::Cycle(ref cycle)) => {
+                assert!(cycle.keys == [1, 2] || cycle.keys == [2, 1], "{:?}", cycle);
+            }
+            ref result => panic!("expected a cycle, got {:?}", result),
+        }
+        assert!(results[1].is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The atomic audit mode, enabled by the `atomic-audit` feature.
+//!
+//! In the serial build the atomics are `Cell`s, so a read-modify-write which is split into a
+//! `load` and a `store` works fine until it runs in the parallel build, where another thread
+//! can update the atomic in between and the update is lost. The audit mode looks for this
+//! pattern in the serial build. The closures passed to `join`, `scope` and the `par_*` helpers
+//! are considered to be separate tasks, which would run in parallel with cfg!(parallel_queries).
+//! A `store` to an atomic which was loaded by the same task, with no read-modify-write
+//! operation on it in between, emits a diagnostic mentioning where the atomic was created.
+//!
+//! This is a heuristic. It only finds the pattern inside of the closures of parallel regions
+//! and it can't tell whether the stored value was actually computed from the loaded one.
+//! The audit mode does nothing in the parallel build.
+
+cfg_if! {
+    if #[cfg(all(feature = "atomic-audit", not(parallel_queries)))] {
+        use std::cell::Cell;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use sync::Location;
+        use sync::instrument::emit_diagnostic;
+
+        /// The task which is currently running on this thread, or 0 outside of parallel regions.
+        thread_local!(static CURRENT_TASK: Cell<usize> = Cell::new(0));
+
+        static NEXT_TASK: AtomicUsize = AtomicUsize::new(1);
+
+        fn current_task() -> usize {
+            CURRENT_TASK.with(|task| task.get())
+        }
+
+        /// Restores the task which was running before a task started.
+        struct TaskScope(usize);
+
+        impl Drop for TaskScope {
+            fn drop(&mut self) {
+                CURRENT_TASK.with(|task| task.set(self.0));
+            }
+        }
+
+        /// Runs `f` as a separate task of a parallel region.
+        #[inline]
+        pub fn task<R, F: FnOnce() -> R>(f: F) -> R {
+            let id = NEXT_TASK.fetch_add(1, Ordering::Relaxed);
+            let _scope = TaskScope(CURRENT_TASK.with(|task| task.replace(id)));
+            f()
+        }
+
+        /// The audit state of a single atomic.
+        #[derive(Debug)]
+        pub struct AtomicAudit {
+            created_at: Option<Location>,
+            /// The task which loaded the atomic since its last store or
+            /// read-modify-write operation.
+            loaded_by: Cell<usize>,
+            /// Whether a diagnostic was already emitted for the atomic.
+            reported: Cell<bool>,
+        }
+
+        impl AtomicAudit {
+            pub fn new(created_at: Option<Location>) -> Self {
+                AtomicAudit {
+                    created_at,
+                    loaded_by: Cell::new(0),
+                    reported: Cell::new(false),
+                }
+            }
+
+            pub fn load(&self) {
+                self.loaded_by.set(current_task());
+            }
+
+            pub fn store(&self) {
+                let task = current_task();
+                if task != 0 && self.loaded_by.get() == task && !self.reported.replace(true) {
+                    let location = match self.created_at {
+                        Some(location) => location.to_string(),
+                        None => "<unknown location>".to_string(),
+                    };
+                    emit_diagnostic(&format!("possible lost update of the atomic created at {}: \
+                                              it was loaded and then stored by the same parallel \
+                                              task, which is not atomic if \
+                                              cfg!(parallel_queries) is true; use `fetch_add`, \
+                                              `swap` or `compare_exchange` instead",
+                                             location));
+                }
+                self.loaded_by.set(0);
+            }
+
+            pub fn read_modify_write(&self) {
+                self.loaded_by.set(0);
+            }
+        }
+    } else {
+        #[inline(always)]
+        pub fn task<R, F: FnOnce() -> R>(f: F) -> R {
+            f()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "atomic-audit", not(parallel_queries)))]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use sync::{join, AtomicAt, AtomicUsize};
+    use sync::instrument::test_sink::captured;
+
+    #[test]
+    fn test_split_update_is_reported() {
+        captured("");
+        let counter = AtomicUsize::new_at(0, sync_location!());
+        let location = format!("created at {}:{}:", file!(), line!() - 1);
+        let increment = || {
+            let value = counter.load(Ordering::SeqCst);
+            counter.store(value + 1, Ordering::SeqCst);
+        };
+        join(increment, increment);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        let reports = captured(&location);
+        assert_eq!(reports.len(), 1, "{:?}", reports);
+        assert!(reports[0].starts_with("possible lost update"), "{}", reports[0]);
+    }
+
+    #[test]
+    fn test_fetch_add_is_not_reported() {
+        captured("");
+        let counter = AtomicUsize::new_at(0, sync_location!());
+        let location = format!("created at {}:{}:", file!(), line!() - 1);
+        let increment = || {
+            if counter.load(Ordering::SeqCst) < 10 {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        join(increment, increment);
+        // Outside of parallel regions the atomic is only used by a single thread.
+        let value = counter.load(Ordering::SeqCst);
+        counter.store(value * 2, Ordering::SeqCst);
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+
+        assert!(captured(&location).is_empty());
+    }
+}
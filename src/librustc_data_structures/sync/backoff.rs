@@ -0,0 +1,141 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Exponential backoff for spin loops, like the one of crossbeam.
+//!
+//! `Backoff::snooze` first spins for an exponentially growing number of spin loop hints, then
+//! yields the thread. Once `is_completed` returns true, the waiting thread should block instead,
+//! e.g. on a `Lock`, since the other thread is likely not running. Spinning a fixed number of
+//! times instead wastes the time of the other hardware thread of the core on SMT machines.
+//!
+//! Without cfg!(parallel_queries) no other thread can make progress while we spin, so a spin
+//! loop which doesn't complete immediately is a bug and `snooze` panics.
+
+use std::cell::Cell;
+#[cfg(parallel_queries)]
+use std::sync::atomic;
+#[cfg(parallel_queries)]
+use std::thread;
+
+/// The last step which spins instead of yielding. It spins `1 << SPIN_LIMIT` times.
+#[cfg(parallel_queries)]
+const SPIN_LIMIT: u32 = 6;
+/// The last step of `snooze`. After it `is_completed` returns true.
+const YIELD_LIMIT: u32 = 10;
+
+/// The state of a spin loop.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: Cell<u32>,
+}
+
+impl Backoff {
+    #[inline]
+    pub fn new() -> Self {
+        Backoff { step: Cell::new(0) }
+    }
+
+    /// Restarts the backoff, e.g. after the loop made progress.
+    #[inline]
+    pub fn reset(&self) {
+        self.step.set(0);
+    }
+
+    /// Waits before the next iteration of a spin loop, for longer on every call.
+    #[cfg(parallel_queries)]
+    #[inline]
+    pub fn snooze(&self) {
+        let step = self.step.get();
+        if step <= SPIN_LIMIT {
+            for _ in 0..1 << step {
+                atomic::spin_loop_hint();
+            }
+        } else {
+            thread::yield_now();
+        }
+        if step <= YIELD_LIMIT {
+            self.step.set(step + 1);
+        }
+    }
+
+    /// Waits before the next iteration of a spin loop, for longer on every call.
+    #[cfg(not(parallel_queries))]
+    #[inline]
+    pub fn snooze(&self) {
+        panic!("spinning without parallel_queries, nothing else can make progress");
+    }
+
+    /// Returns whether spinning any longer is pointless, so the caller should block instead.
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.step.get() > YIELD_LIMIT
+    }
+}
+
+/// Calls `pred` until it returns true, at most `max_spins + 1` times, and snoozes in between.
+/// Returns the last result of `pred`, so `false` means that the caller should block instead.
+/// Without cfg!(parallel_queries) this panics if `pred` returns false and `max_spins` is not 0.
+#[inline]
+pub fn spin_until<F: FnMut() -> bool>(mut pred: F, max_spins: u32) -> bool {
+    let backoff = Backoff::new();
+    for _ in 0..max_spins {
+        if pred() {
+            return true;
+        }
+        backoff.snooze();
+    }
+    pred()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spin_until_completes_immediately() {
+        assert!(spin_until(|| true, 10));
+        assert!(!spin_until(|| false, 0));
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_escalation() {
+        let backoff = Backoff::new();
+        for _ in 0..YIELD_LIMIT + 1 {
+            assert!(!backoff.is_completed());
+            backoff.snooze();
+        }
+        assert!(backoff.is_completed());
+        // The step stays at the limit.
+        backoff.snooze();
+        assert!(backoff.is_completed());
+        backoff.reset();
+        assert!(!backoff.is_completed());
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_spin_until_gives_up() {
+        let mut calls = 0;
+        assert!(!spin_until(|| { calls += 1; false }, 20));
+        assert_eq!(calls, 21);
+
+        let mut calls = 0;
+        assert!(spin_until(|| { calls += 1; calls == 3 }, 20));
+        assert_eq!(calls, 3);
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[test]
+    #[should_panic(expected = "spinning without parallel_queries")]
+    fn test_serial_spin_panics() {
+        spin_until(|| false, 1);
+    }
+}
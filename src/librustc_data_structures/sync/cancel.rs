@@ -0,0 +1,124 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tokens for cancelling parallel work, e.g. the remaining items of `par_for_each_until`.
+//!
+//! A token can have child tokens, which are cancelled when any of their ancestors is. A child
+//! keeps a reference to its parent and checks the flags of all its ancestors, so cancelling a
+//! token doesn't need to find its children and a child created after its parent was cancelled
+//! is cancelled too. Cancelling a child doesn't cancel its parent.
+
+use super::{AtomicBool, AtomicOrderings, Lrc};
+
+/// Returned by `CancellationToken::check` if the token was cancelled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+struct TokenState {
+    cancelled: AtomicBool,
+    parent: Option<Lrc<TokenState>>,
+}
+
+/// A cancellation flag shared by all clones of the token.
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Lrc<TokenState>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            state: Lrc::new(TokenState {
+                cancelled: AtomicBool::new(false),
+                parent: None,
+            }),
+        }
+    }
+
+    /// Creates a token which is cancelled when this token is, or when it is cancelled itself.
+    pub fn child(&self) -> Self {
+        CancellationToken {
+            state: Lrc::new(TokenState {
+                cancelled: AtomicBool::new(false),
+                parent: Some(self.state.clone()),
+            }),
+        }
+    }
+
+    /// Cancels this token and all of its children.
+    #[inline]
+    pub fn cancel(&self) {
+        self.state.cancelled.store_release(true);
+    }
+
+    /// Returns whether this token or one of its ancestors was cancelled.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        let mut state = &self.state;
+        loop {
+            if state.cancelled.load_acquire() {
+                return true;
+            }
+            match state.parent {
+                Some(ref parent) => state = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Returns `Err(Cancelled)` if this token was cancelled, for use with `?`.
+    #[inline]
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert_eq!(token.check(), Ok(()));
+        clone.cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(token.check(), Err(Cancelled));
+    }
+
+    #[test]
+    fn test_parent_cancels_children() {
+        let parent = CancellationToken::new();
+        let child = parent.child();
+        let grandchild = child.child();
+        let sibling = parent.child();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+        assert_eq!(parent.child().check(), Err(Cancelled));
+    }
+}
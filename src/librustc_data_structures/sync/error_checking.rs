@@ -0,0 +1,130 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Support for the lock error checking mode enabled by the `lock-error-checking` feature.
+//!
+//! In this mode locks panic instead of blocking if they are already held. Since the panic
+//! happens in the second acquirer, each lock remembers who acquired it last so that the
+//! panic message can point at the guard which is still alive. This records the location
+//! passed to `lock_at`, `read_at` or `write_at` and the name of the acquiring thread.
+//! If the `RUSTC_LOCK_BACKTRACE` environment variable is set, a backtrace is captured
+//! at every acquisition as well. That is very slow, but this mode is only meant for debugging.
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use backtrace::Backtrace;
+use parking_lot::Mutex;
+use sync::{Location, TryLockError};
+
+const BACKTRACE_UNKNOWN: usize = 0;
+const BACKTRACE_DISABLED: usize = 1;
+const BACKTRACE_ENABLED: usize = 2;
+
+static CAPTURE_BACKTRACE: AtomicUsize = AtomicUsize::new(BACKTRACE_UNKNOWN);
+
+/// Returns whether `RUSTC_LOCK_BACKTRACE` is set. This is only checked once.
+fn capture_backtrace() -> bool {
+    match CAPTURE_BACKTRACE.load(Ordering::Relaxed) {
+        BACKTRACE_UNKNOWN => {
+            let enabled = env::var_os("RUSTC_LOCK_BACKTRACE").is_some();
+            let state = if enabled { BACKTRACE_ENABLED } else { BACKTRACE_DISABLED };
+            CAPTURE_BACKTRACE.store(state, Ordering::Relaxed);
+            enabled
+        }
+        state => state == BACKTRACE_ENABLED,
+    }
+}
+
+/// Describes an acquisition of a lock.
+pub struct Holder {
+    location: Option<Location>,
+    thread_id: thread::ThreadId,
+    thread: String,
+    backtrace: Option<Backtrace>,
+}
+
+impl Holder {
+    pub fn capture(location: Option<Location>, backtrace: bool) -> Self {
+        let thread = thread::current();
+        Holder {
+            location,
+            thread_id: thread.id(),
+            thread: match thread.name() {
+                Some(name) => name.to_string(),
+                None => format!("{:?}", thread.id()),
+            },
+            backtrace: if backtrace { Some(Backtrace::new()) } else { None },
+        }
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(location) => write!(f, "at {}", location)?,
+            None => write!(f, "at an unknown location")?,
+        }
+        write!(f, " on thread `{}`", self.thread)?;
+        if let Some(ref backtrace) = self.backtrace {
+            write!(f, "\nbacktrace of the acquisition:\n{:?}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+/// The most recent acquisition of a `Lock` or `RwLock`. For a `RwLock` held by multiple
+/// readers this is the last reader to acquire it.
+pub struct LastHolder(Mutex<Option<Holder>>);
+
+impl fmt::Debug for LastHolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("LastHolder { .. }")
+    }
+}
+
+impl LastHolder {
+    pub fn new() -> Self {
+        LastHolder(Mutex::new(None))
+    }
+
+    /// Records an acquisition. This must be called while the lock is held.
+    pub fn acquired(&self, location: Option<Location>) {
+        *self.0.lock() = Some(Holder::capture(location, capture_backtrace()));
+    }
+
+    /// Returns the error of a `try_*_detailed` method which failed since the lock is held.
+    /// The lock is held by the current thread if it acquired it last, which is always the
+    /// case without cfg!(parallel_queries).
+    pub fn try_lock_error(&self) -> TryLockError {
+        match *self.0.lock() {
+            Some(ref holder) if !cfg!(parallel_queries) ||
+                                holder.thread_id == thread::current().id() => {
+                TryLockError::AlreadyBorrowedByCurrentThread { location: holder.location }
+            }
+            _ if !cfg!(parallel_queries) => {
+                TryLockError::AlreadyBorrowedByCurrentThread { location: None }
+            }
+            _ => TryLockError::WouldBlock,
+        }
+    }
+
+    /// Panics because the lock could not be acquired without blocking.
+    #[cfg(parallel_queries)]
+    #[cold]
+    #[inline(never)]
+    pub fn already_held(&self) -> ! {
+        match *self.0.lock() {
+            Some(ref holder) => panic!("lock was already held, it was last acquired {}", holder),
+            None => panic!("lock was already held"),
+        }
+    }
+}
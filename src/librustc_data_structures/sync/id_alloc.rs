@@ -0,0 +1,129 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An allocator of unique IDs for passes which assign fresh IDs in parallel.
+//!
+//! With cfg!(parallel_queries) every worker claims a block of `BLOCK_SIZE` IDs from a shared
+//! `AtomicU64` and then allocates from its block in a `WorkerLocal`, so the shared counter is
+//! only touched once per block. Which worker gets which block depends on the scheduling, so
+//! the IDs are unique but neither dense nor reproducible. `IdAllocator::compact` maps them to
+//! dense IDs in an order chosen by the caller, e.g. the order of the items they were assigned
+//! to, which is reproducible. Without cfg!(parallel_queries) this is a plain counter.
+
+use std::sync::atomic::Ordering;
+use fx::FxHashMap;
+use super::AtomicU64;
+
+cfg_if! {
+    if #[cfg(parallel_queries)] {
+        use std::cell::Cell;
+        use super::WorkerLocal;
+
+        /// The number of IDs a worker claims at once.
+        const BLOCK_SIZE: u64 = 1024;
+    }
+}
+
+/// Like for `WorkerLocal`, `alloc` must be called on a thread of the pool the allocator was
+/// created in.
+pub struct IdAllocator {
+    /// The next ID without cfg!(parallel_queries), otherwise the start of the next free block.
+    next: AtomicU64,
+    /// The next ID and the end of the block of every worker.
+    #[cfg(parallel_queries)]
+    blocks: WorkerLocal<Cell<(u64, u64)>>,
+}
+
+impl IdAllocator {
+    pub fn new() -> Self {
+        IdAllocator {
+            next: AtomicU64::new(0),
+            #[cfg(parallel_queries)]
+            blocks: WorkerLocal::new(|_| Cell::new((0, 0))),
+        }
+    }
+
+    /// Returns an ID which wasn't returned before by this allocator.
+    #[cfg(parallel_queries)]
+    #[inline]
+    pub fn alloc(&self) -> u64 {
+        let (mut next, mut end) = self.blocks.get();
+        if next == end {
+            next = self.next.fetch_add(BLOCK_SIZE, Ordering::Relaxed);
+            end = next + BLOCK_SIZE;
+        }
+        self.blocks.set((next + 1, end));
+        next
+    }
+
+    /// Returns an ID which wasn't returned before by this allocator.
+    #[cfg(not(parallel_queries))]
+    #[inline]
+    pub fn alloc(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Maps the IDs in `ids` to dense IDs starting at 0, in the order they first appear in
+    /// `ids`. So the mapping only depends on the order of `ids`, not on which worker allocated
+    /// which ID.
+    pub fn compact(ids: &[u64]) -> FxHashMap<u64, u64> {
+        let mut dense = FxHashMap::default();
+        for &id in ids {
+            let len = dense.len() as u64;
+            dense.entry(id).or_insert(len);
+        }
+        dense
+    }
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        IdAllocator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fx::FxHashSet;
+    use sync::{in_pool, par_iter};
+    #[cfg(parallel_queries)]
+    use rayon::iter::ParallelIterator;
+
+    /// Allocates an ID for each of 10000 items and returns them in the order of the items.
+    fn assign_ids() -> Vec<u64> {
+        let assign = || {
+            let allocator = IdAllocator::new();
+            par_iter(0..10000).map(|_| allocator.alloc()).collect::<Vec<u64>>()
+        };
+        // Use several workers so every worker allocates IDs.
+        in_pool(4, assign)
+    }
+
+    #[test]
+    fn test_ids_are_unique() {
+        let ids = assign_ids();
+        assert_eq!(ids.iter().collect::<FxHashSet<_>>().len(), 10000);
+    }
+
+    #[test]
+    fn test_compact() {
+        let compact = |ids: Vec<u64>| {
+            let dense = IdAllocator::compact(&ids);
+            ids.iter().map(|id| dense[id]).collect::<Vec<u64>>()
+        };
+        // The dense IDs only depend on the order of the items, so they are the same every time.
+        assert_eq!(compact(assign_ids()), (0..10000).collect::<Vec<u64>>());
+        assert_eq!(compact(assign_ids()), (0..10000).collect::<Vec<u64>>());
+
+        // Repeated IDs keep the dense ID of their first occurrence.
+        assert_eq!(compact(vec![7, 3, 7, 2048]), [0, 1, 0, 2]);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Load balance statistics of parallel regions, enabled by the `lock-metrics` feature.
+//!
+//! `join`, `join_named` and the `par_*` helpers count how many of their tasks each worker
+//! of the thread pool executed. When the region ends its imbalance, the largest count divided
+//! by the smallest one, is recorded under the name of the region. Workers which executed no
+//! tasks are counted as having executed one, so the imbalance of a region with fewer tasks
+//! than workers can still be 1. A large imbalance means that most workers were idle while a
+//! few of them worked through a long task, so the region did not scale.
+//!
+//! The counters can't be a `WorkerLocal`, since rayon runs iterators which it doesn't split on
+//! the calling thread, which may not be a worker. Like in `par_for_each_worker`, such tasks are
+//! counted towards worker 0.
+//!
+//! Tasks spawned in a `scope` are not counted, since they are spawned through rayon's `Scope`.
+//! Without cfg!(parallel_queries) there is a single worker, so the imbalance is always 1.
+
+cfg_if! {
+    if #[cfg(feature = "lock-metrics")] {
+        use std::cmp;
+        use std::io;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use parking_lot::Mutex;
+        use fx::FxHashMap;
+        use sync::CachePadded;
+
+        /// The imbalance of all the runs of the regions with the same name.
+        #[derive(Copy, Clone, Debug)]
+        struct RegionStats {
+            runs: u64,
+            total_imbalance: f64,
+            worst_imbalance: f64,
+        }
+
+        static REGION_STATS: Mutex<Option<FxHashMap<&'static str, RegionStats>>> =
+            Mutex::new(None);
+
+        /// Counts the tasks executed by each worker during a parallel region.
+        pub struct Region {
+            name: &'static str,
+            /// The number of tasks executed by each worker.
+            tasks: Vec<CachePadded<AtomicU64>>,
+        }
+
+        #[cfg(parallel_queries)]
+        fn worker_count() -> usize {
+            ::rayon::current_num_threads()
+        }
+
+        #[cfg(not(parallel_queries))]
+        fn worker_count() -> usize {
+            1
+        }
+
+        #[cfg(parallel_queries)]
+        fn current_worker() -> usize {
+            ::rayon_core::current_thread_index().unwrap_or(0)
+        }
+
+        #[cfg(not(parallel_queries))]
+        fn current_worker() -> usize {
+            0
+        }
+
+        impl Region {
+            pub fn enter(name: &'static str) -> Self {
+                Region {
+                    name,
+                    tasks: (0..worker_count()).map(|_| CachePadded::new(AtomicU64::new(0)))
+                                              .collect(),
+                }
+            }
+
+            /// Runs `f` as a task of this region on the current worker.
+            #[inline]
+            pub fn task<R, F: FnOnce() -> R>(&self, f: F) -> R {
+                // The task may run on a worker of a nested pool with more threads.
+                let worker = current_worker() % self.tasks.len();
+                self.tasks[worker].fetch_add(1, Ordering::Relaxed);
+                f()
+            }
+        }
+
+        impl Drop for Region {
+            fn drop(&mut self) {
+                let tasks: Vec<u64> = self.tasks.iter()
+                                                .map(|tasks| tasks.load(Ordering::Relaxed))
+                                                .collect();
+                let max = tasks.iter().cloned().max().unwrap_or(0);
+                let min = tasks.iter().cloned().min().unwrap_or(0);
+                if max == 0 {
+                    return;
+                }
+                let imbalance = max as f64 / min.max(1) as f64;
+
+                let mut regions = REGION_STATS.lock();
+                let stats = regions.get_or_insert_with(FxHashMap::default)
+                                   .entry(self.name)
+                                   .or_insert(RegionStats {
+                                       runs: 0,
+                                       total_imbalance: 0.0,
+                                       worst_imbalance: 0.0,
+                                   });
+                stats.runs += 1;
+                stats.total_imbalance += imbalance;
+                stats.worst_imbalance = stats.worst_imbalance.max(imbalance);
+            }
+        }
+
+        /// Writes the imbalance of the parallel regions which ran at least once, sorted by
+        /// their worst imbalance in descending order, so the regions which scale worst come
+        /// first. Each line lists the worst and the mean imbalance of all the runs of the
+        /// regions with the same name.
+        pub fn dump_parallel_imbalance(out: &mut dyn io::Write) -> io::Result<()> {
+            let mut regions = Vec::new();
+            if let Some(ref stats) = *REGION_STATS.lock() {
+                regions.extend(stats.iter().map(|(&name, &stats)| (name, stats)));
+            }
+            regions.sort_by(|a, b| {
+                b.1.worst_imbalance.partial_cmp(&a.1.worst_imbalance)
+                                   .unwrap_or(cmp::Ordering::Equal)
+                                   .then(a.0.cmp(b.0))
+            });
+            for (name, stats) in regions {
+                writeln!(out,
+                         "region `{}`: worst imbalance {:.2}, mean imbalance {:.2} over {} runs",
+                         name,
+                         stats.worst_imbalance,
+                         stats.total_imbalance / stats.runs as f64,
+                         stats.runs)?;
+            }
+            Ok(())
+        }
+
+        #[cfg(test)]
+        fn worst_imbalance(name: &str) -> Option<f64> {
+            REGION_STATS.lock().as_ref()?.get(name).map(|stats| stats.worst_imbalance)
+        }
+    } else {
+        pub struct Region;
+
+        impl Region {
+            #[inline(always)]
+            pub fn enter(_name: &'static str) -> Self {
+                Region
+            }
+
+            #[inline(always)]
+            pub fn task<R, F: FnOnce() -> R>(&self, f: F) -> R {
+                f()
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "lock-metrics"))]
+mod tests {
+    use super::*;
+    use sync::par_for_each_worker;
+
+    #[test]
+    fn test_region_without_tasks_is_not_recorded() {
+        drop(Region::enter("test_region_without_tasks_is_not_recorded"));
+        assert!(worst_imbalance("test_region_without_tasks_is_not_recorded").is_none());
+    }
+
+    #[test]
+    fn test_dump_parallel_imbalance() {
+        par_for_each_worker(0..10, |_, _| ());
+        let mut dump = Vec::new();
+        dump_parallel_imbalance(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains("region `par_for_each_worker`: worst imbalance "), "{}", dump);
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[test]
+    fn test_serial_region_is_balanced() {
+        let region = Region::enter("test_serial_region_is_balanced");
+        for _ in 0..10 {
+            region.task(|| ());
+        }
+        drop(region);
+        assert_eq!(worst_imbalance("test_serial_region_is_balanced"), Some(1.0));
+    }
+
+    /// Runs a task sleeping for each of `millis` on a pool with 4 workers,
+    /// and returns the imbalance of the region.
+    #[cfg(parallel_queries)]
+    fn imbalance_of(name: &'static str, millis: &[u64]) -> f64 {
+        use rayon::prelude::*;
+        use std::thread;
+        use std::time::Duration;
+        use sync::in_pool;
+
+        in_pool(4, || {
+            let region = Region::enter(name);
+            // Make every item a separate job, so idle workers can steal each of them.
+            millis.par_iter().with_max_len(1).for_each(|&millis| {
+                region.task(|| thread::sleep(Duration::from_millis(millis)))
+            });
+        });
+        worst_imbalance(name).unwrap()
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_balanced_region() {
+        let imbalance = imbalance_of("test_balanced_region", &[20; 40]);
+        assert!(imbalance < 2.0, "{}", imbalance);
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_skewed_region() {
+        let mut millis = vec![5; 40];
+        millis[0] = 500;
+        let imbalance = imbalance_of("test_skewed_region", &millis);
+        assert!(imbalance > 4.0, "{}", imbalance);
+    }
+}
@@ -0,0 +1,1230 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Optional instrumentation for `Lock` and `RwLock`.
+//!
+//! If the `lock-metrics` feature is enabled, the guards returned by `Lock` and `RwLock` are
+//! `InstrumentedGuard`s which record how long the lock was held when they are dropped.
+//! Otherwise the guards are the plain `lock_api` guards and none of the
+//! instrumentation is compiled in.
+//!
+//! If the `lock-tracing` feature is enabled, lock acquisitions and the parallel regions entered
+//! through `join`, `join_named` and `scope` are spans of `TraceRecord`s, an enter record and an
+//! exit record. Their fields are the name of the lock or region and, for locks, the kind of
+//! acquisition and whether it was contended. The records are passed to the sink installed with
+//! `set_trace_sink`, or to `trace!` as `key=value` pairs if there is none. This doesn't need
+//! `lock-metrics`.
+//!
+//! The records don't use the `tracing` crate. All dependencies of the compiler are vendored and
+//! this crate already logs through `log`, whose records have no key-value fields yet. A sink can
+//! forward the records to `tracing` or any other subscriber.
+//!
+//! The time threads spend blocked acquiring each lock is accumulated as well. It is reported
+//! by `dump_lock_wait_times`, summed over all the locks with the same name.
+//!
+//! `dump_lock_stats_by_type` reports the contention of all locks, named or not, summed over
+//! the locks with the same type of value.
+//!
+//! If the `sync-debug` feature is enabled, each lock also counts its outstanding guards and
+//! where they were acquired. `dump_outstanding_guards` lists these for every named lock, which
+//! helps to find guards which were leaked, e.g. with `mem::forget`. Tests can check that no
+//! guards are left at a checkpoint with `assert_no_guards_held`.
+//!
+//! Diagnostics are passed to the sink installed with `set_diagnostic_sink`,
+//! or printed to stderr if there is none.
+
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use parking_lot;
+use sync;
+use sync::imbalance::Region;
+#[cfg(parallel_queries)]
+use rayon::join as raw_join;
+#[cfg(not(parallel_queries))]
+use sync::serial_join as raw_join;
+
+#[cfg(feature = "lock-metrics")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "lock-metrics")]
+use std::thread;
+#[cfg(feature = "lock-metrics")]
+use std::time::Instant;
+#[cfg(feature = "lock-metrics")]
+use std::intrinsics;
+#[cfg(feature = "lock-metrics")]
+use std::io;
+#[cfg(feature = "lock-metrics")]
+use std::sync::{Arc, Weak};
+#[cfg(feature = "lock-metrics")]
+use fx::FxHashMap;
+#[cfg(feature = "sync-debug")]
+use std::fmt::Write;
+#[cfg(feature = "sync-debug")]
+use std::sync::atomic::AtomicUsize;
+
+/// A source location, used to identify the place a lock was acquired.
+/// `sync_location!()` returns the location of its call site.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Returns the `sync::Location` of the macro invocation.
+#[macro_export]
+macro_rules! sync_location {
+    () => {
+        $crate::sync::Location {
+            file: file!(),
+            line: line!(),
+            column: column!(),
+        }
+    }
+}
+
+/// A callback which receives the diagnostics emitted by the lock instrumentation.
+pub type DiagnosticSink = Box<dyn Fn(&str) + Send + Sync>;
+
+static DIAGNOSTIC_SINK: parking_lot::RwLock<Option<DiagnosticSink>> =
+    parking_lot::RwLock::new(None);
+
+/// Installs a sink for the diagnostics emitted by the lock instrumentation and returns the
+/// previously installed one. Diagnostics are printed to stderr if no sink is installed.
+pub fn set_diagnostic_sink(sink: Option<DiagnosticSink>) -> Option<DiagnosticSink> {
+    mem::replace(&mut *DIAGNOSTIC_SINK.write(), sink)
+}
+
+#[cfg(any(feature = "lock-metrics",
+          all(feature = "atomic-audit", not(parallel_queries))))]
+pub fn emit_diagnostic(msg: &str) {
+    match *DIAGNOSTIC_SINK.read() {
+        Some(ref sink) => sink(msg),
+        None => eprintln!("warning: {}", msg),
+    }
+}
+
+/// The hold time in nanoseconds above which dropping a guard emits a warning.
+/// Zero disables the warning.
+static HOLD_WARN_THRESHOLD: AtomicU64 = AtomicU64::new(0);
+
+/// Warnings about a lock being held too long are emitted at most once per this many seconds
+/// for each lock. The number of warnings suppressed in between is included in the next one.
+#[cfg(feature = "lock-metrics")]
+const HOLD_WARN_INTERVAL_SECS: u64 = 1;
+
+/// Makes dropping a lock guard emit a warning if the lock was held for longer than
+/// `threshold`. Passing a zero duration disables the warning, which is the default.
+/// This does nothing unless the `lock-metrics` feature is enabled.
+pub fn set_lock_hold_warn_threshold(threshold: Duration) {
+    HOLD_WARN_THRESHOLD.store(duration_to_nanos(threshold), Ordering::Relaxed);
+}
+
+fn duration_to_nanos(duration: Duration) -> u64 {
+    duration.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(duration.subsec_nanos() as u64)
+}
+
+/// Whether a guard gives shared or exclusive access to the value of a lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuardKind {
+    Read,
+    Write,
+}
+
+/// The guards of a lock which have not been dropped yet.
+#[cfg(feature = "sync-debug")]
+#[derive(Debug)]
+struct OutstandingGuards {
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+    /// The number of outstanding guards acquired at each location.
+    locations: parking_lot::Mutex<FxHashMap<Option<Location>, usize>>,
+}
+
+#[cfg(feature = "sync-debug")]
+impl OutstandingGuards {
+    fn new() -> Self {
+        OutstandingGuards {
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            locations: parking_lot::Mutex::new(FxHashMap::default()),
+        }
+    }
+
+    fn counter(&self, kind: GuardKind) -> &AtomicUsize {
+        match kind {
+            GuardKind::Read => &self.reads,
+            GuardKind::Write => &self.writes,
+        }
+    }
+
+    fn acquired(&self, kind: GuardKind, location: Option<Location>) {
+        self.counter(kind).fetch_add(1, Ordering::Relaxed);
+        *self.locations.lock().entry(location).or_insert(0) += 1;
+    }
+
+    fn released(&self, kind: GuardKind, location: Option<Location>) {
+        self.counter(kind).fetch_sub(1, Ordering::Relaxed);
+        let mut locations = self.locations.lock();
+        let remove = {
+            let count = locations.get_mut(&location).unwrap();
+            *count -= 1;
+            *count == 0
+        };
+        if remove {
+            locations.remove(&location);
+        }
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        (self.reads.load(Ordering::Relaxed), self.writes.load(Ordering::Relaxed))
+    }
+
+    /// Appends the number of outstanding guards and where they were acquired to `report`.
+    /// Returns false and appends nothing if there are none.
+    fn describe(&self, name: Option<&str>, report: &mut String) -> bool {
+        let (reads, writes) = self.counts();
+        if reads == 0 && writes == 0 {
+            return false;
+        }
+        match name {
+            Some(name) => write!(report, "lock `{}`", name),
+            None => write!(report, "unnamed lock"),
+        }.unwrap();
+        writeln!(report,
+                 " has {} outstanding read guards and {} outstanding write guards",
+                 reads,
+                 writes).unwrap();
+        for (location, count) in self.locations.lock().iter() {
+            match *location {
+                Some(location) => writeln!(report, "    {} acquired at {}", count, location),
+                None => writeln!(report, "    {} acquired at an unknown location", count),
+            }.unwrap();
+        }
+        true
+    }
+}
+
+/// The statistics of a lock which are kept in the registry of named locks.
+#[cfg(feature = "lock-metrics")]
+#[derive(Debug)]
+struct LockStats {
+    name: Option<&'static str>,
+    /// The total time threads spent blocked acquiring the lock, in nanoseconds.
+    wait_nanos: AtomicU64,
+    /// The number of times the lock was acquired.
+    acquisitions: AtomicU64,
+    /// The longest time a guard of the lock was held, in nanoseconds.
+    max_hold_nanos: AtomicU64,
+    #[cfg(feature = "sync-debug")]
+    outstanding: OutstandingGuards,
+}
+
+#[cfg(feature = "lock-metrics")]
+impl Drop for LockStats {
+    fn drop(&mut self) {
+        if let Some(name) = self.name {
+            let wait_nanos = self.wait_nanos.load(Ordering::Relaxed);
+            *RETIRED_WAIT_NANOS.lock()
+                               .get_or_insert_with(FxHashMap::default)
+                               .entry(name)
+                               .or_insert(0) += wait_nanos;
+        }
+    }
+}
+
+/// The statistics of every named lock which is still alive.
+#[cfg(feature = "lock-metrics")]
+static NAMED_LOCKS: parking_lot::Mutex<Vec<Weak<LockStats>>> =
+    parking_lot::Mutex::new(Vec::new());
+
+/// The total wait times of the named locks which were dropped, by name.
+#[cfg(feature = "lock-metrics")]
+static RETIRED_WAIT_NANOS: parking_lot::Mutex<Option<FxHashMap<&'static str, u64>>> =
+    parking_lot::Mutex::new(None);
+
+/// Counts the blocking lock acquisitions of all locks. Bucket `i` counts the acquisitions
+/// which waited for at least `2^i` and less than `2^(i + 1)` nanoseconds, except that
+/// bucket 0 also counts waits of 0 nanoseconds.
+#[cfg(feature = "lock-metrics")]
+static WAIT_HISTOGRAM: parking_lot::Mutex<[u64; 64]> = parking_lot::Mutex::new([0; 64]);
+
+/// The statistics of all the locks with the same type of value.
+#[cfg(feature = "lock-metrics")]
+#[derive(Debug, Default)]
+struct TypeStats {
+    instances: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+/// The statistics of the locks of each type of value. Entries are never removed, so this is
+/// bounded by the number of types used in locks rather than by the number of locks.
+#[cfg(feature = "lock-metrics")]
+static TYPE_STATS: parking_lot::Mutex<Option<FxHashMap<&'static str, Arc<TypeStats>>>> =
+    parking_lot::Mutex::new(None);
+
+/// Returns the statistics of the locks containing a `T` and counts a new lock.
+#[cfg(feature = "lock-metrics")]
+fn type_stats<T: ?Sized>() -> Arc<TypeStats> {
+    let type_name = unsafe { intrinsics::type_name::<T>() };
+    let stats = TYPE_STATS.lock()
+                          .get_or_insert_with(FxHashMap::default)
+                          .entry(type_name)
+                          .or_insert_with(Default::default)
+                          .clone();
+    stats.instances.fetch_add(1, Ordering::Relaxed);
+    stats
+}
+
+/// Writes the number of locks, contended acquisitions and the total time threads spent
+/// blocked acquiring them for each type of value stored in a lock, sorted by the wait time
+/// in descending order. Unlike `dump_lock_wait_times`, this includes locks without names.
+/// The counts include the locks which were already dropped.
+#[cfg(feature = "lock-metrics")]
+pub fn dump_lock_stats_by_type(out: &mut dyn io::Write) -> io::Result<()> {
+    let mut rows = Vec::new();
+    if let Some(ref types) = *TYPE_STATS.lock() {
+        for (&name, stats) in types {
+            rows.push((name,
+                       stats.instances.load(Ordering::Relaxed),
+                       stats.contended.load(Ordering::Relaxed),
+                       stats.wait_nanos.load(Ordering::Relaxed)));
+        }
+    }
+    rows.sort_by(|a, b| b.3.cmp(&a.3).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+    for (name, instances, contended, wait_nanos) in rows {
+        writeln!(out,
+                 "`{}`: {} locks, {} contended acquisitions, waited {:?}",
+                 name,
+                 instances,
+                 contended,
+                 Duration::from_nanos(wait_nanos))?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "lock-metrics")]
+fn live_named_locks() -> Vec<Arc<LockStats>> {
+    let mut named_locks = NAMED_LOCKS.lock();
+    named_locks.retain(|stats| stats.upgrade().is_some());
+    named_locks.iter().filter_map(|stats| stats.upgrade()).collect()
+}
+
+/// Writes the total time threads spent blocked acquiring each named lock, summed over all
+/// the locks with the same name and sorted in descending order. This is followed by a
+/// histogram of the wait times of all locks. Acquisitions which did not block are not
+/// counted, so this can be used to find the locks which would benefit most from sharding.
+#[cfg(feature = "lock-metrics")]
+pub fn dump_lock_wait_times(out: &mut dyn io::Write) -> io::Result<()> {
+    let mut totals = RETIRED_WAIT_NANOS.lock().clone().unwrap_or_default();
+    let named_locks = live_named_locks();
+    for stats in &named_locks {
+        *totals.entry(stats.name.unwrap()).or_insert(0) +=
+            stats.wait_nanos.load(Ordering::Relaxed);
+    }
+    // Dropping the last reference to a lock's statistics locks `RETIRED_WAIT_NANOS`.
+    drop(named_locks);
+
+    let mut totals: Vec<_> = totals.into_iter().filter(|&(_, nanos)| nanos > 0).collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    for (name, nanos) in totals {
+        writeln!(out, "lock `{}` waited {:?}", name, Duration::from_nanos(nanos))?;
+    }
+
+    let histogram = *WAIT_HISTOGRAM.lock();
+    writeln!(out, "wait time histogram:")?;
+    for (i, &count) in histogram.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        let low = if i == 0 { 0 } else { 1 << i };
+        writeln!(out,
+                 "    {:?} to {:?}: {}",
+                 Duration::from_nanos(low),
+                 Duration::from_nanos(1 << (i + 1)),
+                 count)?;
+    }
+    Ok(())
+}
+
+/// Returns a report listing every named lock which has outstanding guards, along with
+/// the places those guards were acquired. Guards acquired with `lock`, `read` or `write`
+/// instead of `lock_at`, `read_at` or `write_at` are listed at an unknown location.
+/// A lock which keeps showing up here although nothing should be holding it likely
+/// had a guard leaked with `mem::forget`.
+#[cfg(feature = "sync-debug")]
+pub fn dump_outstanding_guards() -> String {
+    let mut report = String::new();
+    let named_locks = live_named_locks();
+    for stats in &named_locks {
+        stats.outstanding.describe(stats.name, &mut report);
+    }
+    report
+}
+
+/// Panics if any named lock has outstanding guards, listing them like
+/// `dump_outstanding_guards`. Tests can call this at points where no thread should
+/// hold any lock, to find leaked guards before they cause a deadlock later on.
+/// Use `Lock::assert_no_guards_held` to check unnamed locks.
+#[cfg(feature = "sync-debug")]
+pub fn assert_no_guards_held() {
+    let report = dump_outstanding_guards();
+    if !report.is_empty() {
+        panic!("guards are still held at a checkpoint:\n{}", report);
+    }
+}
+
+/// The instrumentation state of a single `Lock` or `RwLock`.
+#[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+#[derive(Debug)]
+pub struct LockInfo {
+    /// The name the lock was created with by `named`.
+    name: Option<&'static str>,
+    /// When the last long hold warning was emitted for this lock and how many
+    /// warnings have been suppressed since.
+    #[cfg(feature = "lock-metrics")]
+    last_warning: parking_lot::Mutex<(Option<Instant>, usize)>,
+    #[cfg(feature = "lock-metrics")]
+    stats: Arc<LockStats>,
+    #[cfg(feature = "lock-metrics")]
+    type_stats: Arc<TypeStats>,
+}
+
+#[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+impl LockInfo {
+    /// Creates the instrumentation state of a lock containing a `T`.
+    #[cfg(feature = "lock-metrics")]
+    pub fn new<T: ?Sized>(name: Option<&'static str>) -> Self {
+        let stats = Arc::new(LockStats {
+            name,
+            wait_nanos: AtomicU64::new(0),
+            acquisitions: AtomicU64::new(0),
+            max_hold_nanos: AtomicU64::new(0),
+            #[cfg(feature = "sync-debug")]
+            outstanding: OutstandingGuards::new(),
+        });
+        if name.is_some() {
+            NAMED_LOCKS.lock().push(Arc::downgrade(&stats));
+        }
+        LockInfo {
+            name,
+            last_warning: parking_lot::Mutex::new((None, 0)),
+            stats,
+            type_stats: type_stats::<T>(),
+        }
+    }
+
+    /// Creates the instrumentation state of a lock, which only consists of its name
+    /// without the `lock-metrics` feature.
+    #[cfg(not(feature = "lock-metrics"))]
+    pub fn new<T: ?Sized>(name: Option<&'static str>) -> Self {
+        LockInfo { name }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name.unwrap_or("<unnamed>")
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+impl LockInfo {
+    /// Returns the name the lock was created with by `named`.
+    pub fn given_name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Returns the number of outstanding read and write guards.
+    #[cfg(feature = "sync-debug")]
+    pub fn outstanding_guards(&self) -> (usize, usize) {
+        self.stats.outstanding.counts()
+    }
+
+    /// Panics if the lock has outstanding guards.
+    #[cfg(feature = "sync-debug")]
+    pub fn assert_no_guards_held(&self) {
+        let mut report = String::new();
+        if self.stats.outstanding.describe(self.stats.name, &mut report) {
+            panic!("guards are still held at a checkpoint:\n{}", report);
+        }
+    }
+
+    /// Returns the total time threads spent blocked acquiring the lock.
+    pub fn wait_time(&self) -> Duration {
+        Duration::from_nanos(self.stats.wait_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of times the lock was acquired.
+    pub fn acquisitions(&self) -> u64 {
+        self.stats.acquisitions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the longest time a guard of the lock was held.
+    pub fn max_hold_time(&self) -> Duration {
+        Duration::from_nanos(self.stats.max_hold_nanos.load(Ordering::Relaxed))
+    }
+
+    fn waited(&self, waited: Duration) {
+        let nanos = duration_to_nanos(waited);
+        self.stats.wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.type_stats.contended.fetch_add(1, Ordering::Relaxed);
+        self.type_stats.wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        let bucket = if nanos == 0 { 0 } else { 63 - nanos.leading_zeros() as usize };
+        WAIT_HISTOGRAM.lock()[bucket] += 1;
+    }
+
+    fn released(&self, held: Duration, location: Option<Location>) {
+        let nanos = duration_to_nanos(held);
+        let mut max = self.stats.max_hold_nanos.load(Ordering::Relaxed);
+        while nanos > max {
+            match self.stats.max_hold_nanos.compare_exchange_weak(max, nanos,
+                                                                  Ordering::Relaxed,
+                                                                  Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(read) => max = read,
+            }
+        }
+
+        let threshold = HOLD_WARN_THRESHOLD.load(Ordering::Relaxed);
+        if threshold == 0 || nanos < threshold {
+            return;
+        }
+
+        let suppressed = {
+            let mut last_warning = self.last_warning.lock();
+            let now = Instant::now();
+            if let Some(last) = last_warning.0 {
+                if now.duration_since(last) < Duration::from_secs(HOLD_WARN_INTERVAL_SECS) {
+                    last_warning.1 += 1;
+                    return;
+                }
+            }
+            last_warning.0 = Some(now);
+            mem::replace(&mut last_warning.1, 0)
+        };
+
+        let location = match location {
+            Some(location) => location.to_string(),
+            None => "<unknown location>".to_string(),
+        };
+        let mut msg = format!("lock `{}` was held for {:?}, longer than the threshold of {:?} \
+                               (acquired at {} on thread `{}`)",
+                              self.name(),
+                              held,
+                              Duration::from_nanos(threshold),
+                              location,
+                              thread::current().name().unwrap_or("<unnamed>"));
+        if suppressed > 0 {
+            msg.push_str(&format!(", {} similar warnings were suppressed", suppressed));
+        }
+        emit_diagnostic(&msg);
+    }
+}
+
+/// Whether a trace record marks the start or the end of a span.
+#[cfg(feature = "lock-tracing")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TracePhase {
+    Enter,
+    Exit,
+}
+
+/// What a span of trace records covers.
+#[cfg(feature = "lock-tracing")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceSpan {
+    /// The acquisition of a lock. `kind` is "lock", "read", "write" or "upgradable_read" and
+    /// `contended` says whether the thread had to block. An uncontended acquisition has an
+    /// empty span, its enter record is only emitted once the lock was acquired.
+    Lock { name: &'static str, kind: &'static str, contended: bool },
+    /// A parallel region entered through `join`, `join_named` or `scope`.
+    Region { name: &'static str },
+}
+
+/// A trace record emitted if the `lock-tracing` feature is enabled.
+#[cfg(feature = "lock-tracing")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub phase: TracePhase,
+    pub span: TraceSpan,
+}
+
+/// Prints the fields of the record as `key=value` pairs, e.g.
+/// `enter lock name=query_cache kind=write contended=true`.
+#[cfg(feature = "lock-tracing")]
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phase = match self.phase {
+            TracePhase::Enter => "enter",
+            TracePhase::Exit => "exit",
+        };
+        match self.span {
+            TraceSpan::Lock { name, kind, contended } => {
+                write!(f, "{} lock name={} kind={} contended={}", phase, name, kind, contended)
+            }
+            TraceSpan::Region { name } => write!(f, "{} region name={}", phase, name),
+        }
+    }
+}
+
+/// A callback which receives the trace records emitted if the `lock-tracing` feature is enabled.
+#[cfg(feature = "lock-tracing")]
+pub type TraceSink = Box<dyn Fn(&TraceRecord) + Send + Sync>;
+
+#[cfg(feature = "lock-tracing")]
+static TRACE_SINK: parking_lot::RwLock<Option<TraceSink>> = parking_lot::RwLock::new(None);
+
+/// Installs a sink for the trace records and returns the previously installed one. Records are
+/// passed to `trace!` in the format of their `Display` impl if no sink is installed.
+#[cfg(feature = "lock-tracing")]
+pub fn set_trace_sink(sink: Option<TraceSink>) -> Option<TraceSink> {
+    mem::replace(&mut *TRACE_SINK.write(), sink)
+}
+
+#[cfg(feature = "lock-tracing")]
+fn emit_trace(phase: TracePhase, span: TraceSpan) {
+    let record = TraceRecord { phase, span };
+    match *TRACE_SINK.read() {
+        Some(ref sink) => sink(&record),
+        None => trace!("{}", record),
+    }
+}
+
+/// Acquires a lock with `try_acquire`, falling back to the blocking `acquire` if that fails.
+/// If the `lock-metrics` feature is enabled, the time spent in `acquire` is added to the wait
+/// time of the lock, the clock is not read if `try_acquire` succeeds. If the `lock-tracing`
+/// feature is enabled, the acquisition is a span of trace records.
+#[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+#[inline]
+pub fn acquire<G, F, A>(info: &LockInfo, _kind: &'static str, try_acquire: F, acquire: A) -> G
+    where F: FnOnce() -> Option<G>,
+          A: FnOnce() -> G
+{
+    if let Some(guard) = try_acquire() {
+        #[cfg(feature = "lock-tracing")]
+        {
+            let span = TraceSpan::Lock { name: info.name(), kind: _kind, contended: false };
+            emit_trace(TracePhase::Enter, span);
+            emit_trace(TracePhase::Exit, span);
+        }
+        return guard;
+    }
+    acquire_contended(info, _kind, acquire)
+}
+
+#[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+#[cold]
+#[inline(never)]
+fn acquire_contended<G, A>(_info: &LockInfo, _kind: &'static str, acquire: A) -> G
+    where A: FnOnce() -> G
+{
+    #[cfg(feature = "lock-tracing")]
+    let span = TraceSpan::Lock { name: _info.name(), kind: _kind, contended: true };
+    #[cfg(feature = "lock-tracing")]
+    emit_trace(TracePhase::Enter, span);
+    #[cfg(feature = "lock-metrics")]
+    let start = Instant::now();
+    let guard = acquire();
+    #[cfg(feature = "lock-metrics")]
+    _info.waited(start.elapsed());
+    #[cfg(feature = "lock-tracing")]
+    emit_trace(TracePhase::Exit, span);
+    guard
+}
+
+/// Emits the enter record of a parallel region and the exit record when dropped.
+#[cfg(feature = "lock-tracing")]
+struct TraceRegion(&'static str);
+
+#[cfg(feature = "lock-tracing")]
+impl TraceRegion {
+    fn enter(name: &'static str) -> Self {
+        emit_trace(TracePhase::Enter, TraceSpan::Region { name });
+        TraceRegion(name)
+    }
+}
+
+#[cfg(feature = "lock-tracing")]
+impl Drop for TraceRegion {
+    fn drop(&mut self) {
+        emit_trace(TracePhase::Exit, TraceSpan::Region { name: self.0 });
+    }
+}
+
+/// Same as `join`, but names the parallel region in the trace records emitted if the
+/// `lock-tracing` feature is enabled and in the load balance statistics recorded if the
+/// `lock-metrics` feature is enabled.
+#[inline]
+pub fn join_named<A, B, RA, RB>(_name: &'static str, oper_a: A, oper_b: B) -> (RA, RB)
+    where A: FnOnce() -> RA + sync::MaybeSend,
+          B: FnOnce() -> RB + sync::MaybeSend,
+          RA: sync::MaybeSend,
+          RB: sync::MaybeSend
+{
+    #[cfg(feature = "lock-tracing")]
+    let _region = TraceRegion::enter(_name);
+    let region = Region::enter(_name);
+    raw_join(|| region.task(oper_a), || region.task(oper_b))
+}
+
+#[cfg(any(feature = "lock-tracing", feature = "lock-metrics"))]
+pub fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+    where A: FnOnce() -> RA + sync::MaybeSend,
+          B: FnOnce() -> RB + sync::MaybeSend,
+          RA: sync::MaybeSend,
+          RB: sync::MaybeSend
+{
+    join_named("join", oper_a, oper_b)
+}
+
+#[cfg(all(feature = "lock-tracing", parallel_queries))]
+pub fn scope<'scope, OP, R>(op: OP) -> R
+    where OP: for<'s> FnOnce(&'s ::rayon::Scope<'scope>) -> R + 'scope + sync::MaybeSend,
+          R: sync::MaybeSend
+{
+    let _region = TraceRegion::enter("scope");
+    ::rayon::scope(op)
+}
+
+#[cfg(all(feature = "lock-tracing", not(parallel_queries)))]
+pub fn scope<'scope, F, R>(f: F) -> R
+    where F: FnOnce(&sync::SerialScope<'scope>) -> R
+{
+    let _region = TraceRegion::enter("scope");
+    sync::serial_scope(f)
+}
+
+/// Records the acquisition of a lock and reports to its `LockInfo` when dropped.
+#[cfg(feature = "lock-metrics")]
+struct Probe<'a> {
+    info: &'a LockInfo,
+    acquired: Instant,
+    #[cfg(feature = "sync-debug")]
+    kind: GuardKind,
+    location: Option<Location>,
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a> Drop for Probe<'a> {
+    fn drop(&mut self) {
+        #[cfg(feature = "sync-debug")]
+        self.info.stats.outstanding.released(self.kind, self.location);
+        self.info.released(self.acquired.elapsed(), self.location);
+    }
+}
+
+/// A lock guard which reports how long the lock was held to the lock's instrumentation.
+/// The lock is released before the report is made.
+#[cfg(feature = "lock-metrics")]
+pub struct InstrumentedGuard<'a, G> {
+    // This must be declared before `probe` so that it is dropped first.
+    guard: G,
+    probe: Probe<'a>,
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a, G> InstrumentedGuard<'a, G> {
+    #[inline]
+    pub fn new(guard: G, info: &'a LockInfo, _kind: GuardKind, location: Option<Location>)
+               -> Self {
+        info.stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "sync-debug")]
+        info.stats.outstanding.acquired(_kind, location);
+        InstrumentedGuard {
+            guard,
+            probe: Probe {
+                info,
+                acquired: Instant::now(),
+                #[cfg(feature = "sync-debug")]
+                kind: _kind,
+                location,
+            },
+        }
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a, G: Deref> Deref for InstrumentedGuard<'a, G> {
+    type Target = G::Target;
+
+    #[inline]
+    fn deref(&self) -> &G::Target {
+        &*self.guard
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a, G: DerefMut> DerefMut for InstrumentedGuard<'a, G> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut *self.guard
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a, G: fmt::Debug> fmt::Debug for InstrumentedGuard<'a, G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.guard.fmt(f)
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a, G: fmt::Display> fmt::Display for InstrumentedGuard<'a, G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.guard.fmt(f)
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+impl<'a, G> InstrumentedGuard<'a, G> {
+    /// Returns the guard which is instrumented.
+    #[inline]
+    pub fn raw(s: &Self) -> &G {
+        &s.guard
+    }
+
+    /// Upgrades an upgradable read guard with `upgrade`, keeping the probe alive in the write
+    /// guard, so the lock is reported as held since the read lock was acquired.
+    #[inline]
+    pub fn upgrade<W, F: FnOnce(G) -> W>(s: Self, upgrade: F) -> InstrumentedGuard<'a, W> {
+        #[allow(unused_mut)]
+        let InstrumentedGuard { guard, mut probe } = s;
+        #[cfg(feature = "sync-debug")]
+        {
+            probe.info.stats.outstanding.released(probe.kind, probe.location);
+            probe.info.stats.outstanding.acquired(GuardKind::Write, probe.location);
+            probe.kind = GuardKind::Write;
+        }
+        InstrumentedGuard {
+            guard: upgrade(guard),
+            probe,
+        }
+    }
+}
+
+/// Implements `map` for the instrumented version of a guard, keeping the probe alive
+/// in the mapped guard.
+#[cfg(feature = "lock-metrics")]
+macro_rules! impl_instrumented_map {
+    ($guard:ident => $mapped:ident, $($mutbl:tt)*) => {
+        impl<'a, T: ?Sized> InstrumentedGuard<'a, $guard<'a, T>> {
+            #[inline]
+            pub fn map<U: ?Sized, F>(s: Self, f: F) -> InstrumentedGuard<'a, $mapped<'a, U>>
+                where F: FnOnce(&$($mutbl)* T) -> &$($mutbl)* U
+            {
+                let InstrumentedGuard { guard, probe } = s;
+                InstrumentedGuard {
+                    guard: $guard::map(guard, f),
+                    probe,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+use sync::raw::{RawLockGuard, RawMappedLockGuard, RawReadGuard, RawMappedReadGuard,
+                RawWriteGuard, RawMappedWriteGuard};
+
+#[cfg(feature = "lock-metrics")]
+impl_instrumented_map!(RawLockGuard => RawMappedLockGuard, mut);
+#[cfg(feature = "lock-metrics")]
+impl_instrumented_map!(RawMappedLockGuard => RawMappedLockGuard, mut);
+#[cfg(feature = "lock-metrics")]
+impl_instrumented_map!(RawReadGuard => RawMappedReadGuard, );
+#[cfg(feature = "lock-metrics")]
+impl_instrumented_map!(RawMappedReadGuard => RawMappedReadGuard, );
+#[cfg(feature = "lock-metrics")]
+impl_instrumented_map!(RawWriteGuard => RawMappedWriteGuard, mut);
+#[cfg(feature = "lock-metrics")]
+impl_instrumented_map!(RawMappedWriteGuard => RawMappedWriteGuard, mut);
+
+/// A diagnostic sink shared by the tests of the modules which emit diagnostics.
+#[cfg(all(test, any(feature = "lock-metrics",
+                    all(feature = "atomic-audit", not(parallel_queries)))))]
+pub mod test_sink {
+    use super::*;
+    use std::sync::{Once, ONCE_INIT};
+
+    static CAPTURED: parking_lot::Mutex<Option<Vec<String>>> = parking_lot::Mutex::new(None);
+
+    /// Returns the diagnostics emitted so far which mention `needle`. Tests run concurrently,
+    /// so the diagnostics of all tests end up in the same sink and are told apart by `needle`.
+    pub fn captured(needle: &str) -> Vec<String> {
+        static INSTALL: Once = ONCE_INIT;
+        INSTALL.call_once(|| {
+            set_diagnostic_sink(Some(Box::new(|msg| {
+                CAPTURED.lock().get_or_insert_with(Vec::new).push(msg.to_string());
+            })));
+        });
+        CAPTURED.lock()
+                .as_ref()
+                .map(|msgs| msgs.iter().filter(|msg| msg.contains(needle)).cloned().collect())
+                .unwrap_or_default()
+    }
+}
+
+#[cfg(all(test, feature = "lock-metrics"))]
+pub mod tests {
+    use super::*;
+    use super::test_sink::captured;
+    use std::thread;
+    use std::time::Duration;
+    use sync::{Lock, LockGuard, MappedLockGuard, RwLock, ReadGuard, MappedReadGuard};
+
+    fn enable_hold_warnings() {
+        captured("");
+        set_lock_hold_warn_threshold(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_long_hold_warns() {
+        enable_hold_warnings();
+        let lock = Lock::named("test_long_hold_warns", 0);
+        let line = line!() + 1;
+        let guard = lock.lock_at(sync_location!());
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+
+        let warnings = captured("`test_long_hold_warns`");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains(&format!("{}:{}:", file!(), line)), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn test_short_hold_does_not_warn() {
+        enable_hold_warnings();
+        let lock = RwLock::named("test_short_hold_does_not_warn", 0);
+        *lock.write() += 1;
+        assert_eq!(*lock.read(), 1);
+        assert!(captured("`test_short_hold_does_not_warn`").is_empty());
+    }
+
+    #[test]
+    fn test_hold_warnings_are_rate_limited() {
+        enable_hold_warnings();
+        let lock = RwLock::named("test_hold_warnings_are_rate_limited", 0);
+        for _ in 0..3 {
+            let _guard = lock.read();
+            thread::sleep(Duration::from_millis(30));
+        }
+        assert_eq!(captured("`test_hold_warnings_are_rate_limited`").len(), 1);
+    }
+
+    #[test]
+    fn test_mapped_guard_keeps_probe() {
+        enable_hold_warnings();
+        let lock = Lock::named("test_mapped_guard_keeps_probe", (1, 2));
+        {
+            let guard: MappedLockGuard<i32> = LockGuard::map(lock.lock(), |pair| &mut pair.1);
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(*guard, 2);
+        }
+        assert_eq!(captured("`test_mapped_guard_keeps_probe`").len(), 1);
+
+        let lock = RwLock::new(vec![1, 2, 3]);
+        let guard: MappedReadGuard<[i32]> = ReadGuard::map(lock.read(), |v| &v[1..]);
+        let guard = MappedReadGuard::map(guard, |v| &v[1]);
+        assert_eq!(*guard, 3);
+    }
+
+    #[test]
+    fn test_uncontended_acquisitions_do_not_wait() {
+        let lock = RwLock::named("test_uncontended_acquisitions_do_not_wait", 0);
+        *lock.write() += 1;
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(lock.wait_time(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_lock_stats_by_type() {
+        struct StatsByTypePayload;
+
+        let locks: Vec<_> = (0..3).map(|_| Lock::new(StatsByTypePayload)).collect();
+        let rw_lock = RwLock::new(StatsByTypePayload);
+        drop(locks.into_iter().next());
+
+        // Contend on one of the locks in the parallel build,
+        // unless locks panic instead of blocking.
+        let contend = cfg!(all(parallel_queries, not(feature = "lock-error-checking")));
+        #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+        {
+            use std::sync::{Arc, Barrier};
+
+            let lock = Arc::new(Lock::new(StatsByTypePayload));
+            let barrier = Arc::new(Barrier::new(2));
+            let guard = lock.lock();
+            let waiter = {
+                let (lock, barrier) = (lock.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    drop(lock.lock());
+                })
+            };
+            barrier.wait();
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+            waiter.join().unwrap();
+        }
+        drop(rw_lock);
+
+        let mut dump = Vec::new();
+        dump_lock_stats_by_type(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let rows: Vec<_> = dump.lines().filter(|row| row.contains("StatsByTypePayload`")).collect();
+        assert_eq!(rows.len(), 1, "{}", dump);
+        let expected = if contend {
+            "::StatsByTypePayload`: 5 locks, 1 contended acquisitions, waited "
+        } else {
+            "::StatsByTypePayload`: 4 locks, 0 contended acquisitions, waited 0ns"
+        };
+        assert!(rows[0].contains(expected), "{}", rows[0]);
+    }
+
+    #[test]
+    fn test_max_hold_time() {
+        let lock = Lock::new(0);
+        assert_eq!(lock.max_hold_time(), Duration::from_secs(0));
+        {
+            let _guard = lock.lock();
+            thread::sleep(Duration::from_millis(30));
+        }
+        let max = lock.max_hold_time();
+        assert!(max >= Duration::from_millis(30), "{:?}", max);
+        // Shorter holds don't lower it.
+        *lock.lock() += 1;
+        assert_eq!(lock.max_hold_time(), max);
+
+        let lock = RwLock::new(0);
+        {
+            let _guard = lock.read();
+            thread::sleep(Duration::from_millis(30));
+        }
+        assert!(lock.max_hold_time() >= Duration::from_millis(30));
+    }
+
+    // Locks panic instead of blocking in the lock error checking mode.
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_wait_times() {
+        use std::sync::{Arc, Barrier};
+
+        let lock = Arc::new(Lock::named("test_wait_times", 0));
+        let barrier = Arc::new(Barrier::new(2));
+        let guard = lock.lock();
+        let waiter = {
+            let (lock, barrier) = (lock.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                *lock.lock() += 1;
+            })
+        };
+        barrier.wait();
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        waiter.join().unwrap();
+
+        // The waiter may start waiting slightly after the barrier.
+        assert!(lock.wait_time() >= Duration::from_millis(40), "{:?}", lock.wait_time());
+        let mut dump = Vec::new();
+        dump_lock_wait_times(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains("lock `test_wait_times` waited "), "{}", dump);
+        assert!(dump.contains("wait time histogram:\n    "), "{}", dump);
+    }
+
+    #[cfg(feature = "sync-debug")]
+    #[test]
+    fn test_leaked_guards_are_dumped() {
+        use std::mem;
+
+        let lock = Lock::named("test_leaked_guards_are_dumped", 0);
+        drop(lock.lock());
+        assert_eq!(lock.outstanding_guards(), 0);
+        let line = line!() + 1;
+        mem::forget(lock.lock_at(sync_location!()));
+        assert_eq!(lock.outstanding_guards(), 1);
+
+        let rw_lock = RwLock::named("test_leaked_guards_are_dumped_rw", 0);
+        let guard = rw_lock.read();
+        mem::forget(rw_lock.read());
+        assert_eq!(rw_lock.outstanding_guards(), (2, 0));
+        drop(guard);
+        assert_eq!(rw_lock.outstanding_guards(), (1, 0));
+
+        let dump = dump_outstanding_guards();
+        assert!(dump.contains("lock `test_leaked_guards_are_dumped` has 0 outstanding read guards \
+                               and 1 outstanding write guards"), "{}", dump);
+        assert!(dump.contains(&format!("1 acquired at {}:{}:", file!(), line)), "{}", dump);
+        assert!(dump.contains("lock `test_leaked_guards_are_dumped_rw` has 1 outstanding read \
+                               guards and 0 outstanding write guards\n    \
+                               1 acquired at an unknown location\n"), "{}", dump);
+    }
+
+    #[cfg(feature = "sync-debug")]
+    #[test]
+    fn test_released_guards_are_not_dumped() {
+        let lock = Lock::named("test_released_guards_are_not_dumped", 0);
+        let guard = lock.lock();
+        assert!(dump_outstanding_guards().contains("`test_released_guards_are_not_dumped`"));
+        drop(guard);
+        assert!(!dump_outstanding_guards().contains("`test_released_guards_are_not_dumped`"));
+    }
+
+    #[cfg(feature = "sync-debug")]
+    #[test]
+    fn test_leaked_guard_is_detected_at_checkpoint() {
+        use std::mem;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let lock = Lock::new(0);
+        drop(lock.lock());
+        lock.assert_no_guards_held();
+
+        let line = line!() + 1;
+        mem::forget(lock.lock_at(sync_location!()));
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| lock.assert_no_guards_held()));
+        let msg = payload.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.starts_with("guards are still held at a checkpoint:\nunnamed lock has 0 \
+                                 outstanding read guards and 1 outstanding write guards"),
+                "{}", msg);
+        assert!(msg.contains(&format!("1 acquired at {}:{}:", file!(), line)), "{}", msg);
+
+        // Other tests may leak guards of named locks too, so this only checks that ours is listed.
+        let rw_lock = RwLock::named("test_leaked_guard_is_detected_at_checkpoint", 0);
+        rw_lock.assert_no_guards_held();
+        mem::forget(rw_lock.write());
+        let payload = panic::catch_unwind(|| assert_no_guards_held());
+        let msg = payload.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.contains("lock `test_leaked_guard_is_detected_at_checkpoint` has 0 \
+                              outstanding read guards and 1 outstanding write guards"),
+                "{}", msg);
+    }
+}
+
+#[cfg(all(test, feature = "lock-tracing"))]
+mod trace_tests {
+    use super::*;
+    use std::sync::{Once, ONCE_INIT};
+    use sync::RwLock;
+
+    static TRACES: parking_lot::Mutex<Option<Vec<TraceRecord>>> = parking_lot::Mutex::new(None);
+
+    fn name_of(span: TraceSpan) -> &'static str {
+        match span {
+            TraceSpan::Lock { name, .. } | TraceSpan::Region { name } => name,
+        }
+    }
+
+    /// Installs the sink which collects the records returned by `traces`. Tests run
+    /// concurrently, so the records of all tests end up in the same sink.
+    fn capture_traces() {
+        static INSTALL: Once = ONCE_INIT;
+        INSTALL.call_once(|| {
+            set_trace_sink(Some(Box::new(|record| {
+                TRACES.lock().get_or_insert_with(Vec::new).push(*record);
+            })));
+        });
+    }
+
+    /// Returns the trace records emitted so far for the locks or regions named `name`.
+    fn traces(name: &str) -> Vec<TraceRecord> {
+        TRACES.lock()
+              .as_ref()
+              .map(|records| {
+                  records.iter().filter(|record| name_of(record.span) == name).cloned().collect()
+              })
+              .unwrap_or_default()
+    }
+
+    fn lock_span(name: &'static str, kind: &'static str, contended: bool) -> TraceSpan {
+        TraceSpan::Lock { name, kind, contended }
+    }
+
+    #[test]
+    fn test_trace_uncontended_acquire() {
+        capture_traces();
+        let lock = RwLock::named("test_trace_uncontended_acquire", 0);
+        *lock.write() += 1;
+        drop(lock.read());
+        let write = lock_span("test_trace_uncontended_acquire", "write", false);
+        let read = lock_span("test_trace_uncontended_acquire", "read", false);
+        assert_eq!(traces("test_trace_uncontended_acquire"), vec![
+            TraceRecord { phase: TracePhase::Enter, span: write },
+            TraceRecord { phase: TracePhase::Exit, span: write },
+            TraceRecord { phase: TracePhase::Enter, span: read },
+            TraceRecord { phase: TracePhase::Exit, span: read },
+        ]);
+    }
+
+    // Error checking makes contended acquisitions panic instead of blocking.
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_trace_contended_acquire() {
+        use std::sync::Arc;
+        use std::thread;
+        use sync::Lock;
+
+        capture_traces();
+        let lock = Arc::new(Lock::named("test_trace_contended_acquire", 0));
+        let guard = lock.lock();
+        let thread = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.lock() += 1)
+        };
+        let backoff = sync::Backoff::new();
+        while traces("test_trace_contended_acquire").len() < 3 {
+            backoff.snooze();
+        }
+        drop(guard);
+        thread.join().unwrap();
+        let uncontended = lock_span("test_trace_contended_acquire", "lock", false);
+        let contended = lock_span("test_trace_contended_acquire", "lock", true);
+        assert_eq!(traces("test_trace_contended_acquire"), vec![
+            TraceRecord { phase: TracePhase::Enter, span: uncontended },
+            TraceRecord { phase: TracePhase::Exit, span: uncontended },
+            TraceRecord { phase: TracePhase::Enter, span: contended },
+            TraceRecord { phase: TracePhase::Exit, span: contended },
+        ]);
+    }
+
+    #[test]
+    fn test_trace_parallel_region() {
+        capture_traces();
+        let (a, b) = ::sync::join_named("test_trace_parallel_region", || 1, || 2);
+        assert_eq!((a, b), (1, 2));
+        let region = TraceSpan::Region { name: "test_trace_parallel_region" };
+        assert_eq!(traces("test_trace_parallel_region"), vec![
+            TraceRecord { phase: TracePhase::Enter, span: region },
+            TraceRecord { phase: TracePhase::Exit, span: region },
+        ]);
+    }
+
+    #[test]
+    fn test_trace_record_display() {
+        let record = TraceRecord {
+            phase: TracePhase::Exit,
+            span: lock_span("query_cache", "write", true),
+        };
+        assert_eq!(record.to_string(), "exit lock name=query_cache kind=write contended=true");
+        let record = TraceRecord {
+            phase: TracePhase::Enter,
+            span: TraceSpan::Region { name: "codegen" },
+        };
+        assert_eq!(record.to_string(), "enter region name=codegen");
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A latch which threads can wait on until another thread sets it, e.g. while another thread
+//! computes a key of an `ActiveCache`.
+//!
+//! Waiting first spins with a `Backoff`, since latches are often set soon after a thread
+//! starts waiting on them. After that a thread outside of a rayon pool parks until `set`
+//! wakes it.
+//!
+//! A waiting rayon worker keeps running the tasks of its pool instead. rustc-rayon only lets a
+//! worker steal pool tasks in a loop of our own with its unstable internal API, but a worker
+//! which installs an operation on *another* pool runs the tasks of its own pool until that
+//! operation completes, and sleeps like an idle worker when there are none. So the worker
+//! installs the parking on an idle single threaded "waiter pool". `set` wakes that pool's
+//! thread, whose completed operation then wakes the worker. Waiter pools are kept in a
+//! global free-list, since creating a thread per wait would be expensive, and a worker which
+//! waits on a latch while running a task of an outer wait takes a second one. Since the
+//! worker isn't blocked, the deadlock handler of its pool isn't involved, and a pool whose
+//! workers wait on latches doesn't need one.
+//!
+//! `wait_while_helping` also runs work supplied by the caller, e.g. from a queue of pending
+//! jobs, until the latch is set or there is nothing left to do.
+//!
+//! Without cfg!(parallel_queries) no other thread can set the latch, so waiting on a latch
+//! which isn't set panics.
+
+use std::sync::atomic::Ordering;
+use super::AtomicBool;
+
+cfg_if! {
+    if #[cfg(parallel_queries)] {
+        use parking_lot::{Mutex, Condvar};
+        use rayon_core::{ThreadPool, ThreadPoolBuilder};
+        use super::{Backoff, StaticOnce};
+
+        /// The idle waiter pools, see the module docs.
+        static WAITER_POOLS: StaticOnce<Mutex<Vec<ThreadPool>>> = StaticOnce::new();
+    }
+}
+
+pub struct WaitableLatch {
+    set: AtomicBool,
+    /// Held while setting the latch, so a parking thread can't miss the notification.
+    #[cfg(parallel_queries)]
+    mutex: Mutex<()>,
+    #[cfg(parallel_queries)]
+    condvar: Condvar,
+}
+
+impl WaitableLatch {
+    pub fn new() -> Self {
+        WaitableLatch {
+            set: AtomicBool::new(false),
+            #[cfg(parallel_queries)]
+            mutex: Mutex::new(()),
+            #[cfg(parallel_queries)]
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Returns whether the latch is set, without blocking.
+    #[inline]
+    pub fn probe(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Sets the latch and wakes all threads waiting on it. Setting it again does nothing.
+    #[cfg(parallel_queries)]
+    pub fn set(&self) {
+        let _guard = self.mutex.lock();
+        if self.probe() {
+            return;
+        }
+        self.set.store(true, Ordering::Release);
+        self.condvar.notify_all();
+    }
+
+    /// Sets the latch and wakes all threads waiting on it. Setting it again does nothing.
+    #[cfg(not(parallel_queries))]
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+    }
+
+    /// Blocks until the latch is set. A rayon worker runs the tasks of its pool meanwhile.
+    #[cfg(parallel_queries)]
+    pub fn wait(&self) {
+        let backoff = Backoff::new();
+        while !backoff.is_completed() {
+            if self.probe() {
+                return;
+            }
+            backoff.snooze();
+        }
+        if ::rayon_core::current_thread_index().is_none() {
+            self.park();
+            return;
+        }
+        let pools = WAITER_POOLS.get_or_init(|| Mutex::new(Vec::new()));
+        // The lock is released before building a new pool.
+        let idle = pools.lock().pop();
+        let pool = idle.unwrap_or_else(|| {
+            ThreadPoolBuilder::new()
+                .num_threads(1)
+                .thread_name(|_| "latch-waiter".to_string())
+                .build()
+                .unwrap()
+        });
+        // This worker runs the tasks of its pool until `park` returns on the waiter pool.
+        pool.install(|| self.park());
+        pools.lock().push(pool);
+    }
+
+    /// Blocks the current thread until the latch is set.
+    #[cfg(parallel_queries)]
+    fn park(&self) {
+        let mut guard = self.mutex.lock();
+        while !self.probe() {
+            self.condvar.wait(&mut guard);
+        }
+    }
+
+    /// Blocks until the latch is set.
+    #[cfg(not(parallel_queries))]
+    pub fn wait(&self) {
+        if !self.probe() {
+            panic!("waiting on a `WaitableLatch` which isn't set would deadlock");
+        }
+    }
+
+    /// Calls `help` until the latch is set or `help` returns `false` because it has nothing
+    /// left to do, and then blocks until the latch is set. Use this to run other jobs instead
+    /// of wasting the thread while waiting. `help` should only do a small amount of work per
+    /// call, since the latch is only checked in between.
+    pub fn wait_while_helping<F: FnMut() -> bool>(&self, mut help: F) {
+        while !self.probe() {
+            if !help() {
+                break;
+            }
+        }
+        self.wait();
+    }
+}
+
+impl Default for WaitableLatch {
+    fn default() -> Self {
+        WaitableLatch::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_latch() {
+        let latch = WaitableLatch::new();
+        assert!(!latch.probe());
+        latch.set();
+        latch.set();
+        assert!(latch.probe());
+        latch.wait();
+        let mut helped = false;
+        latch.wait_while_helping(|| {
+            helped = true;
+            true
+        });
+        assert!(!helped);
+    }
+
+    #[test]
+    fn test_help_sets_latch() {
+        let latch = WaitableLatch::new();
+        let mut jobs = vec![3, 2, 1];
+        let mut done = Vec::new();
+        latch.wait_while_helping(|| {
+            match jobs.pop() {
+                Some(job) => {
+                    done.push(job);
+                    if job == 2 {
+                        latch.set();
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+        // The latch is checked after every job.
+        assert_eq!(done, [1, 2]);
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[test]
+    #[should_panic(expected = "waiting on a `WaitableLatch` which isn't set would deadlock")]
+    fn test_wait_on_unset_latch() {
+        WaitableLatch::new().wait_while_helping(|| false);
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_set_by_other_thread() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let latch = Arc::new(WaitableLatch::new());
+        let setter = latch.clone();
+        // The latch is likely set while `wait` still spins.
+        let thread = thread::spawn(move || setter.set());
+        latch.wait();
+        assert!(latch.probe());
+        thread.join().unwrap();
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_waiting_worker_helps() {
+        use std::collections::VecDeque;
+        use std::time::{Duration, Instant};
+        use std::thread;
+        use sync::{Lock, in_pool, join};
+
+        let latch = WaitableLatch::new();
+        let jobs: Lock<VecDeque<usize>> = Lock::new((0..10).collect());
+        let done = Lock::new(Vec::new());
+        let (woken_at, set_at) = in_pool(2, || join(|| {
+            latch.wait_while_helping(|| {
+                let job = jobs.lock().pop_front();
+                job.map(|job| done.lock().push(job)).is_some()
+            });
+            Instant::now()
+        }, || {
+            // Set the latch after the waiter ran out of jobs and parked.
+            while done.lock().len() < 10 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(20));
+            let set_at = Instant::now();
+            latch.set();
+            set_at
+        }));
+        // The waiter completed all jobs before the latch was set, and `set` woke it.
+        assert_eq!(*done.lock(), (0..10).collect::<Vec<_>>());
+        assert!(woken_at >= set_at);
+        assert!(woken_at - set_at < Duration::from_secs(1));
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_waiting_worker_runs_pool_tasks() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+        use std::time::{Duration, Instant};
+        use std::thread;
+        use sync::in_pool;
+
+        let latch = Arc::new(WaitableLatch::new());
+        let count = Arc::new(AtomicUsize::new(0));
+        let setter = {
+            let latch = latch.clone();
+            let count = count.clone();
+            thread::spawn(move || {
+                // Set the latch after the waiter ran the spawned tasks, or give up.
+                let start = Instant::now();
+                while count.load(Ordering::SeqCst) < 10 &&
+                      start.elapsed() < Duration::from_secs(5) {
+                    thread::yield_now();
+                }
+                let count_when_set = count.load(Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                let set_at = Instant::now();
+                latch.set();
+                (count_when_set, set_at)
+            })
+        };
+        // The waiter is the only worker, so only it can run the spawned tasks.
+        let woken_at = in_pool(1, || {
+            for _ in 0..10 {
+                let count = count.clone();
+                ::rayon::spawn(move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            latch.wait();
+            Instant::now()
+        });
+        let (count_when_set, set_at) = setter.join().unwrap();
+        assert_eq!(count_when_set, 10);
+        assert!(woken_at >= set_at);
+        assert!(woken_at - set_at < Duration::from_secs(1));
+    }
+}
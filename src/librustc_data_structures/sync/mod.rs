@@ -0,0 +1,3420 @@
+// Copyright 2017 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! This module defines types which are thread safe if cfg!(parallel_queries) is true.
+//!
+//! `Send` and `Sync` are the traits of `std::marker` if cfg!(parallel_queries) is true,
+//! otherwise they are auto traits implemented by every type. `MaybeSend` and `MaybeSync`
+//! are the same bounds as ordinary traits with blanket impls, so they don't shadow the names
+//! of the `std` traits and a where-clause can require both the real `std::marker::Send` and
+//! `MaybeSend`. Generic bounds should use `MaybeSend` and `MaybeSync`. Trait objects like
+//! `Box<dyn Emitter + Send>` still need the auto traits, since only auto traits can be added
+//! to a trait object, so the shadowing `Send` and `Sync` can't be removed.
+//!
+//! If cfg!(strict_sync_check) is true the serial build uses the `std` traits too, so a check
+//! build catches values which aren't thread safe where the parallel build would reject them.
+//! `Lrc` is then `Arc`, since `Rc` can't be made `Send`, and `OneThread` checks the thread it
+//! is used on like in the parallel build. The other serial types keep their cheap
+//! implementations, but `Atomic`, `LockCell`, `Lock`, `RwLock`, `MTLock` and `SendSyncBox` get
+//! the `Send` and `Sync` impls of their parallel versions. Those impls are unsound, the serial
+//! compiler does use threads. So the cfg is only for type checking: this module refuses to
+//! compile with it unless cfg!(strict_sync_check_metadata_only) is true as well, which the
+//! rustc wrapper of bootstrap only sets for invocations which don't generate code, i.e. for
+//! `x.py check`. `join` and `SerialScope::spawn` require `MaybeSend` like rayon, while the
+//! closures of `par_iter` are not checked.
+//!
+//! `Lrc` is an alias of either Rc or Arc.
+//!
+//! `Lock` is a mutex.
+//! It internally uses a `lock_api::Mutex` of the `RawLock` of the `raw` module, which is the
+//! raw mutex of `parking_lot` if cfg!(parallel_queries) is true and panics instead of blocking
+//! like `RefCell` otherwise. So without the `lock-metrics` feature its guards are the
+//! `lock_api` guards in both builds.
+//!
+//! `RwLock` is a read-write lock.
+//! It internally uses a `lock_api::RwLock` of the `RawRwLock` of the `raw` module, like `Lock`.
+//!
+//! `LockCell` is a thread safe version of `Cell`, with `set` and `get` operations.
+//! It can never deadlock. It uses `Cell` when
+//! cfg!(parallel_queries) is false, otherwise it is a `Lock`.
+//!
+//! `MTLock` is a mutex which disappears if cfg!(parallel_queries) is false.
+//!
+//! `MTRwLock` is a read-write lock which disappears if cfg!(parallel_queries) is false.
+//!
+//! `LazyShared` is a lazily initialized `Lrc<RwLock<T>>`.
+//!
+//! `upgrade_or_init` upgrades a `Weak`, or replaces its value if it was dropped.
+//!
+//! `lrc_str` and `lrc_string` convert strings into `Lrc<str>`s.
+//!
+//! `RawLock` and `RawRwLock` implement the `lock_api` traits and are the raw locks of `Lock` and
+//! `RwLock`, for code which is generic over the `lock_api` traits.
+//!
+//! `Sharded` splits a value into several shards with their own `Lock`. `ShardedHashMap` is a
+//! sharded `FxHashMap`. Both can be frozen into a read-only version without locks.
+//!
+//! `WorkQueue` is a `Lock<VecDeque<T>>` shared by the producers and consumers of work items.
+//!
+//! `OnceLock` is a `Once` with the interface of `std::sync::OnceLock`, which makes it easier
+//! to switch to the standard library type later.
+//!
+//! `StaticOnce` is a `Once` which can be used in a `static`.
+//!
+//! `ThreadLocalOnce` is a lazily initialized value which may only be used on one thread.
+//!
+//! `AtomicUsize`, `AtomicBool`, `AtomicU32` and `AtomicU64` are the atomics from
+//! `std::sync::atomic` if cfg!(parallel_queries) is true, otherwise they are `Cell`s
+//! with the same interface.
+//!
+//! `AtomicOrderings` provides loads and stores named after their ordering for all of them.
+//! `AtomicSaturating` provides additions and subtractions for the integer ones which saturate
+//! at the bounds of the type instead of wrapping. `AtomicMax` updates them to the maximum or
+//! minimum of their value and another one. `AtomicFetchUpdate` replaces the value with the
+//! result of a closure, like `fetch_update` of the std atomics.
+//!
+//! If the `atomic-audit` feature is enabled, the serial atomics report `load`s and `store`s
+//! which would be a lost update if cfg!(parallel_queries) was true.
+//!
+//! `UnsafeSend` is `Send` even if its value isn't, for handing values over to other threads.
+//!
+//! `CachePadded` aligns its contents to a cache line if cfg!(parallel_queries) is true.
+//!
+//! `MTRef` is a immutable reference if cfg!(parallel_queries), and an mutable reference otherwise.
+//!
+//! `SendSyncBox` is a box which checks that its contents are `Send` and `Sync` when it is
+//! created, and stays `Send` and `Sync` when it is coerced to a box of a trait object.
+//!
+//! `rustc_erase_owner!` erases a OwningRef owner into Erased or Erased + Send + Sync
+//! depending on the value of cfg!(parallel_queries).
+//!
+//! The `par_*` functions are parallel iteration helpers for common patterns on top
+//! of `par_iter`. They run sequentially if cfg!(parallel_queries) is false.
+//!
+//! `in_place_scope` is a `scope` whose body runs on the current thread, so it can borrow
+//! locals which are not `Send`. The tasks spawned in it still run on the thread pool.
+//! Outside of a thread pool they only start after the body returned.
+//!
+//! `scope_fifo` is a `scope` which starts the tasks spawned in it in the order they were
+//! spawned, e.g. for emitting diagnostics roughly in source order.
+//!
+//! `CancellationToken` is a flag for cancelling parallel work, which also cancels the
+//! tokens created from it with `child`.
+//!
+//! `Backoff` and `spin_until` implement exponential backoff for spin loops.
+//!
+//! `parallel!` runs a list of statements with nested `join`s.
+//!
+//! `TryLock` is implemented by `Lock`, `RwLock` and `Once`, so generic code can try to acquire
+//! any of them and get the same `Contended` error.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher, BuildHasher};
+use std::cmp::{self, Ordering};
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt;
+use std::ops::{Deref, DerefMut, CoerceUnsized};
+use std::marker::Unsize;
+use std::cell::{RefCell, UnsafeCell};
+use std::panic;
+use std::iter::FromIterator;
+use std::mem;
+use std::ptr;
+use std::time::Duration;
+use owning_ref::{Erased, OwningRef};
+use AbortOnDrop;
+
+pub use self::instrument::{Location, DiagnosticSink, set_diagnostic_sink,
+                           set_lock_hold_warn_threshold, join_named};
+#[cfg(any(feature = "lock-tracing", feature = "lock-metrics"))]
+pub use self::instrument::join;
+#[cfg(feature = "lock-tracing")]
+pub use self::instrument::{scope, TracePhase, TraceRecord, TraceSink, TraceSpan, set_trace_sink};
+#[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+use self::instrument::LockInfo;
+#[cfg(feature = "lock-metrics")]
+pub use self::instrument::{dump_lock_wait_times, dump_lock_stats_by_type};
+#[cfg(feature = "lock-metrics")]
+pub use self::imbalance::dump_parallel_imbalance;
+#[cfg(feature = "sync-debug")]
+pub use self::instrument::{dump_outstanding_guards, assert_no_guards_held};
+pub use self::par::{par_for_each_window, par_for_each_with_affinity, par_map_in_place,
+                    par_for_each_worker, par_collect_indexvec, par_split_process,
+                    par_accumulate, par_collect_into, par_for_each_until,
+                    par_bincount, par_for_each_yielding, par_unzip,
+                    par_argmin, par_argmax, par_for_each_send,
+                    par_for_each_collect_errors, par_for_each_prioritized,
+                    par_matching_indices, par_for_each_seeded,
+                    par_map_collect_partial, par_for_each_weighted, par_reduce_tree,
+                    par_collect_sorted, par_collect_sorted_stable};
+pub use self::cancel::{CancellationToken, Cancelled};
+pub use self::shutdown::{ShutdownOrder, drain_pool_then};
+pub use self::transactional::TransactionalLock;
+pub use self::pool::{Pool, PoolGuard};
+pub use self::owned_guard::{OwnedMappedReadGuard, read_arc_map};
+pub use self::latch::WaitableLatch;
+pub use self::id_alloc::IdAllocator;
+pub use self::weak_cache::{WeakCacheRegistry, WeakCacheRegistration, WeakHashMap, PurgeStats,
+                           purge_weak_caches};
+pub use self::sharded::{Sharded, FrozenSharded, ShardedHashMap, FrozenShardedHashMap};
+pub use self::active_cache::{ActiveCache, Probe, ClaimToken, Wait, CycleError, WaitError};
+pub use self::backoff::{Backoff, spin_until};
+pub use self::try_lock::{TryLock, Contended, TryLockError};
+pub use self::watchdog::{heartbeat, install_hang_watchdog, install_hang_watchdog_with,
+                         HangHandler};
+#[cfg(feature = "serde")]
+pub use self::serde_impls::atomic;
+pub use self::raw::{RawLock, RawRwLock};
+
+/// Returned by the branches of `parallel!`. Each branch is a closure returning this, so
+/// `return` in a branch is a type error instead of silently ending only that branch.
+#[doc(hidden)]
+pub struct ParallelBranch;
+
+/// Runs 1 to 8 expression statements in parallel, using balanced nested `join`s if
+/// cfg!(parallel_queries) is true, and runs them in order otherwise. The statements can't use
+/// `?`, `return`, `break` or `continue` to leave their branch. Panics are propagated like `join`
+/// does, so with cfg!(parallel_queries) every branch runs before a panic is resumed, while
+/// otherwise the statements after a panicking one don't run.
+#[macro_export]
+macro_rules! parallel {
+    ($a:expr;) => {{
+        let branch = || -> $crate::sync::ParallelBranch {
+            $a;
+            $crate::sync::ParallelBranch
+        };
+        branch();
+    }};
+    ($a:expr; $b:expr;) => {{
+        $crate::sync::join(|| parallel!($a;), || parallel!($b;));
+    }};
+    ($a:expr; $b:expr; $c:expr;) => {{
+        $crate::sync::join(|| parallel!($a; $b;), || parallel!($c;));
+    }};
+    ($a:expr; $b:expr; $c:expr; $d:expr;) => {{
+        $crate::sync::join(|| parallel!($a; $b;), || parallel!($c; $d;));
+    }};
+    ($a:expr; $b:expr; $c:expr; $d:expr; $e:expr;) => {{
+        $crate::sync::join(|| parallel!($a; $b; $c;), || parallel!($d; $e;));
+    }};
+    ($a:expr; $b:expr; $c:expr; $d:expr; $e:expr; $f:expr;) => {{
+        $crate::sync::join(|| parallel!($a; $b; $c;), || parallel!($d; $e; $f;));
+    }};
+    ($a:expr; $b:expr; $c:expr; $d:expr; $e:expr; $f:expr; $g:expr;) => {{
+        $crate::sync::join(|| parallel!($a; $b; $c; $d;), || parallel!($e; $f; $g;));
+    }};
+    ($a:expr; $b:expr; $c:expr; $d:expr; $e:expr; $f:expr; $g:expr; $h:expr;) => {{
+        $crate::sync::join(|| parallel!($a; $b; $c; $d;), || parallel!($e; $f; $g; $h;));
+    }};
+    ($($rest:tt)*) => {
+        compile_error!("parallel! takes 1 to 8 expressions, each followed by `;`")
+    };
+}
+
+/// Returns a reference to the value of the `Once` `$once`, initializing it with `$init` if it
+/// is not yet initialized. This expands to `Once::init_parallel`, which checks the atomic
+/// `initialized` flag of the `Once` before taking the initialization lock and again after, so
+/// `$init` is evaluated at most once and callers which find the value set only do an `Acquire`
+/// load. Prefer this over `init_locking`, which holds the lock while the initializer runs.
+#[macro_export]
+macro_rules! once_init {
+    ($once:expr, $init:expr) => {
+        $crate::sync::Once::init_parallel(&$once, || $init)
+    };
+}
+
+#[macro_use]
+mod instrument;
+mod par;
+mod cancel;
+mod active_cache;
+mod sharded;
+mod shutdown;
+mod transactional;
+mod weak_cache;
+mod pool;
+mod owned_guard;
+mod latch;
+mod id_alloc;
+mod backoff;
+mod try_lock;
+mod imbalance;
+mod watchdog;
+mod audit;
+mod raw;
+#[cfg(feature = "lock-error-checking")]
+mod error_checking;
+#[cfg(feature = "serde")]
+mod serde_impls;
+#[cfg(feature = "lock-error-checking")]
+use self::error_checking::LastHolder;
+use self::instrument::GuardKind;
+use self::raw::{InnerLock, RawLockGuard, RawMappedLockGuard};
+use self::raw::{InnerRwLock, RawReadGuard, RawMappedReadGuard, RawWriteGuard, RawMappedWriteGuard};
+use self::raw::RawUpgradableReadGuard;
+
+#[cfg(test)]
+mod test;
+
+/// Runs `f` in a new thread pool with `threads` workers, for tests which need several workers.
+/// The global pool may only have one.
+#[cfg(all(test, parallel_queries))]
+fn in_pool<R: Send>(threads: usize, f: impl FnOnce() -> R + Send) -> R {
+    ::rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap().install(f)
+}
+
+/// Runs `f` on the current thread.
+#[cfg(all(test, not(parallel_queries)))]
+fn in_pool<R>(_threads: usize, f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Creates atomics which remember where they were created. The location is used by the
+/// diagnostics of the `atomic-audit` feature. Use `sync_location!()` to get the current location.
+pub trait AtomicAt {
+    type Value;
+
+    fn new_at(v: Self::Value, location: Location) -> Self;
+}
+
+/// Loads and stores with the ordering in their name, so the ordering can't be mixed up with
+/// another argument. Without cfg!(parallel_queries) the ordering is ignored.
+pub trait AtomicOrderings {
+    type Value;
+
+    fn load_relaxed(&self) -> Self::Value;
+    fn load_acquire(&self) -> Self::Value;
+    fn load_seqcst(&self) -> Self::Value;
+    fn store_relaxed(&self, v: Self::Value);
+    fn store_release(&self, v: Self::Value);
+    fn store_seqcst(&self, v: Self::Value);
+}
+
+macro_rules! impl_atomic_orderings {
+    ($($atomic:ident: $value:ty),*) => {
+        $(
+            impl AtomicOrderings for $atomic {
+                type Value = $value;
+
+                #[inline]
+                fn load_relaxed(&self) -> $value {
+                    self.load(::std::sync::atomic::Ordering::Relaxed)
+                }
+
+                #[inline]
+                fn load_acquire(&self) -> $value {
+                    self.load(::std::sync::atomic::Ordering::Acquire)
+                }
+
+                #[inline]
+                fn load_seqcst(&self) -> $value {
+                    self.load(::std::sync::atomic::Ordering::SeqCst)
+                }
+
+                #[inline]
+                fn store_relaxed(&self, v: $value) {
+                    self.store(v, ::std::sync::atomic::Ordering::Relaxed)
+                }
+
+                #[inline]
+                fn store_release(&self, v: $value) {
+                    self.store(v, ::std::sync::atomic::Ordering::Release)
+                }
+
+                #[inline]
+                fn store_seqcst(&self, v: $value) {
+                    self.store(v, ::std::sync::atomic::Ordering::SeqCst)
+                }
+            }
+        )*
+    }
+}
+
+/// Additions and subtractions which saturate at the bounds of the type instead of wrapping,
+/// for counters which must not wrap around. They return the previous value like `fetch_add`.
+/// If cfg!(parallel_queries) is true they are compare-and-swap loops, which are more expensive
+/// than `fetch_add` and retry while other threads modify the value.
+pub trait AtomicSaturating {
+    type Value;
+
+    fn fetch_add_saturating(&self, v: Self::Value, order: ::std::sync::atomic::Ordering)
+                            -> Self::Value;
+    fn fetch_sub_saturating(&self, v: Self::Value, order: ::std::sync::atomic::Ordering)
+                            -> Self::Value;
+}
+
+/// Updates a high-water or low-water mark, e.g. the deepest query stack seen so far, and
+/// returns whether the stored value changed. This is `fetch_max` or `fetch_min`, which return
+/// the previous value instead.
+pub trait AtomicMax {
+    type Value;
+
+    fn update_max(&self, v: Self::Value, order: ::std::sync::atomic::Ordering) -> bool;
+    fn update_min(&self, v: Self::Value, order: ::std::sync::atomic::Ordering) -> bool;
+}
+
+macro_rules! impl_atomic_max {
+    ($($atomic:ident: $value:ty),*) => {
+        $(
+            impl AtomicMax for $atomic {
+                type Value = $value;
+
+                #[inline]
+                fn update_max(&self, v: $value, order: ::std::sync::atomic::Ordering) -> bool {
+                    self.fetch_max(v, order) < v
+                }
+
+                #[inline]
+                fn update_min(&self, v: $value, order: ::std::sync::atomic::Ordering) -> bool {
+                    self.fetch_min(v, order) > v
+                }
+            }
+        )*
+    }
+}
+
+impl_atomic_max!(AtomicUsize: usize, AtomicU32: u32, AtomicU64: u64);
+
+/// Like `fetch_update` of the std atomics, which isn't available yet. Calls `f` with the value
+/// and replaces it with the result of `f` if that is `Some`, returning the previous value in
+/// `Ok`. If `f` returns `None` the value isn't changed and `Err` is returned with the value `f`
+/// was called with. `set_order` is the ordering of a successful update, `fetch_order` the one
+/// of the loads. `fetch_order` can't be `Release` or `AcqRel` and can't be stronger than
+/// `set_order`, like for `compare_exchange`.
+///
+/// If cfg!(parallel_queries) is true this is a compare-and-swap loop, which calls `f` again if
+/// another thread modified the value in between, so `f` shouldn't have side effects. Otherwise
+/// `f` is called once.
+///
+/// The integer atomics of `std` have an unstable `fetch_update` with the arguments in another
+/// order, so calls in the parallel build trigger the `unstable_name_collisions` lint, which
+/// must be allowed for now. The method of this trait is the one which is called.
+pub trait AtomicFetchUpdate {
+    type Value;
+
+    fn fetch_update<F>(&self,
+                       set_order: ::std::sync::atomic::Ordering,
+                       fetch_order: ::std::sync::atomic::Ordering,
+                       f: F)
+                       -> Result<Self::Value, Self::Value>
+        where F: FnMut(Self::Value) -> Option<Self::Value>;
+}
+
+pub fn serial_join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+    where A: FnOnce() -> RA + MaybeSend,
+          B: FnOnce() -> RB + MaybeSend,
+          RA: MaybeSend,
+          RB: MaybeSend
+{
+    (audit::task(oper_a), audit::task(oper_b))
+}
+
+/// The closures spawned in a `serial_scope`. A `FnOnce` can't be called in a box, so they are
+/// wrapped in a `FnMut` which panics when it is called twice.
+type SerialJobs<'scope> = RefCell<VecDeque<Box<dyn FnMut(&SerialScope<'scope>) + 'scope>>>;
+
+pub struct SerialScope<'scope> {
+    jobs: SerialJobs<'scope>,
+}
+
+impl<'scope> SerialScope<'scope> {
+    /// Queues `f` to run after the closure which spawned it returns, like rayon may do.
+    pub fn spawn<F>(&self, f: F)
+        where F: FnOnce(&SerialScope<'scope>) + MaybeSend + 'scope
+    {
+        let mut f = Some(f);
+        self.jobs.borrow_mut().push_back(Box::new(move |scope| {
+            (f.take().expect("spawned closure called twice"))(scope)
+        }));
+    }
+}
+
+/// Runs `f` and then the closures spawned in it, in the order they were spawned, including the
+/// closures those spawn. Like with rayon, a spawned closure doesn't run before the closure
+/// spawning it returns, and all of them have run when this returns. If any of them panics, the
+/// others still run and then the first panic is resumed.
+pub fn serial_scope<'scope, F, R>(f: F) -> R
+    where F: FnOnce(&SerialScope<'scope>) -> R
+{
+    let scope = SerialScope { jobs: RefCell::new(VecDeque::new()) };
+    let mut result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(&scope)));
+    loop {
+        let job = scope.jobs.borrow_mut().pop_front();
+        let mut job = match job {
+            Some(job) => job,
+            None => break,
+        };
+        let job_result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            audit::task(|| job(&scope))
+        }));
+        if let (&Ok(_), Err(panic)) = (&result, job_result) {
+            result = Err(panic);
+        }
+    }
+    match result {
+        Ok(result) => result,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+#[cfg(all(strict_sync_check, not(strict_sync_check_metadata_only)))]
+compile_error!("cfg(strict_sync_check) makes the serial locks and cells `Send` and `Sync`, \
+                which is unsound, so it can only be used with `x.py check`");
+
+cfg_if! {
+    if #[cfg(any(parallel_queries, strict_sync_check))] {
+        pub use std::marker::Send as Send;
+        pub use std::marker::Sync as Sync;
+
+        pub trait MaybeSend: Send {}
+        pub trait MaybeSync: Sync {}
+
+        impl<T: ?Sized + Send> MaybeSend for T {}
+        impl<T: ?Sized + Sync> MaybeSync for T {}
+
+        pub use std::sync::Arc as Lrc;
+        pub use std::sync::Weak as Weak;
+
+        pub type MetadataRef = OwningRef<Box<dyn Erased + Send + Sync>, [u8]>;
+
+        #[macro_export]
+        macro_rules! rustc_erase_owner {
+            ($v:expr) => {{
+                let v = $v;
+                ::rustc_data_structures::sync::assert_send_val(&v);
+                v.erase_send_sync_owner()
+            }}
+        }
+    } else {
+        pub auto trait Send {}
+        pub auto trait Sync {}
+
+        impl<T: ?Sized> Send for T {}
+        impl<T: ?Sized> Sync for T {}
+
+        pub trait MaybeSend {}
+        pub trait MaybeSync {}
+
+        impl<T: ?Sized> MaybeSend for T {}
+        impl<T: ?Sized> MaybeSync for T {}
+
+        pub use std::rc::Rc as Lrc;
+        pub use std::rc::Weak as Weak;
+
+        pub type MetadataRef = OwningRef<Box<dyn Erased>, [u8]>;
+
+        #[macro_export]
+        macro_rules! rustc_erase_owner {
+            ($v:expr) => {
+                $v.erase_owner()
+            }
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(not(parallel_queries))] {
+        #[cfg(not(any(feature = "lock-tracing", feature = "lock-metrics")))]
+        pub use self::serial_join as join;
+        #[cfg(not(feature = "lock-tracing"))]
+        pub use self::serial_scope as scope;
+        pub use self::serial_scope as in_place_scope;
+        pub use self::serial_scope as scope_fifo;
+
+        pub use std::iter::Iterator as ParallelIterator;
+
+        pub fn par_iter<T: IntoIterator>(t: T) -> T::IntoIter {
+            t.into_iter()
+        }
+
+        use std::cell::Cell;
+        use std::ops::{BitAnd, BitOr, BitXor, Not};
+        use std::sync::atomic::Ordering as AtomicOrdering;
+        #[cfg(feature = "atomic-audit")]
+        use self::audit::AtomicAudit;
+
+        #[derive(Debug)]
+        pub struct Atomic<T: Copy> {
+            value: Cell<T>,
+            #[cfg(feature = "atomic-audit")]
+            audit: AtomicAudit,
+        }
+
+        impl<T: Copy> Atomic<T> {
+            #[inline]
+            pub fn new(v: T) -> Self {
+                Atomic::with_location(v, None)
+            }
+
+            #[inline]
+            fn with_location(v: T, _location: Option<Location>) -> Self {
+                Atomic {
+                    value: Cell::new(v),
+                    #[cfg(feature = "atomic-audit")]
+                    audit: AtomicAudit::new(_location),
+                }
+            }
+
+            /// Returns a mutable reference to the value, like `get_mut` of the std atomics. This
+            /// isn't audited since the exclusive borrow rules out concurrent accesses.
+            #[inline]
+            pub fn get_mut(&mut self) -> &mut T {
+                self.value.get_mut()
+            }
+
+            #[inline]
+            pub fn into_inner(self) -> T {
+                self.value.into_inner()
+            }
+
+            #[inline]
+            pub fn load(&self, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.load();
+                self.value.get()
+            }
+
+            #[inline]
+            pub fn store(&self, val: T, _: AtomicOrdering) {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.store();
+                self.value.set(val)
+            }
+
+            #[inline]
+            pub fn swap(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                self.value.replace(val)
+            }
+        }
+
+        impl<T: Copy> AtomicAt for Atomic<T> {
+            type Value = T;
+
+            #[inline]
+            fn new_at(v: T, location: Location) -> Self {
+                Atomic::with_location(v, Some(location))
+            }
+        }
+
+        impl<T: Copy + PartialEq> Atomic<T> {
+            #[inline]
+            pub fn compare_exchange(&self,
+                                    current: T,
+                                    new: T,
+                                    _: AtomicOrdering,
+                                    _: AtomicOrdering)
+                                    -> Result<T, T> {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let read = self.value.get();
+                if read == current {
+                    self.value.set(new);
+                    Ok(read)
+                } else {
+                    Err(read)
+                }
+            }
+        }
+
+        impl<T: Copy + PartialEq> AtomicFetchUpdate for Atomic<T> {
+            type Value = T;
+
+            #[inline]
+            fn fetch_update<F>(&self, _: AtomicOrdering, _: AtomicOrdering, mut f: F)
+                               -> Result<T, T>
+                where F: FnMut(T) -> Option<T>
+            {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                match f(old) {
+                    Some(new) => {
+                        self.value.set(new);
+                        Ok(old)
+                    }
+                    None => Err(old),
+                }
+            }
+        }
+
+        impl<T: Ord + Copy> Atomic<T> {
+            #[inline]
+            pub fn fetch_max(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                self.value.set(cmp::max(old, val));
+                old
+            }
+
+            #[inline]
+            pub fn fetch_min(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                self.value.set(cmp::min(old, val));
+                old
+            }
+        }
+
+        impl<T: BitAnd<Output=T> + Copy> Atomic<T> {
+            #[inline]
+            pub fn fetch_and(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                self.value.set(old & val);
+                old
+            }
+        }
+
+        impl<T: BitAnd<Output=T> + Not<Output=T> + Copy> Atomic<T> {
+            #[inline]
+            pub fn fetch_nand(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                self.value.set(!(old & val));
+                old
+            }
+        }
+
+        impl<T: BitOr<Output=T> + Copy> Atomic<T> {
+            #[inline]
+            pub fn fetch_or(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                self.value.set(old | val);
+                old
+            }
+        }
+
+        impl<T: BitXor<Output=T> + Copy> Atomic<T> {
+            #[inline]
+            pub fn fetch_xor(&self, val: T, _: AtomicOrdering) -> T {
+                #[cfg(feature = "atomic-audit")]
+                self.audit.read_modify_write();
+                let old = self.value.get();
+                self.value.set(old ^ val);
+                old
+            }
+        }
+
+        pub type AtomicUsize = Atomic<usize>;
+        pub type AtomicBool = Atomic<bool>;
+        pub type AtomicU32 = Atomic<u32>;
+        pub type AtomicU64 = Atomic<u64>;
+
+        // Like the atomics of `std`. Only for type checking, see the module docs.
+        #[cfg(strict_sync_check)]
+        unsafe impl<T: Copy + Send> Sync for Atomic<T> {}
+
+        impl_atomic_orderings!(AtomicBool: bool, AtomicUsize: usize,
+                               AtomicU32: u32, AtomicU64: u64);
+
+        macro_rules! impl_atomic_saturating {
+            ($($atomic:ident: $value:ty),*) => {
+                $(
+                    impl AtomicSaturating for $atomic {
+                        type Value = $value;
+
+                        #[inline]
+                        fn fetch_add_saturating(&self, v: $value, _: AtomicOrdering) -> $value {
+                            #[cfg(feature = "atomic-audit")]
+                            self.audit.read_modify_write();
+                            let old = self.value.get();
+                            self.value.set(old.saturating_add(v));
+                            old
+                        }
+
+                        #[inline]
+                        fn fetch_sub_saturating(&self, v: $value, _: AtomicOrdering) -> $value {
+                            #[cfg(feature = "atomic-audit")]
+                            self.audit.read_modify_write();
+                            let old = self.value.get();
+                            self.value.set(old.saturating_sub(v));
+                            old
+                        }
+                    }
+                )*
+            }
+        }
+
+        impl_atomic_saturating!(AtomicUsize: usize, AtomicU32: u32, AtomicU64: u64);
+
+        macro_rules! impl_atomic_fetch_add_sub {
+            ($($atomic:ident: $value:ty),*) => {
+                $(
+                    impl $atomic {
+                        /// Adds `val` and returns the previous value. Like for the
+                        /// atomics of `std`, this wraps around at the bounds of the type
+                        /// instead of panicking on overflow.
+                        #[inline]
+                        pub fn fetch_add(&self, val: $value, _: AtomicOrdering) -> $value {
+                            #[cfg(feature = "atomic-audit")]
+                            self.audit.read_modify_write();
+                            let old = self.value.get();
+                            self.value.set(old.wrapping_add(val));
+                            old
+                        }
+
+                        /// Subtracts `val` and returns the previous value. Wraps around
+                        /// like `fetch_add`.
+                        #[inline]
+                        pub fn fetch_sub(&self, val: $value, _: AtomicOrdering) -> $value {
+                            #[cfg(feature = "atomic-audit")]
+                            self.audit.read_modify_write();
+                            let old = self.value.get();
+                            self.value.set(old.wrapping_sub(val));
+                            old
+                        }
+                    }
+                )*
+            }
+        }
+
+        impl_atomic_fetch_add_sub!(AtomicUsize: usize, AtomicU32: u32, AtomicU64: u64);
+
+        #[derive(Debug)]
+        pub struct WorkerLocal<T>(OneThread<T>);
+
+        impl<T> WorkerLocal<T> {
+            /// Creates a new worker local where the `initial` closure computes the
+            /// value this worker local should take for each thread in the thread pool.
+            #[inline]
+            pub fn new<F: FnMut(usize) -> T>(mut f: F) -> WorkerLocal<T> {
+                WorkerLocal(OneThread::new(f(0)))
+            }
+
+            /// Returns the worker-local value for each thread
+            #[inline]
+            pub fn into_inner(self) -> Vec<T> {
+                vec![OneThread::into_inner(self.0)]
+            }
+        }
+
+        impl<T> Deref for WorkerLocal<T> {
+            type Target = T;
+
+            #[inline(always)]
+            fn deref(&self) -> &T {
+                &*self.0
+            }
+        }
+
+        pub type MTRef<'a, T> = &'a mut T;
+
+        #[derive(Debug, Default, PartialEq, Eq, Hash)]
+        pub struct MTLock<T>(T);
+
+        // Like `MTLock<T>(Lock<T>)`. Only for type checking, see the module docs.
+        #[cfg(strict_sync_check)]
+        unsafe impl<T: Send> Sync for MTLock<T> {}
+
+        impl<T> MTLock<T> {
+            #[inline(always)]
+            pub fn new(inner: T) -> Self {
+                MTLock(inner)
+            }
+
+            #[inline(always)]
+            pub fn into_inner(self) -> T {
+                self.0
+            }
+
+            #[inline(always)]
+            pub fn get_mut(&mut self) -> &mut T {
+                &mut self.0
+            }
+
+            #[inline(always)]
+            pub fn lock(&self) -> &T {
+                &self.0
+            }
+
+            #[inline(always)]
+            pub fn lock_mut(&mut self) -> &mut T {
+                &mut self.0
+            }
+        }
+
+        // FIXME: Probably a bad idea (in the threaded case)
+        impl<T: Clone> Clone for MTLock<T> {
+            #[inline]
+            fn clone(&self) -> Self {
+                MTLock(self.0.clone())
+            }
+        }
+
+        #[derive(Debug, Default)]
+        pub struct MTRwLock<T>(T);
+
+        impl<T> MTRwLock<T> {
+            #[inline(always)]
+            pub fn new(inner: T) -> Self {
+                MTRwLock(inner)
+            }
+
+            #[inline(always)]
+            pub fn into_inner(self) -> T {
+                self.0
+            }
+
+            #[inline(always)]
+            pub fn get_mut(&mut self) -> &mut T {
+                &mut self.0
+            }
+
+            #[inline(always)]
+            pub fn read(&self) -> &T {
+                &self.0
+            }
+
+            #[inline(always)]
+            pub fn write(&mut self) -> &mut T {
+                &mut self.0
+            }
+        }
+
+        // FIXME: Probably a bad idea (in the threaded case)
+        impl<T: Clone> Clone for MTRwLock<T> {
+            #[inline]
+            fn clone(&self) -> Self {
+                MTRwLock(self.0.clone())
+            }
+        }
+
+        pub struct LockCell<T>(Cell<T>);
+
+        // Like `LockCell<T>(Lock<T>)`. Only for type checking, see the module docs.
+        #[cfg(strict_sync_check)]
+        unsafe impl<T: Send> Sync for LockCell<T> {}
+
+        impl<T> LockCell<T> {
+            #[inline(always)]
+            pub fn new(inner: T) -> Self {
+                LockCell(Cell::new(inner))
+            }
+
+            #[inline(always)]
+            pub fn into_inner(self) -> T {
+                self.0.into_inner()
+            }
+
+            #[inline(always)]
+            pub fn set(&self, new_inner: T) {
+                self.0.set(new_inner);
+            }
+
+            #[inline(always)]
+            pub fn get(&self) -> T where T: Copy {
+                self.0.get()
+            }
+
+            #[inline(always)]
+            pub fn set_mut(&mut self, new_inner: T) {
+                self.0.set(new_inner);
+            }
+
+            #[inline(always)]
+            pub fn get_mut(&mut self) -> T where T: Copy {
+                self.0.get()
+            }
+        }
+
+        impl<T> LockCell<Option<T>> {
+            #[inline(always)]
+            pub fn take(&self) -> Option<T> {
+                unsafe { (*self.0.as_ptr()).take() }
+            }
+        }
+    } else {
+        pub use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU32, AtomicU64};
+
+        macro_rules! impl_atomic_at {
+            ($($atomic:ident: $value:ty),*) => {
+                $(
+                    impl AtomicAt for $atomic {
+                        type Value = $value;
+
+                        #[inline]
+                        fn new_at(v: $value, _location: Location) -> Self {
+                            $atomic::new(v)
+                        }
+                    }
+                )*
+            }
+        }
+
+        impl_atomic_at!(AtomicBool: bool, AtomicUsize: usize, AtomicU32: u32, AtomicU64: u64);
+        impl_atomic_orderings!(AtomicBool: bool, AtomicUsize: usize,
+                               AtomicU32: u32, AtomicU64: u64);
+
+        /// The ordering of a failed compare-and-swap, which can't be a release.
+        #[inline]
+        fn failure_ordering(order: std::sync::atomic::Ordering) -> std::sync::atomic::Ordering {
+            use std::sync::atomic::Ordering::*;
+            match order {
+                Release => Relaxed,
+                AcqRel => Acquire,
+                order => order,
+            }
+        }
+
+        macro_rules! impl_atomic_saturating {
+            ($($atomic:ident: $value:ty),*) => {
+                $(
+                    impl AtomicSaturating for $atomic {
+                        type Value = $value;
+
+                        #[inline]
+                        fn fetch_add_saturating(&self,
+                                                v: $value,
+                                                order: std::sync::atomic::Ordering)
+                                                -> $value {
+                            let mut old = self.load(failure_ordering(order));
+                            loop {
+                                let new = old.saturating_add(v);
+                                match self.compare_exchange_weak(old, new, order,
+                                                                 failure_ordering(order)) {
+                                    Ok(old) => return old,
+                                    Err(read) => old = read,
+                                }
+                            }
+                        }
+
+                        #[inline]
+                        fn fetch_sub_saturating(&self,
+                                                v: $value,
+                                                order: std::sync::atomic::Ordering)
+                                                -> $value {
+                            let mut old = self.load(failure_ordering(order));
+                            loop {
+                                let new = old.saturating_sub(v);
+                                match self.compare_exchange_weak(old, new, order,
+                                                                 failure_ordering(order)) {
+                                    Ok(old) => return old,
+                                    Err(read) => old = read,
+                                }
+                            }
+                        }
+                    }
+                )*
+            }
+        }
+
+        impl_atomic_saturating!(AtomicUsize: usize, AtomicU32: u32, AtomicU64: u64);
+
+        macro_rules! impl_atomic_fetch_update {
+            ($($atomic:ident: $value:ty),*) => {
+                $(
+                    impl AtomicFetchUpdate for $atomic {
+                        type Value = $value;
+
+                        #[inline]
+                        fn fetch_update<F>(&self,
+                                           set_order: std::sync::atomic::Ordering,
+                                           fetch_order: std::sync::atomic::Ordering,
+                                           mut f: F)
+                                           -> Result<$value, $value>
+                            where F: FnMut($value) -> Option<$value>
+                        {
+                            let mut old = self.load(fetch_order);
+                            while let Some(new) = f(old) {
+                                match self.compare_exchange_weak(old, new, set_order,
+                                                                 fetch_order) {
+                                    Ok(old) => return Ok(old),
+                                    Err(read) => old = read,
+                                }
+                            }
+                            Err(old)
+                        }
+                    }
+                )*
+            }
+        }
+
+        impl_atomic_fetch_update!(AtomicBool: bool, AtomicUsize: usize,
+                                  AtomicU32: u32, AtomicU64: u64);
+
+        pub type MTRef<'a, T> = &'a T;
+
+        // The comparisons use the ones of `Lock`, which avoid deadlocks
+        #[derive(Debug, Default, PartialEq, Eq, Hash)]
+        pub struct MTLock<T>(Lock<T>);
+
+        impl<T> MTLock<T> {
+            #[inline(always)]
+            pub fn new(inner: T) -> Self {
+                MTLock(Lock::new(inner))
+            }
+
+            #[inline(always)]
+            pub fn into_inner(self) -> T {
+                self.0.into_inner()
+            }
+
+            #[inline(always)]
+            pub fn get_mut(&mut self) -> &mut T {
+                self.0.get_mut()
+            }
+
+            #[inline(always)]
+            pub fn lock(&self) -> LockGuard<T> {
+                self.0.lock()
+            }
+
+            #[inline(always)]
+            pub fn lock_mut(&self) -> LockGuard<T> {
+                self.lock()
+            }
+        }
+
+        #[derive(Debug, Default)]
+        pub struct MTRwLock<T>(RwLock<T>);
+
+        impl<T> MTRwLock<T> {
+            #[inline(always)]
+            pub fn new(inner: T) -> Self {
+                MTRwLock(RwLock::new(inner))
+            }
+
+            #[inline(always)]
+            pub fn into_inner(self) -> T {
+                self.0.into_inner()
+            }
+
+            #[inline(always)]
+            pub fn get_mut(&mut self) -> &mut T {
+                self.0.get_mut()
+            }
+
+            #[inline(always)]
+            pub fn read(&self) -> ReadGuard<T> {
+                self.0.read()
+            }
+
+            #[inline(always)]
+            pub fn write(&self) -> WriteGuard<T> {
+                self.0.write()
+            }
+        }
+
+        use std;
+        #[cfg(not(any(feature = "lock-tracing", feature = "lock-metrics")))]
+        pub use rayon::join;
+        #[cfg(not(feature = "lock-tracing"))]
+        pub use rayon::scope;
+
+        /// The closures spawned in an `in_place_scope` outside of a thread pool, which are
+        /// wrapped in a `FnMut` like the ones of `scope_fifo`.
+        type DeferredJobs<'scope> =
+            InnerLock<Vec<Box<dyn FnMut(&::rayon::Scope<'scope>) + Send + 'scope>>>;
+
+        pub struct InPlaceScope<'a, 'scope: 'a> {
+            /// The scope of the thread pool, or `None` outside of a thread pool.
+            scope: Option<&'a ::rayon::Scope<'scope>>,
+            deferred: DeferredJobs<'scope>,
+        }
+
+        impl<'a, 'scope> InPlaceScope<'a, 'scope> {
+            /// Spawns `f` in the scope. Outside of a thread pool it only starts after the body
+            /// of the scope returned.
+            pub fn spawn<F>(&self, f: F)
+                where F: FnOnce(&::rayon::Scope<'scope>) + Send + 'scope
+            {
+                match self.scope {
+                    Some(scope) => scope.spawn(f),
+                    None => {
+                        let mut f = Some(f);
+                        self.deferred.lock().push(Box::new(move |scope| {
+                            (f.take().expect("spawned closure called twice"))(scope)
+                        }));
+                    }
+                }
+            }
+        }
+
+        /// Same as `scope`, but `op` runs on the current thread, so it doesn't need to be
+        /// `Send` and can borrow locals which aren't. The closures spawned in the scope still
+        /// need to be `Send`, they run on the thread pool like the ones of `scope`. `scope`
+        /// itself runs `op` on the current thread if it is a worker of a thread pool, and on a
+        /// worker of the global thread pool otherwise. So outside of a thread pool the
+        /// closures spawned by `op` are collected and only spawned in a `scope` of the global
+        /// thread pool after `op` returned. They are dropped without running if `op` panics.
+        pub fn in_place_scope<'scope, OP, R>(op: OP) -> R
+            where OP: for<'a> FnOnce(&InPlaceScope<'a, 'scope>) -> R + 'scope
+        {
+            struct AssertSend<T>(T);
+            // `op` and its result stay on the current thread
+            unsafe impl<T> std::marker::Send for AssertSend<T> {}
+
+            if ::rayon_core::current_thread_index().is_some() {
+                let op = AssertSend(op);
+                return ::rayon::scope(move |scope| {
+                    AssertSend((op.0)(&InPlaceScope {
+                        scope: Some(scope),
+                        deferred: InnerLock::new(Vec::new()),
+                    }))
+                }).0;
+            }
+            let in_place = InPlaceScope {
+                scope: None,
+                deferred: InnerLock::new(Vec::new()),
+            };
+            let result = op(&in_place);
+            let deferred = in_place.deferred.into_inner();
+            ::rayon::scope(move |scope| {
+                for mut job in deferred {
+                    scope.spawn(move |scope| job(scope));
+                }
+            });
+            result
+        }
+
+        /// The closures spawned in a `scope_fifo`, in the order they were spawned. A `FnOnce`
+        /// can't be called in a box, so they are wrapped in a `FnMut`.
+        type FifoJobs<'scope> =
+            InnerLock<VecDeque<Box<dyn for<'a> FnMut(&FifoScope<'a, 'scope>) + Send + 'scope>>>;
+
+        pub struct FifoScope<'a, 'scope: 'a> {
+            scope: &'a ::rayon::Scope<'scope>,
+            jobs: Lrc<FifoJobs<'scope>>,
+        }
+
+        impl<'a, 'scope> FifoScope<'a, 'scope> {
+            /// Spawns `f` in the scope. The closures spawned in the scope start in the order
+            /// they were spawned, but they may finish in any order.
+            pub fn spawn<F>(&self, f: F)
+                where F: for<'b> FnOnce(&FifoScope<'b, 'scope>) + Send + 'scope
+            {
+                let mut f = Some(f);
+                self.jobs.lock().push_back(Box::new(move |scope| {
+                    (f.take().expect("spawned closure called twice"))(scope)
+                }));
+                // rayon runs the tasks of a worker in LIFO order, so each task runs the oldest
+                // closure instead of the one it was spawned for.
+                let jobs = self.jobs.clone();
+                self.scope.spawn(move |scope| {
+                    let job = jobs.lock().pop_front();
+                    let mut job = job.expect("a task of `scope_fifo` has no closure");
+                    job(&FifoScope { scope, jobs });
+                });
+            }
+        }
+
+        /// Same as `scope`, but the closures spawned in the scope start in the order they were
+        /// spawned, like with `scope_fifo` of newer versions of rayon. The serial `scope`
+        /// already runs them in this order.
+        pub fn scope_fifo<'scope, OP, R>(op: OP) -> R
+            where OP: for<'a> FnOnce(&FifoScope<'a, 'scope>) -> R + 'scope + Send,
+                  R: Send
+        {
+            let jobs = Lrc::new(InnerLock::new(VecDeque::new()));
+            ::rayon::scope(move |scope| op(&FifoScope { scope, jobs }))
+        }
+
+        pub use rayon_core::WorkerLocal;
+
+        pub use rayon::iter::ParallelIterator;
+        use rayon::iter::IntoParallelIterator;
+
+        pub fn par_iter<T: IntoParallelIterator>(t: T) -> T::Iter {
+            t.into_par_iter()
+        }
+
+        /// This makes locks panic if they are already held.
+        /// It is only useful when you are running in a single thread.
+        /// It is enabled by the `lock-error-checking` feature.
+        const ERROR_CHECKING: bool = cfg!(feature = "lock-error-checking");
+
+        pub struct LockCell<T>(Lock<T>);
+
+        impl<T> LockCell<T> {
+            #[inline(always)]
+            pub fn new(inner: T) -> Self {
+                LockCell(Lock::new(inner))
+            }
+
+            #[inline(always)]
+            pub fn into_inner(self) -> T {
+                self.0.into_inner()
+            }
+
+            #[inline(always)]
+            pub fn set(&self, new_inner: T) {
+                *self.0.lock() = new_inner;
+            }
+
+            #[inline(always)]
+            pub fn get(&self) -> T where T: Copy {
+                *self.0.lock()
+            }
+
+            #[inline(always)]
+            pub fn set_mut(&mut self, new_inner: T) {
+                *self.0.get_mut() = new_inner;
+            }
+
+            #[inline(always)]
+            pub fn get_mut(&mut self) -> T where T: Copy {
+                *self.0.get_mut()
+            }
+        }
+
+        impl<T> LockCell<Option<T>> {
+            #[inline(always)]
+            pub fn take(&self) -> Option<T> {
+                self.0.lock().take()
+            }
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "lock-metrics")] {
+        use self::instrument::InstrumentedGuard;
+
+        pub type ReadGuard<'a, T> = InstrumentedGuard<'a, RawReadGuard<'a, T>>;
+        pub type MappedReadGuard<'a, T> = InstrumentedGuard<'a, RawMappedReadGuard<'a, T>>;
+        pub type WriteGuard<'a, T> = InstrumentedGuard<'a, RawWriteGuard<'a, T>>;
+        pub type MappedWriteGuard<'a, T> = InstrumentedGuard<'a, RawMappedWriteGuard<'a, T>>;
+        pub type LockGuard<'a, T> = InstrumentedGuard<'a, RawLockGuard<'a, T>>;
+        pub type MappedLockGuard<'a, T> = InstrumentedGuard<'a, RawMappedLockGuard<'a, T>>;
+    } else {
+        pub type ReadGuard<'a, T> = RawReadGuard<'a, T>;
+        pub type MappedReadGuard<'a, T> = RawMappedReadGuard<'a, T>;
+        pub type WriteGuard<'a, T> = RawWriteGuard<'a, T>;
+        pub type MappedWriteGuard<'a, T> = RawMappedWriteGuard<'a, T>;
+        pub type LockGuard<'a, T> = RawLockGuard<'a, T>;
+        pub type MappedLockGuard<'a, T> = RawMappedLockGuard<'a, T>;
+    }
+}
+
+pub fn assert_sync<T: ?Sized + Sync>() {}
+pub fn assert_send<T: ?Sized + Send>() {}
+pub fn assert_send_val<T: ?Sized + Send>(_t: &T) {}
+pub fn assert_send_sync_val<T: ?Sized + Sync + Send>(_t: &T) {}
+
+/// Checks that a value can be erased to a `dyn Erased + Send + Sync`, like `rustc_erase_owner!`
+/// does for owners. This must be called before the value is boxed as a trait object like
+/// `Box<dyn Any>`, since it can't be checked once the type is erased. `SendSyncBox` does this
+/// check when it is created.
+pub fn assert_erased_send_sync(_v: &(dyn Erased + Send + Sync)) {}
+
+pub trait HashMapExt<K, V> {
+    /// Same as HashMap::insert, but it may panic if there's already an
+    /// entry for `key` with a value not equal to `value`
+    fn insert_same(&mut self, key: K, value: V);
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> HashMapExt<K, V> for HashMap<K, V, S> {
+    fn insert_same(&mut self, key: K, value: V) {
+        self.entry(key).and_modify(|old| assert!(*old == value)).or_insert(value);
+    }
+}
+
+pub trait LockOptionExt<T> {
+    /// Initializes the value with `f` if it is `None` and returns a guard to the inner value.
+    /// Unlike with `Once`, the value can be changed or reset to `None` afterwards.
+    fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> MappedLockGuard<T>;
+}
+
+impl<T> LockOptionExt<T> for Lock<Option<T>> {
+    #[inline]
+    fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> MappedLockGuard<T> {
+        LockGuard::map(self.lock(), |value| value.get_or_insert_with(f))
+    }
+}
+
+/// The result of `Once::init_racy`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RaceOutcome<'a, T: 'a> {
+    /// The value was already initialized, so the closure was not called.
+    Initialized(&'a T),
+    /// The value computed by the closure initialized the `Once`.
+    Won(&'a T),
+    /// Another value initialized the `Once` while the closure was running. `yours` is the value
+    /// the closure computed.
+    Lost { winner: &'a T, yours: T },
+}
+
+impl<'a, T> RaceOutcome<'a, T> {
+    /// Returns the value of the `Once`.
+    #[inline]
+    pub fn value(&self) -> &'a T {
+        match *self {
+            RaceOutcome::Initialized(value) |
+            RaceOutcome::Won(value) |
+            RaceOutcome::Lost { winner: value, .. } => value,
+        }
+    }
+}
+
+/// A type whose inner value can be written once and then will stay read-only
+pub struct Once<T> {
+    /// Set with `Release` ordering after `value` is initialized. From then on `value` is only
+    /// mutated by methods taking `&mut self`, so a thread which finds this set with `Acquire`
+    /// ordering can read `value` without taking `lock`.
+    initialized: AtomicBool,
+    /// Held while `value` is checked and initialized.
+    lock: Lock<()>,
+    value: UnsafeCell<Option<T>>,
+    /// Where the value was initialized, if it is initialized and the location is known.
+    #[cfg(feature = "sync-debug")]
+    initialized_at: Lock<Option<Location>>,
+    /// Held by the thread running the closure of `init_parallel`.
+    #[cfg(parallel_queries)]
+    initializing: InnerLock<()>,
+    /// The number of times the value was replaced or taken. These need `&mut self`, so this
+    /// doesn't need to be atomic.
+    generation: u64,
+}
+
+// `get` hands out `&T` to any thread and any thread may set the value, so this is only `Sync`
+// if `T` is `Send` and `Sync`. `UnsafeCell` already makes it `!Sync` in the serial build.
+#[cfg(any(parallel_queries, strict_sync_check))]
+unsafe impl<T: std::marker::Send + std::marker::Sync> std::marker::Sync for Once<T> {}
+
+impl<T> Once<T> {
+    /// Creates an Once value which is uninitialized
+    #[inline(always)]
+    pub fn new() -> Self {
+        Once {
+            initialized: AtomicBool::new(false),
+            lock: Lock::new(()),
+            value: UnsafeCell::new(None),
+            #[cfg(feature = "sync-debug")]
+            initialized_at: Lock::new(None),
+            #[cfg(parallel_queries)]
+            initializing: InnerLock::new(()),
+            generation: 0,
+        }
+    }
+
+    /// Consumes the value and returns Some(T) if it was initialized
+    #[inline(always)]
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+
+    /// Replaces the value, which may be uninitialized, and returns the old one. This bumps the
+    /// generation returned by `get_with_generation`, also if the values are equal.
+    pub fn replace(&mut self, value: T) -> Option<T> {
+        self.generation += 1;
+        #[cfg(feature = "sync-debug")]
+        {
+            *self.initialized_at.get_mut() = None;
+        }
+        self.initialized.store_relaxed(true);
+        // This is safe since we have a mutable reference
+        unsafe { (*self.value.get()).replace(value) }
+    }
+
+    /// Takes the value, leaving the `Once` uninitialized so it can be set again. This bumps the
+    /// generation returned by `get_with_generation`.
+    pub fn take(&mut self) -> Option<T> {
+        self.generation += 1;
+        #[cfg(feature = "sync-debug")]
+        {
+            *self.initialized_at.get_mut() = None;
+        }
+        self.initialized.store_relaxed(false);
+        // This is safe since we have a mutable reference
+        unsafe { (*self.value.get()).take() }
+    }
+
+    /// Drops the value now, leaving the `Once` uninitialized, and returns whether there was a
+    /// value. Use this to tear down values like file handles before other teardown instead of
+    /// whenever the `Once` is dropped. This needs `&mut self` since `try_get` hands out
+    /// references to the value. This bumps the generation returned by `get_with_generation`.
+    pub fn drop_now(&mut self) -> bool {
+        let value = self.take();
+        let dropped = value.is_some();
+        drop(value);
+        dropped
+    }
+
+    /// Remembers where the value was initialized. This must be called before setting
+    /// `initialized`, so the location is known to anyone who sees the value.
+    #[cfg(feature = "sync-debug")]
+    #[inline(always)]
+    fn record_initialization(&self, location: Option<Location>) {
+        *self.initialized_at.lock() = location;
+    }
+
+    #[cfg(not(feature = "sync-debug"))]
+    #[inline(always)]
+    fn record_initialization(&self, _location: Option<Location>) {}
+
+    /// Describes where the value was initialized for use in panic messages.
+    #[cold]
+    fn initialized_at(&self) -> String {
+        #[cfg(feature = "sync-debug")]
+        {
+            if let Some(location) = *self.initialized_at.lock() {
+                return format!(" at {}", location);
+            }
+        }
+        String::new()
+    }
+
+    /// Initializes the value and publishes it to `try_get`. This must be called while holding
+    /// `self.lock`, and only if the value is uninitialized.
+    #[inline]
+    fn publish(&self, value: T, location: Option<Location>) -> &T {
+        // This is safe since the value is uninitialized, so nobody has a reference to it, and
+        // we hold the lock, so nobody else initializes it
+        let inner = unsafe {
+            *self.value.get() = Some(value);
+            (*self.value.get()).as_ref().unwrap()
+        };
+        self.record_initialization(location);
+        self.initialized.store_release(true);
+        inner
+    }
+
+    #[inline]
+    fn try_set_inner(&self, value: T, location: Option<Location>) -> Option<T> {
+        let _lock = self.lock.lock();
+        if self.try_get().is_some() {
+            return Some(value);
+        }
+        self.publish(value, location);
+        None
+    }
+
+    #[inline]
+    fn try_set_same_inner(&self, value: T, location: Option<Location>) -> Option<T> where T: Eq {
+        let _lock = self.lock.lock();
+        if let Some(inner) = self.try_get() {
+            if *inner != value {
+                panic!("Once already initialized{} with a different value", self.initialized_at());
+            }
+            return Some(value);
+        }
+        self.publish(value, location);
+        None
+    }
+
+    /// Tries to initialize the inner value to `value`.
+    /// Returns `None` if the inner value was uninitialized and `value` was consumed setting it
+    /// otherwise if the inner value was already set it returns `value` back to the caller
+    #[inline]
+    pub fn try_set(&self, value: T) -> Option<T> {
+        self.try_set_inner(value, None)
+    }
+
+    /// Same as `try_set`, but records `location` as the place the value was initialized.
+    /// If the `sync-debug` feature is enabled, it is included in the panic messages of
+    /// `set` and `try_set_same`. Use `sync_location!()` to get the current location.
+    #[inline]
+    pub fn try_set_at(&self, value: T, location: Location) -> Option<T> {
+        self.try_set_inner(value, Some(location))
+    }
+
+    /// Tries to initialize the inner value to `value`.
+    /// Returns `None` if the inner value was uninitialized and `value` was consumed setting it
+    /// otherwise if the inner value was already set it asserts that `value` is equal to the inner
+    /// value and then returns `value` back to the caller
+    #[inline]
+    pub fn try_set_same(&self, value: T) -> Option<T> where T: Eq {
+        self.try_set_same_inner(value, None)
+    }
+
+    /// Same as `try_set_same`, but records `location` as the place the value was initialized.
+    #[inline]
+    pub fn try_set_same_at(&self, value: T, location: Location) -> Option<T> where T: Eq {
+        self.try_set_same_inner(value, Some(location))
+    }
+
+    /// Tries to initialize the inner value to `value` and panics if it was already initialized
+    #[inline]
+    pub fn set(&self, value: T) {
+        if self.try_set(value).is_some() {
+            panic!("Once already initialized{}", self.initialized_at());
+        }
+    }
+
+    /// Same as `set`, but records `location` as the place the value was initialized.
+    #[inline]
+    pub fn set_at(&self, value: T, location: Location) {
+        if self.try_set_at(value, location).is_some() {
+            panic!("Once already initialized{}", self.initialized_at());
+        }
+    }
+
+    /// Tries to initialize the inner value by calling the closure while ensuring that no-one else
+    /// can access the value in the mean time by holding a lock for the duration of the closure.
+    /// If the value was already initialized the closure is not called and `false` is returned,
+    /// otherwise if the value from the closure initializes the inner value, `true` is returned
+    #[inline]
+    pub fn init_locking<F: FnOnce() -> T>(&self, f: F) -> bool {
+        let _lock = self.lock.lock();
+        if self.try_get().is_some() {
+            return false;
+        }
+        self.publish(f(), None);
+        true
+    }
+
+    /// Returns a reference to the inner value, initializing it by calling the closure first if
+    /// it is not yet initialized. Unlike `init_locking` followed by `get` this only takes the
+    /// lock once. Like for `init_locking` the lock is held while the closure runs, so other
+    /// threads block until it returns, and the closure must not use this value.
+    #[inline]
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if let Some(value) = self.try_get() {
+            return value;
+        }
+        let _lock = self.lock.lock();
+        match self.try_get() {
+            Some(value) => value,
+            None => self.publish(f(), None),
+        }
+    }
+
+    /// Tries to initialize the inner value by calling the closure without ensuring that no-one
+    /// else can access it. This mean when this is called from multiple threads, multiple
+    /// closures may concurrently be computing a value which the inner value should take.
+    /// Only one of these closures are used to actually initialize the value.
+    /// If some other closure already set the value,
+    /// we return the value our closure computed wrapped in a `Option`.
+    /// If our closure set the value, `None` is returned.
+    /// If the value is already initialized, the closure is not called and `None` is returned.
+    #[inline]
+    pub fn init_nonlocking<F: FnOnce() -> T>(&self, f: F) -> Option<T> {
+        if self.try_get().is_some() {
+            None
+        } else {
+            self.try_set(f())
+        }
+    }
+
+    /// Tries to initialize the inner value by calling the closure without ensuring that no-one
+    /// else can access it. This mean when this is called from multiple threads, multiple
+    /// closures may concurrently be computing a value which the inner value should take.
+    /// Only one of these closures are used to actually initialize the value.
+    /// If some other closure already set the value, we assert that it our closure computed
+    /// a value equal to the value already set and then
+    /// we return the value our closure computed wrapped in a `Option`.
+    /// If our closure set the value, `None` is returned.
+    /// If the value is already initialized, the closure is not called and `None` is returned.
+    #[inline]
+    pub fn init_nonlocking_same<F: FnOnce() -> T>(&self, f: F) -> Option<T> where T: Eq {
+        if self.try_get().is_some() {
+            None
+        } else {
+            self.try_set_same(f())
+        }
+    }
+
+    /// Same as `init_nonlocking`, but returns a reference to the inner value as well as the
+    /// value computed by our closure if another closure set the inner value first. So the
+    /// values can be compared, e.g. to find nondeterminism.
+    #[inline]
+    pub fn init_racy<F: FnOnce() -> T>(&self, f: F) -> RaceOutcome<T> {
+        if let Some(value) = self.try_get() {
+            return RaceOutcome::Initialized(value);
+        }
+        match self.try_set(f()) {
+            None => RaceOutcome::Won(self.get()),
+            Some(yours) => RaceOutcome::Lost { winner: self.get(), yours },
+        }
+    }
+
+    /// Same as `init_racy`, but panics if another closure set the inner value first and our
+    /// closure computed a different value, printing both. Unlike `init_nonlocking_same` this
+    /// only requires `PartialEq`. Returns a reference to the inner value.
+    #[inline]
+    pub fn init_racy_check_eq<F: FnOnce() -> T>(&self, f: F) -> &T where T: PartialEq + Debug {
+        match self.init_racy(f) {
+            RaceOutcome::Lost { winner, yours } => {
+                if *winner != yours {
+                    panic!("a racing closure initialized the Once{} to a different value\n\
+                            winner: {:?}\n\
+                            ours: {:?}",
+                           self.initialized_at(), winner, yours);
+                }
+                winner
+            }
+            outcome => outcome.value(),
+        }
+    }
+
+    /// Initializes the inner value by calling the closure if it is not yet initialized and
+    /// returns a reference to it. Unlike `init_nonlocking` only one thread calls its closure,
+    /// while the other ones block until the value is set, so this is suited for expensive
+    /// closures. Unlike `init_locking` the lock on the value isn't held while the closure runs,
+    /// so the closure may itself use `join` or the `par_*` functions and read other `Once`s.
+    /// If the closure panics, one of the blocked threads calls its own closure instead.
+    /// The closure must not call `init_parallel` on the same value, which deadlocks if
+    /// cfg!(parallel_queries) is true and panics otherwise.
+    #[inline]
+    pub fn init_parallel<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if let Some(value) = self.try_get() {
+            return value;
+        }
+        {
+            #[cfg(parallel_queries)]
+            let _initializing = self.initializing.lock();
+            if self.try_get().is_none() {
+                self.set(f());
+            }
+        }
+        self.get()
+    }
+
+    /// Same as `init_parallel`, but passes `init` a `Weak` to `this`, so the value can refer
+    /// back to the `Once` holding it, e.g. for lazily created self-referential interned nodes.
+    /// Without `Rc::new_cyclic` the `Weak` can't point to the value itself, so the value must be
+    /// reached through the `Once`.
+    ///
+    /// The `Weak` can be upgraded while `init` runs, but the value isn't set yet then, so
+    /// `try_get` returns `None` and `get` panics. `init` may store the `Weak`, but must not
+    /// initialize `this` itself, which deadlocks like for `init_parallel`.
+    pub fn get_or_init_cyclic<F>(this: &Lrc<Once<T>>, init: F) -> &T
+        where F: FnOnce(&Weak<Once<T>>) -> T
+    {
+        this.init_parallel(|| init(&Lrc::downgrade(this)))
+    }
+
+    /// Tries to get a reference to the inner value, returns `None` if it is not yet initialized.
+    /// This never takes the lock, it only loads the `initialized` flag.
+    #[inline(always)]
+    pub fn try_get(&self) -> Option<&T> {
+        if self.initialized.load_acquire() {
+            // This is safe since the value isn't mutated through shared references once
+            // `initialized` is set
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Same as `try_get`, but also returns the number of times the value was replaced or taken.
+    /// If the generation is the same for two observations, the value wasn't replaced in between,
+    /// even with an equal value.
+    #[inline]
+    pub fn get_with_generation(&self) -> Option<(&T, u64)> {
+        self.try_get().map(|value| (value, self.generation))
+    }
+
+    /// Gets reference to the inner value, panics if it is not yet initialized
+    #[inline(always)]
+    pub fn get(&self) -> &T {
+        self.try_get().expect("value was not set")
+    }
+
+    /// Gets reference to the inner value, panics if it is not yet initialized
+    #[inline(always)]
+    pub fn borrow(&self) -> &T {
+        self.get()
+    }
+
+    /// Returns a view of this value with the interface of `std::sync::OnceLock`
+    #[inline(always)]
+    pub fn as_once_lock(&self) -> &OnceLock<T> {
+        // This is safe since `OnceLock` is a transparent wrapper around `Once`
+        unsafe { &*(self as *const Once<T> as *const OnceLock<T>) }
+    }
+}
+
+struct UninitializedPlaceholder;
+
+impl Debug for UninitializedPlaceholder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("<uninitialized>")
+    }
+}
+
+/// Prints the same format in both builds, like the `Debug` impl of `Lock`. The value is printed
+/// if it is initialized, otherwise `<uninitialized>`. This never blocks, an `Once` which is
+/// being initialized, e.g. by `init_locking`, prints `<locked>` instead.
+impl<T: Debug> Debug for Once<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("Once");
+        if let Some(value) = self.try_get() {
+            d.field("data", value);
+        } else if self.lock.try_lock_raw().is_some() {
+            d.field("data", &UninitializedPlaceholder);
+        } else {
+            d.field("data", &LockedPlaceholder);
+        }
+        d.finish()
+    }
+}
+
+/// A `Once` with methods whose signatures match the ones of `std::sync::OnceLock`, so code
+/// written against it can switch to `std::sync::OnceLock` by only changing the import.
+#[repr(transparent)]
+pub struct OnceLock<T>(Once<T>);
+
+impl<T> OnceLock<T> {
+    /// Creates an uninitialized OnceLock value
+    #[inline(always)]
+    pub fn new() -> Self {
+        OnceLock(Once::new())
+    }
+
+    /// Gets a reference to the value, returns `None` if it is not yet initialized
+    #[inline(always)]
+    pub fn get(&self) -> Option<&T> {
+        self.0.try_get()
+    }
+
+    /// Initializes the value to `value`. Returns `Err(value)` if it was already initialized.
+    #[inline]
+    pub fn set(&self, value: T) -> Result<(), T> {
+        match self.0.try_set(value) {
+            Some(value) => Err(value),
+            None => Ok(()),
+        }
+    }
+
+    /// Gets a reference to the value, initializing it with `f` if it is not yet initialized.
+    /// If multiple threads call this concurrently only one of them runs `f` and the others
+    /// wait for it to finish. Calling this from within `f` on the same value panics or
+    /// deadlocks.
+    #[inline]
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        self.0.get_or_init(f)
+    }
+
+    /// Consumes the value and returns Some(T) if it was initialized
+    #[inline(always)]
+    pub fn into_inner(self) -> Option<T> {
+        self.0.into_inner()
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        OnceLock::new()
+    }
+}
+
+/// A `Once` which can be used in a `static`, since it has a constant constructor and is
+/// always `Sync`. It is built on `std::sync::Once`, so after initialization reading the value
+/// only takes an atomic load. It is the same in both builds, since the storage of the serial
+/// `Once` can't be shared by the threads of a process which uses a `static`.
+pub struct StaticOnce<T> {
+    once: std::sync::Once,
+    value: UnsafeCell<Option<T>>,
+}
+
+// The value is only written once, inside of `call_once`, and only read after that.
+unsafe impl<T: std::marker::Send + std::marker::Sync> std::marker::Sync for StaticOnce<T> {}
+
+impl<T> StaticOnce<T> {
+    /// Creates an uninitialized StaticOnce value
+    pub const fn new() -> Self {
+        StaticOnce {
+            once: std::sync::ONCE_INIT,
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Tries to initialize the inner value to `value`.
+    /// Returns `None` if the inner value was uninitialized and `value` was consumed setting it
+    /// otherwise if the inner value was already set it returns `value` back to the caller
+    #[inline]
+    pub fn try_set(&self, value: T) -> Option<T> {
+        let mut value = Some(value);
+        self.once.call_once(|| unsafe { *self.value.get() = value.take() });
+        value
+    }
+
+    /// Tries to initialize the inner value to `value`.
+    /// Returns `None` if the inner value was uninitialized and `value` was consumed setting it
+    /// otherwise if the inner value was already set it asserts that `value` is equal to the inner
+    /// value and then returns `value` back to the caller
+    #[inline]
+    pub fn try_set_same(&self, value: T) -> Option<T> where T: Eq {
+        let value = self.try_set(value)?;
+        if *self.get() != value {
+            panic!("StaticOnce already initialized with a different value");
+        }
+        Some(value)
+    }
+
+    /// Tries to initialize the inner value to `value` and panics if it was already initialized
+    #[inline]
+    pub fn set(&self, value: T) {
+        if self.try_set(value).is_some() {
+            panic!("StaticOnce already initialized");
+        }
+    }
+
+    /// Same as `Once::init_nonlocking`.
+    #[inline]
+    pub fn init_nonlocking<F: FnOnce() -> T>(&self, f: F) -> Option<T> {
+        if self.once.is_completed() {
+            None
+        } else {
+            self.try_set(f())
+        }
+    }
+
+    /// Same as `Once::init_nonlocking_same`.
+    #[inline]
+    pub fn init_nonlocking_same<F: FnOnce() -> T>(&self, f: F) -> Option<T> where T: Eq {
+        if self.once.is_completed() {
+            None
+        } else {
+            self.try_set_same(f())
+        }
+    }
+
+    /// Gets a reference to the value, initializing it by calling the closure if it is not yet
+    /// initialized. Only one thread calls its closure, the other ones block until it returns.
+    /// If the closure panics, all later uses of this value panic.
+    #[inline]
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        self.once.call_once(|| unsafe { *self.value.get() = Some(f()) });
+        self.get()
+    }
+
+    /// Tries to get a reference to the inner value, returns `None` if it is not yet initialized
+    #[inline]
+    pub fn try_get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Gets reference to the inner value, panics if it is not yet initialized
+    #[inline]
+    pub fn get(&self) -> &T {
+        self.try_get().expect("value was not set")
+    }
+}
+
+/// A lazily created value which can be shared and mutated by multiple owners.
+/// This is `Once<Lrc<RwLock<T>>>` where the initialization happens at most once
+/// and every caller of `get` receives a clone of the same `Lrc`.
+pub struct LazyShared<T>(Once<Lrc<RwLock<T>>>);
+
+impl<T> LazyShared<T> {
+    /// Creates an uninitialized LazyShared value
+    #[inline(always)]
+    pub fn new() -> Self {
+        LazyShared(Once::new())
+    }
+
+    /// Returns the shared value, creating it using `init` if it is not yet initialized.
+    /// If multiple threads call this concurrently only one of them runs `init` and the
+    /// others wait for it to finish.
+    #[inline]
+    pub fn get<F: FnOnce() -> T>(&self, init: F) -> Lrc<RwLock<T>> {
+        self.0.init_locking(|| Lrc::new(RwLock::new(init())));
+        self.0.get().clone()
+    }
+
+    /// Returns the shared value if it is initialized
+    #[inline]
+    pub fn try_get(&self) -> Option<Lrc<RwLock<T>>> {
+        self.0.try_get().cloned()
+    }
+}
+
+/// Returns the value `weak` points to if it is still alive. Otherwise creates a new value with
+/// `init`, makes `weak` point to it and returns it. This is the lookup of a cache which keeps
+/// its values only as long as someone else uses them.
+pub fn upgrade_or_init<T, F>(weak: &mut Weak<T>, init: F) -> Lrc<T>
+    where F: FnOnce() -> Lrc<T>
+{
+    if let Some(value) = weak.upgrade() {
+        return value;
+    }
+    let value = init();
+    *weak = Lrc::downgrade(&value);
+    value
+}
+
+/// Copies `s` into a new `Lrc<str>`, without an intermediate `String`.
+#[inline]
+pub fn lrc_str(s: &str) -> Lrc<str> {
+    Lrc::from(s)
+}
+
+/// Moves `s` into a new `Lrc<str>`. This copies the contents, since the reference counts are
+/// stored in the same allocation.
+#[inline]
+pub fn lrc_string(s: String) -> Lrc<str> {
+    Lrc::from(s)
+}
+
+/// Returns the value protected by the lock if `lrc` is the last reference to it, e.g. to
+/// dismantle a structure shared by the tasks of a phase once they are done. Otherwise `lrc` is
+/// returned unchanged.
+#[inline]
+pub fn try_unwrap_lock<T>(lrc: Lrc<Lock<T>>) -> Result<T, Lrc<Lock<T>>> {
+    Lrc::try_unwrap(lrc).map(Lock::into_inner)
+}
+
+/// Returns the value protected by the lock if `lrc` is the last reference to it. Otherwise
+/// `lrc` is returned unchanged.
+#[inline]
+pub fn try_unwrap_rwlock<T>(lrc: Lrc<RwLock<T>>) -> Result<T, Lrc<RwLock<T>>> {
+    Lrc::try_unwrap(lrc).map(RwLock::into_inner)
+}
+
+/// A first-in first-out queue which can be shared by the producers and consumers of work items,
+/// e.g. the tasks of a parallel pass which find more work while processing an item.
+/// Unlike `work_queue::WorkQueue` this does not remove duplicate items.
+#[derive(Debug)]
+pub struct WorkQueue<T>(Lock<VecDeque<T>>);
+
+impl<T> WorkQueue<T> {
+    /// Creates an empty queue
+    #[inline]
+    pub fn new() -> Self {
+        WorkQueue(Lock::new(VecDeque::new()))
+    }
+
+    /// Adds `item` to the back of the queue
+    #[inline]
+    pub fn push(&self, item: T) {
+        self.0.lock().push_back(item);
+    }
+
+    /// Removes the item at the front of the queue, returns `None` if it is empty
+    #[inline]
+    pub fn pop(&self) -> Option<T> {
+        self.0.lock().pop_front()
+    }
+
+    /// Removes up to `n` items from the front of the queue while holding the lock only once.
+    /// Consumers which process many small items should prefer this to `pop` to reduce
+    /// contention. Returns an empty vector if the queue is empty.
+    #[inline]
+    pub fn drain_batch(&self, n: usize) -> Vec<T> {
+        let mut queue = self.0.lock();
+        let n = cmp::min(n, queue.len());
+        queue.drain(..n).collect()
+    }
+
+    /// Returns the number of items in the queue. Other threads may change it at any time.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    /// Returns whether the queue is empty. Other threads may change it at any time.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().is_empty()
+    }
+
+    /// Consumes the queue and returns the remaining items
+    #[inline]
+    pub fn into_inner(self) -> VecDeque<T> {
+        self.0.into_inner()
+    }
+}
+
+impl<T> Default for WorkQueue<T> {
+    #[inline]
+    fn default() -> Self {
+        WorkQueue::new()
+    }
+}
+
+impl<T: Copy + Debug> Debug for LockCell<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("LockCell")
+            .field("value", &self.get())
+            .finish()
+    }
+}
+
+impl<T:Default> Default for LockCell<T> {
+    /// Creates a `LockCell<T>`, with the `Default` value for T.
+    #[inline]
+    fn default() -> LockCell<T> {
+        LockCell::new(Default::default())
+    }
+}
+
+impl<T:PartialEq + Copy> PartialEq for LockCell<T> {
+    #[inline]
+    fn eq(&self, other: &LockCell<T>) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl<T:Eq + Copy> Eq for LockCell<T> {}
+
+impl<T:PartialOrd + Copy> PartialOrd for LockCell<T> {
+    #[inline]
+    fn partial_cmp(&self, other: &LockCell<T>) -> Option<Ordering> {
+        self.get().partial_cmp(&other.get())
+    }
+
+    #[inline]
+    fn lt(&self, other: &LockCell<T>) -> bool {
+        self.get() < other.get()
+    }
+
+    #[inline]
+    fn le(&self, other: &LockCell<T>) -> bool {
+        self.get() <= other.get()
+    }
+
+    #[inline]
+    fn gt(&self, other: &LockCell<T>) -> bool {
+        self.get() > other.get()
+    }
+
+    #[inline]
+    fn ge(&self, other: &LockCell<T>) -> bool {
+        self.get() >= other.get()
+    }
+}
+
+impl<T:Ord + Copy> Ord for LockCell<T> {
+    #[inline]
+    fn cmp(&self, other: &LockCell<T>) -> Ordering {
+        self.get().cmp(&other.get())
+    }
+}
+
+/// The error of a `try_*_detailed` method of a lock which is held, if the holders of the lock
+/// are not recorded. See `TryLockError`.
+#[cfg(not(feature = "lock-error-checking"))]
+#[inline]
+fn try_lock_error() -> TryLockError {
+    if cfg!(parallel_queries) {
+        TryLockError::WouldBlock
+    } else {
+        TryLockError::AlreadyBorrowedByCurrentThread { location: None }
+    }
+}
+
+pub struct Lock<T> {
+    inner: InnerLock<T>,
+    #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+    info: LockInfo,
+    #[cfg(feature = "lock-error-checking")]
+    holder: LastHolder,
+}
+
+// Like `parking_lot::Mutex`. Only for type checking, see the module docs.
+#[cfg(all(strict_sync_check, not(parallel_queries)))]
+unsafe impl<T: Send> Sync for Lock<T> {}
+
+impl<T> Lock<T> {
+    #[inline(always)]
+    pub fn new(inner: T) -> Self {
+        Lock {
+            inner: InnerLock::new(inner),
+            #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+            info: LockInfo::new::<T>(None),
+            #[cfg(feature = "lock-error-checking")]
+            holder: LastHolder::new(),
+        }
+    }
+
+    /// Creates a lock containing the value returned by `f`. Unlike `default` this doesn't
+    /// require `T: Default`.
+    #[inline(always)]
+    pub fn default_with<F: FnOnce() -> T>(f: F) -> Self {
+        Lock::new(f())
+    }
+
+    /// Creates a lock with a name which identifies it in the diagnostics emitted when the
+    /// `lock-metrics` feature is enabled and in the trace records of the `lock-tracing` feature.
+    #[inline(always)]
+    pub fn named(_name: &'static str, inner: T) -> Self {
+        Lock {
+            inner: InnerLock::new(inner),
+            #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+            info: LockInfo::new::<T>(Some(_name)),
+            #[cfg(feature = "lock-error-checking")]
+            holder: LastHolder::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Returns the number of guards of this lock which have not been dropped yet.
+    /// If this stays nonzero although nothing should be holding the lock, a guard
+    /// was likely leaked with `mem::forget`.
+    #[cfg(feature = "sync-debug")]
+    pub fn outstanding_guards(&self) -> usize {
+        self.info.outstanding_guards().1
+    }
+
+    /// Panics if this lock has guards which have not been dropped yet, listing where they were
+    /// acquired. Tests can call this at points where nothing should be holding the lock.
+    #[cfg(feature = "sync-debug")]
+    pub fn assert_no_guards_held(&self) {
+        self.info.assert_no_guards_held();
+    }
+
+    /// Returns the total time threads spent blocked acquiring this lock.
+    #[cfg(feature = "lock-metrics")]
+    pub fn wait_time(&self) -> Duration {
+        self.info.wait_time()
+    }
+
+    /// Returns the number of times this lock was acquired.
+    #[cfg(feature = "lock-metrics")]
+    pub fn acquisitions(&self) -> u64 {
+        self.info.acquisitions()
+    }
+
+    /// Returns the longest time a guard of this lock was held, also by the current thread if
+    /// cfg!(parallel_queries) is false.
+    #[cfg(feature = "lock-metrics")]
+    pub fn max_hold_time(&self) -> Duration {
+        self.info.max_hold_time()
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    #[inline(always)]
+    fn instrument<'a, G>(&'a self, guard: G, location: Option<Location>)
+                         -> InstrumentedGuard<'a, G> {
+        self.acquired(location);
+        InstrumentedGuard::new(guard, &self.info, GuardKind::Write, location)
+    }
+
+    #[cfg(not(feature = "lock-metrics"))]
+    #[inline(always)]
+    fn instrument<G>(&self, guard: G, location: Option<Location>) -> G {
+        self.acquired(location);
+        guard
+    }
+
+    #[cfg(feature = "lock-error-checking")]
+    #[inline(always)]
+    fn acquired(&self, location: Option<Location>) {
+        self.holder.acquired(location);
+    }
+
+    #[cfg(not(feature = "lock-error-checking"))]
+    #[inline(always)]
+    fn acquired(&self, _location: Option<Location>) {}
+
+    #[cfg(all(parallel_queries, feature = "lock-error-checking"))]
+    #[cold]
+    fn already_held(&self) -> ! {
+        self.holder.already_held()
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[cold]
+    fn already_held(&self) -> ! {
+        panic!("lock was already held")
+    }
+
+    #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+    #[inline(always)]
+    fn acquire<G, F, A>(&self, kind: &'static str, try_acquire: F, acquire: A) -> G
+        where F: FnOnce() -> Option<G>,
+              A: FnOnce() -> G
+    {
+        instrument::acquire(&self.info, kind, try_acquire, acquire)
+    }
+
+    #[cfg(not(any(feature = "lock-metrics", feature = "lock-tracing")))]
+    #[inline(always)]
+    fn acquire<G, F, A>(&self, _kind: &'static str, _try_acquire: F, acquire: A) -> G
+        where F: FnOnce() -> Option<G>,
+              A: FnOnce() -> G
+    {
+        acquire()
+    }
+
+    #[inline(always)]
+    fn try_lock_raw(&self) -> Option<RawLockGuard<T>> {
+        self.inner.try_lock()
+    }
+
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<LockGuard<T>> {
+        self.try_lock_raw().map(|guard| self.instrument(guard, None))
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn try_lock_for_raw(&self, _timeout: Duration) -> Option<RawLockGuard<T>> {
+        self.try_lock_raw()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn try_lock_for_raw(&self, timeout: Duration) -> Option<RawLockGuard<T>> {
+        self.inner.try_lock_for(timeout)
+    }
+
+    /// Tries to acquire the lock, waiting at most `timeout` for it to become available, e.g. to
+    /// give up on a contended cache instead of blocking. If cfg!(parallel_queries) is false the
+    /// timeout is ignored and this returns immediately, since no other thread could release
+    /// the lock in the meantime.
+    #[inline(always)]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<LockGuard<T>> {
+        self.try_lock_for_raw(timeout).map(|guard| self.instrument(guard, None))
+    }
+
+    /// Returns whether the lock is currently held, e.g. to sample contention. parking_lot 0.6
+    /// can't read the state of a mutex, so with cfg!(parallel_queries) this tries to acquire
+    /// the lock and releases it again right away. So a thread which acquires the lock at the
+    /// same time may briefly find it held by the probe. The answer may be outdated as soon as
+    /// this returns.
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        // The result of the probe is a temporary, so the lock is released before returning.
+        self.try_lock_raw().is_none()
+    }
+
+    /// Same as `try_lock`, but the error tells whether the lock is held by the current thread.
+    #[inline]
+    pub fn try_lock_detailed(&self) -> Result<LockGuard<T>, TryLockError> {
+        self.try_lock().ok_or_else(|| self.try_lock_error())
+    }
+
+    #[cfg(feature = "lock-error-checking")]
+    #[cold]
+    fn try_lock_error(&self) -> TryLockError {
+        self.holder.try_lock_error()
+    }
+
+    #[cfg(not(feature = "lock-error-checking"))]
+    #[cold]
+    fn try_lock_error(&self) -> TryLockError {
+        try_lock_error()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn lock_raw(&self) -> RawLockGuard<T> {
+        if ERROR_CHECKING {
+            self.inner.try_lock().unwrap_or_else(|| self.already_held())
+        } else {
+            self.inner.lock()
+        }
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn lock_raw(&self) -> RawLockGuard<T> {
+        self.inner.lock()
+    }
+
+    #[inline(always)]
+    pub fn lock(&self) -> LockGuard<T> {
+        let guard = self.acquire("lock", || self.try_lock_raw(), || self.lock_raw());
+        self.instrument(guard, None)
+    }
+
+    /// Same as `lock`, but records `location` as the place the lock was acquired.
+    /// Use `sync_location!()` to get the current location.
+    #[inline(always)]
+    pub fn lock_at(&self, location: Location) -> LockGuard<T> {
+        let guard = self.acquire("lock", || self.try_lock_raw(), || self.lock_raw());
+        self.instrument(guard, Some(location))
+    }
+
+    #[inline(always)]
+    pub fn with_lock<F: FnOnce(&mut T) -> R, R>(&self, f: F) -> R {
+        f(&mut *self.lock())
+    }
+
+    /// Same as `with_lock`, but also returns whether the lock was contended, e.g. to shard or
+    /// batch a hot path adaptively. This is only a hint: the lock counts as contended if a
+    /// `try_lock` before blocking fails, so the holder may already release it in between. If
+    /// cfg!(parallel_queries) is false this is always `false`.
+    #[inline]
+    pub fn with_lock_contention<F: FnOnce(&mut T) -> R, R>(&self, f: F) -> (R, bool) {
+        #[cfg(parallel_queries)]
+        let (mut guard, contended) = match self.try_lock() {
+            Some(guard) => (guard, false),
+            None => (self.lock(), true),
+        };
+        #[cfg(not(parallel_queries))]
+        let (mut guard, contended) = (self.lock(), false);
+        (f(&mut *guard), contended)
+    }
+
+    /// Same as `with_lock`, but aborts the process if `f` panics, so the value is never
+    /// observed in the state `f` left it in. This is meant for critical sections which
+    /// restore invariants of the value and must not panic.
+    #[inline]
+    pub fn with_lock_no_unwind<F: FnOnce(&mut T) -> R, R>(&self, f: F) -> R {
+        let mut guard = self.lock();
+        // This is dropped before `guard`, so we abort before the lock is released.
+        let _bomb = AbortOnDrop("Lock::with_lock_no_unwind");
+        f(&mut *guard)
+    }
+
+    #[inline(always)]
+    pub fn borrow(&self) -> LockGuard<T> {
+        self.lock()
+    }
+
+    #[inline(always)]
+    pub fn borrow_mut(&self) -> LockGuard<T> {
+        self.lock()
+    }
+
+    /// Releases the lock held by `guard`, runs `f` and acquires the lock again, e.g. to wait for
+    /// another thread which needs the lock. The lock is also acquired again if `f` panics.
+    ///
+    /// Other threads can modify the value while `f` runs, so *every invariant protected by the
+    /// lock must be checked again after this returns*, including everything computed from the
+    /// value before the call. Panics before releasing anything if `guard` is not a guard of
+    /// this lock. The lock is acquired again by blocking also with the `lock-error-checking`
+    /// feature, since other threads are expected to hold it when `f` returns. If the lock can't
+    /// be acquired again, e.g. because `f` leaked a guard of it, the process is aborted.
+    pub fn unlocked<'a, F: FnOnce() -> R, R>(&'a self, guard: &mut LockGuard<'a, T>, f: F) -> R {
+        assert!(ptr::eq(RawLockGuard::mutex(raw_guard(guard)), &self.inner),
+                "the guard passed to `unlocked` is of another lock");
+        unlock_around(guard, || {
+            let guard = self.acquire("lock", || self.try_lock_raw(), || self.inner.lock());
+            self.instrument(guard, None)
+        }, f)
+    }
+
+    /// Makes a guard of the part of the value selected by `f`, like `LockGuard::map`, if `f`
+    /// returns `Some`, e.g. a field of an enum variant. Otherwise the original guard is
+    /// returned, so the lock isn't released.
+    pub fn filter_map<'a, U: ?Sized, F>(guard: LockGuard<'a, T>, f: F)
+                                        -> Result<MappedLockGuard<'a, U>, LockGuard<'a, T>>
+        where F: FnOnce(&mut T) -> Option<&mut U>
+    {
+        let mut guard = guard;
+        // The guards of lock_api 0.1 can't map fallibly yet, so `f` is called before
+        // mapping. The pointer stays valid since the value is locked until the mapped guard is
+        // dropped.
+        match f(&mut *guard).map(|part| part as *mut U) {
+            Some(part) => Ok(LockGuard::map(guard, |_| unsafe { &mut *part })),
+            None => Err(guard),
+        }
+    }
+}
+
+impl<T: Default> Default for Lock<T> {
+    #[inline]
+    fn default() -> Self {
+        Lock::default_with(T::default)
+    }
+}
+
+/// Printed instead of the value of a lock which is held.
+struct LockedPlaceholder;
+
+impl Debug for LockedPlaceholder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("<locked>")
+    }
+}
+
+/// Prints the same format in both builds. The value is printed if the lock is free, otherwise
+/// `<locked>`, like the `Debug` impl of `std::sync::Mutex`. This doesn't count as an
+/// acquisition of the lock.
+impl<T: Debug> Debug for Lock<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("Lock");
+        #[cfg(feature = "lock-metrics")]
+        {
+            if let Some(name) = self.info.given_name() {
+                d.field("name", &name);
+            }
+        }
+        match self.try_lock_raw() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &LockedPlaceholder),
+        };
+        d.finish()
+    }
+}
+
+// FIXME: Probably a bad idea
+impl<T: Clone> Clone for Lock<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Lock::new(self.borrow().clone())
+    }
+}
+
+impl<C: FromIterator<T>, T> FromIterator<T> for Lock<C> {
+    #[inline]
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Lock::new(iter.into_iter().collect())
+    }
+}
+
+/// Returns the `lock_api` guard wrapped by `guard`.
+#[cfg(feature = "lock-metrics")]
+#[inline(always)]
+fn raw_guard<'g, 'a, G>(guard: &'g InstrumentedGuard<'a, G>) -> &'g G {
+    InstrumentedGuard::raw(guard)
+}
+
+/// Returns the `lock_api` guard wrapped by `guard`.
+#[cfg(not(feature = "lock-metrics"))]
+#[inline(always)]
+fn raw_guard<G>(guard: &G) -> &G {
+    guard
+}
+
+/// Puts a guard returned by `acquire` into `slot` when dropped.
+struct Reacquire<'g, G: 'g, A: FnMut() -> G> {
+    slot: &'g mut G,
+    acquire: A,
+}
+
+impl<'g, G: 'g, A: FnMut() -> G> Drop for Reacquire<'g, G, A> {
+    fn drop(&mut self) {
+        // `slot` holds a guard which was already dropped. If `acquire` panicked, the owner of
+        // `slot` would drop it again and release the lock held by someone else, so abort.
+        let bomb = AbortOnDrop("acquiring a lock again after `unlocked`");
+        unsafe { ptr::write(self.slot, (self.acquire)()) }
+        mem::forget(bomb);
+    }
+}
+
+/// Drops `guard`, runs `f` and puts a new guard returned by `acquire` into `guard`, also if `f`
+/// panics. This is used instead of the `unlocked` methods of the `lock_api` guards so that the
+/// lock is acquired again like any other time, including the instrumentation.
+fn unlock_around<G, A, F, R>(guard: &mut G, acquire: A, f: F) -> R
+    where A: FnMut() -> G,
+          F: FnOnce() -> R
+{
+    unsafe { drop(ptr::read(guard)) };
+    let _reacquire = Reacquire { slot: &mut *guard, acquire };
+    f()
+}
+
+/// Compares the values of the locks `a` and `b`, which are acquired with `lock`. If they are the
+/// same lock it is only acquired once, since acquiring it twice would deadlock. Otherwise they
+/// are acquired in address order, so threads comparing two locks in opposite orders can't
+/// deadlock.
+fn lock_pair_eq<'a, L, G, F>(a: &'a L, b: &'a L, lock: F) -> bool
+    where F: Fn(&'a L) -> G,
+          G: Deref,
+          G::Target: PartialEq
+{
+    if ptr::eq(a, b) {
+        let guard = lock(a);
+        return *guard == *guard;
+    }
+    if (a as *const L) < (b as *const L) {
+        let a = lock(a);
+        let b = lock(b);
+        *a == *b
+    } else {
+        let b = lock(b);
+        let a = lock(a);
+        *a == *b
+    }
+}
+
+/// Compares the values of the locks. See `lock_pair_eq` for how this avoids deadlocks.
+impl<T: PartialEq> PartialEq for Lock<T> {
+    #[inline]
+    fn eq(&self, other: &Lock<T>) -> bool {
+        lock_pair_eq(self, other, Lock::lock)
+    }
+}
+
+impl<T: Eq> Eq for Lock<T> {}
+
+impl<T: Hash> Hash for Lock<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.lock().hash(state)
+    }
+}
+
+/// Collects the items before locking, so the lock is acquired once per call and isn't held
+/// while the iterator runs.
+impl<'a, T> Extend<T> for &'a Lock<Vec<T>> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let mut items: Vec<T> = iter.into_iter().collect();
+        self.lock().append(&mut items);
+    }
+}
+
+/// Collects the items before locking, so the lock is acquired once per call and isn't held
+/// while the iterator runs.
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Extend<(K, V)> for &'a Lock<HashMap<K, V, S>> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let items: Vec<(K, V)> = iter.into_iter().collect();
+        self.lock().extend(items);
+    }
+}
+
+pub struct RwLock<T> {
+    inner: InnerRwLock<T>,
+    #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+    info: LockInfo,
+    #[cfg(feature = "lock-error-checking")]
+    holder: LastHolder,
+}
+
+// Like `parking_lot::RwLock`. Only for type checking, see the module docs.
+#[cfg(all(strict_sync_check, not(parallel_queries)))]
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    #[inline(always)]
+    pub fn new(inner: T) -> Self {
+        RwLock {
+            inner: InnerRwLock::new(inner),
+            #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+            info: LockInfo::new::<T>(None),
+            #[cfg(feature = "lock-error-checking")]
+            holder: LastHolder::new(),
+        }
+    }
+
+    /// Creates a lock containing the value returned by `f`. Unlike `default` this doesn't
+    /// require `T: Default`.
+    #[inline(always)]
+    pub fn default_with<F: FnOnce() -> T>(f: F) -> Self {
+        RwLock::new(f())
+    }
+
+    /// Creates a lock with a name which identifies it in the diagnostics emitted when the
+    /// `lock-metrics` feature is enabled and in the trace records of the `lock-tracing` feature.
+    #[inline(always)]
+    pub fn named(_name: &'static str, inner: T) -> Self {
+        RwLock {
+            inner: InnerRwLock::new(inner),
+            #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+            info: LockInfo::new::<T>(Some(_name)),
+            #[cfg(feature = "lock-error-checking")]
+            holder: LastHolder::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Returns the number of read and write guards of this lock which have not been dropped yet.
+    /// If these stay nonzero although nothing should be holding the lock, a guard
+    /// was likely leaked with `mem::forget`.
+    #[cfg(feature = "sync-debug")]
+    pub fn outstanding_guards(&self) -> (usize, usize) {
+        self.info.outstanding_guards()
+    }
+
+    /// Panics if this lock has guards which have not been dropped yet, listing where they were
+    /// acquired. Tests can call this at points where nothing should be holding the lock.
+    #[cfg(feature = "sync-debug")]
+    pub fn assert_no_guards_held(&self) {
+        self.info.assert_no_guards_held();
+    }
+
+    /// Returns the total time threads spent blocked acquiring this lock.
+    #[cfg(feature = "lock-metrics")]
+    pub fn wait_time(&self) -> Duration {
+        self.info.wait_time()
+    }
+
+    /// Returns the number of times this lock was acquired.
+    #[cfg(feature = "lock-metrics")]
+    pub fn acquisitions(&self) -> u64 {
+        self.info.acquisitions()
+    }
+
+    /// Returns the longest time a guard of this lock was held, also by the current thread if
+    /// cfg!(parallel_queries) is false.
+    #[cfg(feature = "lock-metrics")]
+    pub fn max_hold_time(&self) -> Duration {
+        self.info.max_hold_time()
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    #[inline(always)]
+    fn instrument<'a, G>(&'a self, guard: G, kind: GuardKind, location: Option<Location>)
+                         -> InstrumentedGuard<'a, G> {
+        self.acquired(location);
+        InstrumentedGuard::new(guard, &self.info, kind, location)
+    }
+
+    #[cfg(not(feature = "lock-metrics"))]
+    #[inline(always)]
+    fn instrument<G>(&self, guard: G, _kind: GuardKind, location: Option<Location>) -> G {
+        self.acquired(location);
+        guard
+    }
+
+    #[cfg(feature = "lock-error-checking")]
+    #[inline(always)]
+    fn acquired(&self, location: Option<Location>) {
+        self.holder.acquired(location);
+    }
+
+    #[cfg(not(feature = "lock-error-checking"))]
+    #[inline(always)]
+    fn acquired(&self, _location: Option<Location>) {}
+
+    #[cfg(all(parallel_queries, feature = "lock-error-checking"))]
+    #[cold]
+    fn already_held(&self) -> ! {
+        self.holder.already_held()
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[cold]
+    fn already_held(&self) -> ! {
+        panic!("lock was already held")
+    }
+
+    #[cfg(any(feature = "lock-metrics", feature = "lock-tracing"))]
+    #[inline(always)]
+    fn acquire<G, F, A>(&self, kind: &'static str, try_acquire: F, acquire: A) -> G
+        where F: FnOnce() -> Option<G>,
+              A: FnOnce() -> G
+    {
+        instrument::acquire(&self.info, kind, try_acquire, acquire)
+    }
+
+    #[cfg(not(any(feature = "lock-metrics", feature = "lock-tracing")))]
+    #[inline(always)]
+    fn acquire<G, F, A>(&self, _kind: &'static str, _try_acquire: F, acquire: A) -> G
+        where F: FnOnce() -> Option<G>,
+              A: FnOnce() -> G
+    {
+        acquire()
+    }
+
+    #[inline(always)]
+    fn try_read_raw(&self) -> Option<RawReadGuard<T>> {
+        self.inner.try_read()
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn read_raw(&self) -> RawReadGuard<T> {
+        self.inner.read()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn read_raw(&self) -> RawReadGuard<T> {
+        if ERROR_CHECKING {
+            self.inner.try_read().unwrap_or_else(|| self.already_held())
+        } else {
+            self.inner.read()
+        }
+    }
+
+    #[inline(always)]
+    pub fn read(&self) -> ReadGuard<T> {
+        let guard = self.acquire("read", || self.try_read_raw(), || self.read_raw());
+        self.instrument(guard, GuardKind::Read, None)
+    }
+
+    /// Same as `read`, but records `location` as the place the lock was acquired.
+    /// Use `sync_location!()` to get the current location.
+    #[inline(always)]
+    pub fn read_at(&self, location: Location) -> ReadGuard<T> {
+        let guard = self.acquire("read", || self.try_read_raw(), || self.read_raw());
+        self.instrument(guard, GuardKind::Read, Some(location))
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn try_read_for_raw(&self, _timeout: Duration) -> Option<RawReadGuard<T>> {
+        self.try_read_raw()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn try_read_for_raw(&self, timeout: Duration) -> Option<RawReadGuard<T>> {
+        self.inner.try_read_for(timeout)
+    }
+
+    /// Tries to acquire a read lock, waiting at most `timeout` for the lock to become available.
+    /// If cfg!(parallel_queries) is false this returns immediately, since no other thread
+    /// could release the lock in the meantime.
+    #[inline(always)]
+    pub fn try_read_for(&self, timeout: Duration) -> Option<ReadGuard<T>> {
+        self.try_read_for_raw(timeout).map(|guard| self.instrument(guard, GuardKind::Read, None))
+    }
+
+    #[inline(always)]
+    pub fn with_read_lock<F: FnOnce(&T) -> R, R>(&self, f: F) -> R {
+        f(&*self.read())
+    }
+
+    /// Same as `with_read_lock`, but also returns whether the lock was contended, like
+    /// `Lock::with_lock_contention`.
+    #[inline]
+    pub fn with_read_lock_contention<F: FnOnce(&T) -> R, R>(&self, f: F) -> (R, bool) {
+        #[cfg(parallel_queries)]
+        let (guard, contended) = match self.try_read() {
+            Ok(guard) => (guard, false),
+            Err(()) => (self.read(), true),
+        };
+        #[cfg(not(parallel_queries))]
+        let (guard, contended) = (self.read(), false);
+        (f(&*guard), contended)
+    }
+
+    /// Acquires read locks on all of `locks`, in order, and returns the guards in the same
+    /// order. This is not atomic: a writer may modify a lock after an earlier one in `locks`
+    /// was read locked and before that lock is, so the guards only show a consistent snapshot
+    /// if the writers of these locks also hold one of the other locks. Since a read lock may
+    /// wait for a queued writer, all threads acquiring several of these locks should do so in
+    /// the same order, and `locks` must not contain the same lock twice.
+    pub fn read_many<'a>(locks: &[&'a RwLock<T>]) -> Vec<ReadGuard<'a, T>> {
+        locks.iter().map(|lock| lock.read()).collect()
+    }
+
+    #[inline(always)]
+    fn try_write_raw(&self) -> Option<RawWriteGuard<T>> {
+        self.inner.try_write()
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn try_write_for_raw(&self, _timeout: Duration) -> Option<RawWriteGuard<T>> {
+        self.try_write_raw()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn try_write_for_raw(&self, timeout: Duration) -> Option<RawWriteGuard<T>> {
+        self.inner.try_write_for(timeout)
+    }
+
+    /// Tries to acquire a write lock, waiting at most `timeout` for the lock to become available.
+    /// If cfg!(parallel_queries) is false this returns immediately, since no other thread
+    /// could release the lock in the meantime.
+    #[inline(always)]
+    pub fn try_write_for(&self, timeout: Duration) -> Option<WriteGuard<T>> {
+        self.try_write_for_raw(timeout).map(|guard| self.instrument(guard, GuardKind::Write, None))
+    }
+
+    /// Reads the value if that is possible without blocking, i.e. if no writer holds the lock.
+    #[inline(always)]
+    pub fn try_read(&self) -> Result<ReadGuard<T>, ()> {
+        self.try_read_raw()
+            .map(|guard| self.instrument(guard, GuardKind::Read, None))
+            .ok_or(())
+    }
+
+    #[inline(always)]
+    pub fn try_write(&self) -> Result<WriteGuard<T>, ()> {
+        self.try_write_raw()
+            .map(|guard| self.instrument(guard, GuardKind::Write, None))
+            .ok_or(())
+    }
+
+    /// Reads the value if that is possible without blocking. The error tells whether the lock is
+    /// held by the current thread.
+    #[inline]
+    pub fn try_read_detailed(&self) -> Result<ReadGuard<T>, TryLockError> {
+        self.try_read().map_err(|()| self.try_lock_error())
+    }
+
+    /// Same as `try_write`, but the error tells whether the lock is held by the current thread.
+    #[inline]
+    pub fn try_write_detailed(&self) -> Result<WriteGuard<T>, TryLockError> {
+        self.try_write().map_err(|()| self.try_lock_error())
+    }
+
+    #[cfg(feature = "lock-error-checking")]
+    #[cold]
+    fn try_lock_error(&self) -> TryLockError {
+        self.holder.try_lock_error()
+    }
+
+    #[cfg(not(feature = "lock-error-checking"))]
+    #[cold]
+    fn try_lock_error(&self) -> TryLockError {
+        try_lock_error()
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn write_raw(&self) -> RawWriteGuard<T> {
+        self.inner.write()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn write_raw(&self) -> RawWriteGuard<T> {
+        if ERROR_CHECKING {
+            self.inner.try_write().unwrap_or_else(|| self.already_held())
+        } else {
+            self.inner.write()
+        }
+    }
+
+    #[inline(always)]
+    pub fn write(&self) -> WriteGuard<T> {
+        let guard = self.acquire("write", || self.try_write_raw(), || self.write_raw());
+        self.instrument(guard, GuardKind::Write, None)
+    }
+
+    /// Same as `write`, but records `location` as the place the lock was acquired.
+    /// Use `sync_location!()` to get the current location.
+    #[inline(always)]
+    pub fn write_at(&self, location: Location) -> WriteGuard<T> {
+        let guard = self.acquire("write", || self.try_write_raw(), || self.write_raw());
+        self.instrument(guard, GuardKind::Write, Some(location))
+    }
+
+    #[inline(always)]
+    fn try_upgradable_read_raw(&self) -> Option<RawUpgradableReadGuard<T>> {
+        self.inner.try_upgradable_read()
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn upgradable_read_raw(&self) -> RawUpgradableReadGuard<T> {
+        self.inner.upgradable_read()
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn upgradable_read_raw(&self) -> RawUpgradableReadGuard<T> {
+        if ERROR_CHECKING {
+            self.inner.try_upgradable_read().unwrap_or_else(|| self.already_held())
+        } else {
+            self.inner.upgradable_read()
+        }
+    }
+
+    /// Acquires a read lock which can be upgraded to a write lock with
+    /// `UpgradableReadGuard::upgrade` without releasing it in between, e.g. to check whether a
+    /// value must be modified without letting another writer get in between the check and the
+    /// modification. Other readers can still acquire the lock, but only one thread can hold an
+    /// upgradable read lock at a time.
+    ///
+    /// If cfg!(parallel_queries) is false, upgrading panics if the value is still read through
+    /// other guards, since they can't be released while upgrading waits for them.
+    #[inline]
+    pub fn upgradable_read(&self) -> UpgradableReadGuard<T> {
+        let guard = self.acquire("upgradable_read",
+                                 || self.try_upgradable_read_raw(),
+                                 || self.upgradable_read_raw());
+        UpgradableReadGuard {
+            guard: self.instrument(guard, GuardKind::Read, None),
+            lock: self,
+        }
+    }
+
+    #[inline(always)]
+    pub fn with_write_lock<F: FnOnce(&mut T) -> R, R>(&self, f: F) -> R {
+        f(&mut *self.write())
+    }
+
+    /// Same as `with_write_lock`, but also returns whether the lock was contended, like
+    /// `Lock::with_lock_contention`.
+    #[inline]
+    pub fn with_write_lock_contention<F: FnOnce(&mut T) -> R, R>(&self, f: F) -> (R, bool) {
+        #[cfg(parallel_queries)]
+        let (mut guard, contended) = match self.try_write() {
+            Ok(guard) => (guard, false),
+            Err(()) => (self.write(), true),
+        };
+        #[cfg(not(parallel_queries))]
+        let (mut guard, contended) = (self.write(), false);
+        (f(&mut *guard), contended)
+    }
+
+    /// Releases the lock held by `guard`, runs `f` and acquires the lock for writing again, e.g.
+    /// to wait for another thread which needs the lock. The lock is also acquired again if `f`
+    /// panics.
+    ///
+    /// Other threads can modify the value while `f` runs, so *every invariant protected by the
+    /// lock must be checked again after this returns*, including everything computed from the
+    /// value before the call. Panics before releasing anything if `guard` is not a guard of
+    /// this lock. Like for `Lock::unlocked`, the lock is acquired again by blocking and the
+    /// process is aborted if that fails.
+    pub fn unlocked<'a, F: FnOnce() -> R, R>(&'a self, guard: &mut WriteGuard<'a, T>, f: F) -> R {
+        assert!(ptr::eq(RawWriteGuard::rwlock(raw_guard(guard)), &self.inner),
+                "the guard passed to `unlocked` is of another lock");
+        unlock_around(guard, || {
+            let guard = self.acquire("write", || self.try_write_raw(), || self.inner.write());
+            self.instrument(guard, GuardKind::Write, None)
+        }, f)
+    }
+
+    /// Runs `read` under a read lock and passes its result to `decide` after releasing the
+    /// lock. If `decide` returns `true`, `write` runs with the result under a write lock and
+    /// its result is returned, otherwise `None` is returned.
+    ///
+    /// The lock isn't upgraded, so other threads can modify the value between `read` and
+    /// `write`. *`write` must not assume that the value is still in the state `read` saw*; it
+    /// should check it again, e.g. if another thread already inserted the entry `decide` chose
+    /// to insert.
+    pub fn read_then_write<V, U, R, D, W>(&self, read: R, decide: D, write: W) -> Option<U>
+        where R: FnOnce(&T) -> V,
+              D: FnOnce(&V) -> bool,
+              W: FnOnce(&mut T, V) -> U
+    {
+        let value = read(&*self.read());
+        if decide(&value) {
+            Some(write(&mut *self.write(), value))
+        } else {
+            None
+        }
+    }
+
+    /// Makes a guard of the part of the value selected by `f`, like `ReadGuard::map`, if `f`
+    /// returns `Some`, e.g. a field of an enum variant. Otherwise the original guard is
+    /// returned, so the lock isn't released.
+    pub fn filter_map_read<'a, U: ?Sized, F>(guard: ReadGuard<'a, T>, f: F)
+                                             -> Result<MappedReadGuard<'a, U>, ReadGuard<'a, T>>
+        where F: FnOnce(&T) -> Option<&U>
+    {
+        // See `Lock::filter_map` for why this is sound.
+        match f(&*guard).map(|part| part as *const U) {
+            Some(part) => Ok(ReadGuard::map(guard, |_| unsafe { &*part })),
+            None => Err(guard),
+        }
+    }
+
+    /// Makes a guard of the part of the value selected by `f`, like `WriteGuard::map`, if `f`
+    /// returns `Some`, e.g. a field of an enum variant. Otherwise the original guard is
+    /// returned, so the lock isn't released.
+    pub fn filter_map_write<'a, U: ?Sized, F>(guard: WriteGuard<'a, T>, f: F)
+                                              -> Result<MappedWriteGuard<'a, U>, WriteGuard<'a, T>>
+        where F: FnOnce(&mut T) -> Option<&mut U>
+    {
+        let mut guard = guard;
+        // See `Lock::filter_map` for why this is sound.
+        match f(&mut *guard).map(|part| part as *mut U) {
+            Some(part) => Ok(WriteGuard::map(guard, |_| unsafe { &mut *part })),
+            None => Err(guard),
+        }
+    }
+
+    #[inline(always)]
+    pub fn borrow(&self) -> ReadGuard<T> {
+        self.read()
+    }
+
+    #[inline(always)]
+    pub fn borrow_mut(&self) -> WriteGuard<T> {
+        self.write()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[inline]
+    fn default() -> Self {
+        RwLock::default_with(T::default)
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+type InstrumentedUpgradableReadGuard<'a, T> =
+    InstrumentedGuard<'a, RawUpgradableReadGuard<'a, T>>;
+#[cfg(not(feature = "lock-metrics"))]
+type InstrumentedUpgradableReadGuard<'a, T> = RawUpgradableReadGuard<'a, T>;
+
+/// A read guard returned by `RwLock::upgradable_read`, which can be upgraded to a write guard.
+pub struct UpgradableReadGuard<'a, T: 'a> {
+    guard: InstrumentedUpgradableReadGuard<'a, T>,
+    /// Used to report the holder of the lock if upgrading fails with error checking.
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> UpgradableReadGuard<'a, T> {
+    /// Upgrades the guard to a write guard, blocking until the other readers released the lock.
+    /// No writer can modify the value in between.
+    #[inline]
+    pub fn upgrade(s: Self) -> WriteGuard<'a, T> {
+        Self::upgrade_instrumented(s.guard, s.lock)
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    #[inline(always)]
+    fn upgrade_instrumented(guard: InstrumentedUpgradableReadGuard<'a, T>,
+                            lock: &'a RwLock<T>)
+                            -> WriteGuard<'a, T> {
+        InstrumentedGuard::upgrade(guard, |guard| Self::upgrade_raw(guard, lock))
+    }
+
+    #[cfg(not(feature = "lock-metrics"))]
+    #[inline(always)]
+    fn upgrade_instrumented(guard: InstrumentedUpgradableReadGuard<'a, T>,
+                            lock: &'a RwLock<T>)
+                            -> WriteGuard<'a, T> {
+        Self::upgrade_raw(guard, lock)
+    }
+
+    #[cfg(parallel_queries)]
+    #[inline(always)]
+    fn upgrade_raw(guard: RawUpgradableReadGuard<'a, T>, lock: &'a RwLock<T>)
+                   -> RawWriteGuard<'a, T> {
+        if ERROR_CHECKING {
+            RawUpgradableReadGuard::try_upgrade(guard).unwrap_or_else(|_| lock.already_held())
+        } else {
+            RawUpgradableReadGuard::upgrade(guard)
+        }
+    }
+
+    /// Panics if there are other readers, since they can't release the lock in the meantime.
+    #[cfg(not(parallel_queries))]
+    #[inline(always)]
+    fn upgrade_raw(guard: RawUpgradableReadGuard<'a, T>, _lock: &'a RwLock<T>)
+                   -> RawWriteGuard<'a, T> {
+        RawUpgradableReadGuard::upgrade(guard)
+    }
+}
+
+impl<'a, T> Deref for UpgradableReadGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for UpgradableReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Prints the same format as `Lock`. The value is printed if the lock can be read, so also if
+/// it is held by readers, otherwise `<locked>`.
+impl<T: Debug> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        #[cfg(feature = "lock-metrics")]
+        {
+            if let Some(name) = self.info.given_name() {
+                d.field("name", &name);
+            }
+        }
+        match self.try_read_raw() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &LockedPlaceholder),
+        };
+        d.finish()
+    }
+}
+
+// FIXME: Probably a bad idea
+impl<T: Clone> Clone for RwLock<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        RwLock::new(self.borrow().clone())
+    }
+}
+
+impl<C: FromIterator<T>, T> FromIterator<T> for RwLock<C> {
+    #[inline]
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        RwLock::new(iter.into_iter().collect())
+    }
+}
+
+/// Compares the values of the locks using read guards. See `lock_pair_eq` for how this avoids
+/// deadlocks, which are possible for read guards too, since parking_lot blocks new readers while
+/// a writer is waiting.
+impl<T: PartialEq> PartialEq for RwLock<T> {
+    #[inline]
+    fn eq(&self, other: &RwLock<T>) -> bool {
+        lock_pair_eq(self, other, RwLock::read)
+    }
+}
+
+impl<T: Eq> Eq for RwLock<T> {}
+
+impl<T: Hash> Hash for RwLock<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.read().hash(state)
+    }
+}
+
+/// A type which only allows its inner value to be used in one thread.
+/// It will panic if it is used on multiple threads.
+#[derive(Copy, Clone, Hash, Debug, Eq, PartialEq)]
+pub struct OneThread<T> {
+    #[cfg(any(parallel_queries, strict_sync_check))]
+    thread: std::thread::ThreadId,
+    inner: T,
+}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+unsafe impl<T> std::marker::Sync for OneThread<T> {}
+#[cfg(any(parallel_queries, strict_sync_check))]
+unsafe impl<T> std::marker::Send for OneThread<T> {}
+
+impl<T> OneThread<T> {
+    #[inline(always)]
+    fn check(&self) {
+        #[cfg(any(parallel_queries, strict_sync_check))]
+        assert_eq!(std::thread::current().id(), self.thread);
+    }
+
+    #[inline(always)]
+    pub fn new(inner: T) -> Self {
+        OneThread {
+            #[cfg(any(parallel_queries, strict_sync_check))]
+            thread: std::thread::current().id(),
+            inner,
+        }
+    }
+
+    #[inline(always)]
+    pub fn into_inner(value: Self) -> T {
+        value.check();
+        value.inner
+    }
+}
+
+impl<T> Deref for OneThread<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.check();
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for OneThread<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.check();
+        &mut self.inner
+    }
+}
+
+/// A wrapper which is `Send` even if its value isn't, for handing a value over to another
+/// thread at a point where the caller makes sure that nothing else uses it, e.g. an `Rc` of
+/// which no other clones are left. Unlike `OneThread` this doesn't check the thread the value
+/// is used on, so it is unsafe to create. Without cfg!(parallel_queries) every type is `Send`,
+/// so this is just the value.
+pub struct UnsafeSend<T>(T);
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+unsafe impl<T> std::marker::Send for UnsafeSend<T> {}
+
+impl<T> UnsafeSend<T> {
+    /// Wraps `value` so it can be sent to another thread.
+    ///
+    /// This is unsafe since `value` may not be `Send`. The caller must ensure that no other
+    /// thread accesses anything `value` shares with the current thread while it or its parts
+    /// are in use on the receiving thread, e.g. other clones of an `Rc`, the referent of a
+    /// reference or thread local state a `Drop` implementation relies on.
+    #[inline(always)]
+    pub unsafe fn new(value: T) -> Self {
+        UnsafeSend(value)
+    }
+
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// A lazily initialized value which may only be used on the thread which created it,
+/// like `OneThread`. It panics if it is used on another thread, even if it is already
+/// initialized. Without cfg!(parallel_queries) this is a `OnceLock`.
+pub struct ThreadLocalOnce<T>(OneThread<OnceLock<T>>);
+
+impl<T> ThreadLocalOnce<T> {
+    #[inline(always)]
+    pub fn new() -> Self {
+        ThreadLocalOnce(OneThread::new(OnceLock::new()))
+    }
+
+    /// Gets a reference to the value, returns `None` if it is not yet initialized
+    #[inline(always)]
+    pub fn get(&self) -> Option<&T> {
+        self.0.get()
+    }
+
+    /// Gets a reference to the value, initializing it by calling the closure if it is not yet
+    /// initialized.
+    #[inline]
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        self.0.get_or_init(f)
+    }
+
+    /// Consumes the value and returns Some(T) if it was initialized
+    #[inline(always)]
+    pub fn into_inner(self) -> Option<T> {
+        OneThread::into_inner(self.0).into_inner()
+    }
+}
+
+impl<T> Default for ThreadLocalOnce<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        ThreadLocalOnce::new()
+    }
+}
+
+/// Aligns its contents to a cache line if cfg!(parallel_queries) is true. This avoids false
+/// sharing between values which are frequently modified by different threads, like the per
+/// worker counters in a `WorkerLocal<CachePadded<AtomicU64>>`.
+// 64 bytes is the size of a cache line on most current processors.
+#[cfg_attr(parallel_queries, repr(align(64)))]
+#[cfg_attr(not(parallel_queries), repr(transparent))]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    #[inline(always)]
+    pub fn new(inner: T) -> Self {
+        CachePadded(inner)
+    }
+
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Per worker buffers of items which are moved to a shared sink, e.g. a `&Lock<Vec<T>>`, once
+/// a buffer holds `threshold` items. This bounds the memory used by the buffers during long
+/// passes, while the sink is still only extended once per `threshold` items. Like for
+/// `WorkerLocal`, `push` must be called on a thread of the pool the value was created in.
+pub struct BufferedWorkerLocal<T, S> {
+    buffers: WorkerLocal<RefCell<Vec<T>>>,
+    threshold: usize,
+    sink: S,
+}
+
+impl<T, S: Extend<T> + Copy> BufferedWorkerLocal<T, S> {
+    /// Panics if `threshold` is 0.
+    pub fn new(threshold: usize, sink: S) -> Self {
+        assert!(threshold > 0, "BufferedWorkerLocal needs a threshold of at least 1");
+        BufferedWorkerLocal {
+            buffers: WorkerLocal::new(|_| RefCell::new(Vec::with_capacity(threshold))),
+            threshold,
+            sink,
+        }
+    }
+
+    /// Adds `item` to the buffer of the current worker, moving the buffer to the sink if it is
+    /// full.
+    #[inline]
+    pub fn push(&self, item: T) {
+        let mut buffer = self.buffers.borrow_mut();
+        buffer.push(item);
+        if buffer.len() >= self.threshold {
+            let mut sink = self.sink;
+            sink.extend(buffer.drain(..));
+        }
+    }
+
+    /// Moves the items which are left in the buffers to the sink.
+    pub fn flush_all(self) {
+        let mut sink = self.sink;
+        for buffer in self.buffers.into_inner() {
+            let buffer = buffer.into_inner();
+            if !buffer.is_empty() {
+                sink.extend(buffer);
+            }
+        }
+    }
+}
+
+/// A box whose contents were `Send` and `Sync` when it was created. The box can be coerced to
+/// a box of a trait object, e.g. `SendSyncBox<dyn Any>`, which is still `Send` and `Sync` if
+/// cfg!(parallel_queries) is true. So code which passes it around doesn't need to name
+/// `Send` and `Sync` in trait objects, and a value which isn't thread safe is rejected where
+/// the box is created instead of only in the parallel build of some distant use.
+pub struct SendSyncBox<T: ?Sized>(Box<T>);
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+unsafe impl<T: ?Sized> std::marker::Sync for SendSyncBox<T> {}
+#[cfg(any(parallel_queries, strict_sync_check))]
+unsafe impl<T: ?Sized> std::marker::Send for SendSyncBox<T> {}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<SendSyncBox<U>> for SendSyncBox<T> {}
+
+impl<T: Send + Sync> SendSyncBox<T> {
+    #[inline]
+    pub fn new(inner: T) -> Self {
+        SendSyncBox(Box::new(inner))
+    }
+}
+
+impl<T: ?Sized + Send + Sync> SendSyncBox<T> {
+    #[inline]
+    pub fn from_box(inner: Box<T>) -> Self {
+        SendSyncBox(inner)
+    }
+}
+
+impl<T: ?Sized> SendSyncBox<T> {
+    #[inline]
+    pub fn into_box(self) -> Box<T> {
+        self.0
+    }
+}
+
+impl<T: ?Sized> Deref for SendSyncBox<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> DerefMut for SendSyncBox<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for SendSyncBox<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
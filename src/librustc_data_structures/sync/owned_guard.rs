@@ -0,0 +1,170 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A read guard which owns the `Lrc` of its lock, so a reference into a shared table can be
+//! returned from a function which only has the `Lrc`, without cloning the entry.
+//!
+//! `OwnedMappedReadGuard` stores a `MappedReadGuard` whose lifetime is erased to `'static`
+//! next to a clone of the `Lrc`. This is sound because:
+//!
+//! - The `RwLock` lives in the allocation of the `Lrc`, so it doesn't move and stays alive as
+//!   long as the clone is held.
+//! - The guard is declared before the `Lrc`, so it is dropped, releasing the read lock, before
+//!   the `Lrc` is.
+//! - The projection passed to `read_arc_map` is higher-ranked, so it can't return a reference
+//!   which outlives the lock, and `Deref` only lends out the value for the lifetime of the
+//!   owned guard.
+//!
+//! Except for the one which uses several threads, the tests only create, use and drop guards,
+//! so Miri could check the lifetime erasure with them. The nightly toolchain this tree is built
+//! with predates the Miri rustup component though, so they have only run as normal tests.
+
+use std::fmt;
+use std::ops::Deref;
+use super::{Lrc, RwLock, ReadGuard, MappedReadGuard};
+
+pub struct OwnedMappedReadGuard<T: 'static, U: ?Sized + 'static> {
+    /// This borrows from `_lrc` and must be dropped first, so it is declared first.
+    guard: MappedReadGuard<'static, U>,
+    _lrc: Lrc<RwLock<T>>,
+}
+
+/// Locks `lrc` for reading and returns a guard of the part of the value selected by `f`,
+/// which keeps a clone of `lrc` alive. Dropping the guard releases the read lock and then the
+/// clone.
+pub fn read_arc_map<T, U: ?Sized, F>(lrc: &Lrc<RwLock<T>>, f: F) -> OwnedMappedReadGuard<T, U>
+    where F: FnOnce(&T) -> &U
+{
+    let lrc = lrc.clone();
+    // See the module documentation for why the erased lifetime is sound.
+    let lock: &'static RwLock<T> = unsafe { &*(&*lrc as *const RwLock<T>) };
+    OwnedMappedReadGuard {
+        guard: ReadGuard::map(lock.read(), f),
+        _lrc: lrc,
+    }
+}
+
+impl<T, U: ?Sized> Deref for OwnedMappedReadGuard<T, U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U {
+        &*self.guard
+    }
+}
+
+impl<T, U: ?Sized + fmt::Debug> fmt::Debug for OwnedMappedReadGuard<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn entry(table: &Lrc<RwLock<Vec<String>>>, i: usize) -> OwnedMappedReadGuard<Vec<String>, str> {
+        read_arc_map(table, |table| &table[i][..])
+    }
+
+    #[test]
+    fn test_read_arc_map() {
+        let table = Lrc::new(RwLock::new(vec!["a".to_string(), "b".to_string()]));
+        let b = entry(&table, 1);
+        // The original `Lrc` can go away while the guard is alive.
+        drop(table);
+        assert_eq!(&*b, "b");
+        assert_eq!(format!("{:?}", b), "\"b\"");
+    }
+
+    #[test]
+    fn test_owned_guard_does_not_leak() {
+        thread_local!(static DROPPED: Cell<bool> = Cell::new(false));
+
+        struct Table(u32);
+
+        impl Drop for Table {
+            fn drop(&mut self) {
+                DROPPED.with(|dropped| dropped.set(true));
+            }
+        }
+
+        let table = Lrc::new(RwLock::new(Table(7)));
+        let guard = read_arc_map(&table, |table| &table.0);
+        assert_eq!(Lrc::strong_count(&table), 2);
+        drop(guard);
+        assert_eq!(Lrc::strong_count(&table), 1);
+
+        let guard = read_arc_map(&table, |table| &table.0);
+        drop(table);
+        assert_eq!(*guard, 7);
+        assert!(!DROPPED.with(|dropped| dropped.get()));
+        // The guard held the last reference.
+        drop(guard);
+        assert!(DROPPED.with(|dropped| dropped.get()));
+    }
+
+    #[test]
+    fn test_owned_guard_blocks_writers() {
+        let table = Lrc::new(RwLock::new(vec![1, 2]));
+        let guard = read_arc_map(&table, |table| &table[1]);
+        assert!(table.try_write().is_err());
+        // Other readers are fine.
+        assert_eq!(table.read()[0], 1);
+        drop(guard);
+        table.write().push(3);
+        assert_eq!(*read_arc_map(&table, |table| &table[2..]), [3]);
+    }
+
+    #[test]
+    fn test_owned_guard_dropped_with_owner() {
+        let table = Lrc::new(RwLock::new(vec!["a".to_string(), "b".to_string()]));
+
+        // The guard is dropped first, then the last `Lrc` frees the table.
+        let pair = (entry(&table, 0), table.clone());
+        assert_eq!(&*pair.0, "a");
+        assert_eq!(pair.1.read()[1], "b");
+        drop(pair);
+
+        // The `Lrc` is dropped first, then the guard releases the lock and frees the table.
+        let guard = entry(&table, 1);
+        let pair = (table, guard);
+        assert_eq!(Lrc::strong_count(&pair.0), 2);
+        assert_eq!(&*pair.1, "b");
+        drop(pair);
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_owned_guard_blocks_writers_on_other_threads() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use std::time::Duration;
+
+        let table = Lrc::new(RwLock::new(0));
+        let written = Lrc::new(AtomicBool::new(false));
+        let guard = read_arc_map(&table, |value| value);
+        let writer = {
+            let (table, written) = (table.clone(), written.clone());
+            thread::spawn(move || {
+                *table.write() = 1;
+                written.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!written.load(Ordering::SeqCst));
+        assert_eq!(*guard, 0);
+        drop(guard);
+        writer.join().unwrap();
+        assert!(written.load(Ordering::SeqCst));
+        assert_eq!(*table.read(), 1);
+    }
+}
@@ -0,0 +1,1292 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers built on top of `par_iter` for common parallel iteration patterns.
+//! They run on the rayon thread pool if cfg!(parallel_queries) is true and
+//! fall back to plain sequential iteration otherwise. Each call is a parallel region named
+//! after the helper in the load balance statistics of the `lock-metrics` feature.
+
+#[cfg(parallel_queries)]
+use rayon::prelude::*;
+
+#[cfg(not(parallel_queries))]
+use super::audit;
+use super::imbalance::Region;
+use super::cancel::{CancellationToken, Cancelled};
+use super::{MaybeSend, MaybeSync};
+use bit_set::BitSet;
+use indexed_vec::{Idx, IndexVec};
+use std::cmp;
+use std::sync::mpsc::{self, Receiver};
+
+/// Calls `f` on every window of length `window` in `items`, like `slice::windows`.
+/// The windows overlap, so neighbouring windows may be processed concurrently and
+/// `f` must only use shared state which is safe to access from multiple threads.
+/// Panics if `window` is 0.
+pub fn par_for_each_window<T, F>(items: &[T], window: usize, f: F)
+    where T: MaybeSync,
+          F: Fn(&[T]) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_window");
+
+    #[cfg(parallel_queries)]
+    items.par_windows(window).for_each(|w| region.task(|| f(w)));
+
+    #[cfg(not(parallel_queries))]
+    items.windows(window).for_each(|w| region.task(|| audit::task(|| f(w))));
+}
+
+/// Transforms every element of `v` in place with `f`, without allocating. This takes a slice
+/// so it also works for the elements of a `Vec`.
+pub fn par_map_in_place<T, F>(v: &mut [T], f: F)
+    where T: MaybeSend,
+          F: Fn(&mut T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_map_in_place");
+
+    #[cfg(parallel_queries)]
+    v.par_iter_mut().for_each(|item| region.task(|| f(item)));
+
+    #[cfg(not(parallel_queries))]
+    v.iter_mut().for_each(|item| region.task(|| audit::task(|| f(item))));
+}
+
+/// Returns the index of the item which `better` prefers over all others, comparing the keys
+/// of the items. The lowest index wins on ties, whichever way rayon splits the items.
+fn par_arg_extremum<T, K, F, B>(name: &'static str, items: &[T], key: F, better: B)
+                                -> Option<usize>
+    where T: MaybeSync,
+          K: Ord + MaybeSend,
+          F: Fn(&T) -> K + MaybeSync + MaybeSend,
+          B: Fn(&K, &K) -> bool + MaybeSync + MaybeSend
+{
+    let region = Region::enter(name);
+    // The pairs are reduced in order of their indices, so `a` always has the lower index.
+    let pick = |a: (usize, K), b: (usize, K)| if better(&b.1, &a.1) { b } else { a };
+
+    #[cfg(parallel_queries)]
+    let best = items.par_iter()
+                    .enumerate()
+                    .map(|(i, item)| (i, region.task(|| key(item))))
+                    .reduce_with(pick);
+
+    #[cfg(not(parallel_queries))]
+    let best = items.iter()
+                    .enumerate()
+                    .map(|(i, item)| (i, region.task(|| audit::task(|| key(item)))))
+                    .fold(None, |best, b| Some(match best {
+                        Some(a) => pick(a, b),
+                        None => b,
+                    }));
+
+    best.map(|(i, _)| i)
+}
+
+/// Returns the index of the item with the smallest key, or `None` if there are no items.
+/// The lowest index wins on ties, so the result is the same as a sequential scan.
+pub fn par_argmin<T, K, F>(items: &[T], key: F) -> Option<usize>
+    where T: MaybeSync,
+          K: Ord + MaybeSend,
+          F: Fn(&T) -> K + MaybeSync + MaybeSend
+{
+    par_arg_extremum("par_argmin", items, key, |b, a| b < a)
+}
+
+/// Returns the index of the item with the largest key, or `None` if there are no items.
+/// The lowest index wins on ties, so the result is the same as a sequential scan.
+pub fn par_argmax<T, K, F>(items: &[T], key: F) -> Option<usize>
+    where T: MaybeSync,
+          K: Ord + MaybeSend,
+          F: Fn(&T) -> K + MaybeSync + MaybeSend
+{
+    par_arg_extremum("par_argmax", items, key, |b, a| b > a)
+}
+
+/// Calls `f` on every item, grouping the items by the worker `partitioner` assigns them to.
+/// `partitioner(item_index, worker_count)` returns the index of a worker, which is taken modulo
+/// `worker_count`. All items assigned to a worker are processed in order by a single task, so
+/// callers can keep items which share data together, e.g. by memory region. This is only a hint
+/// about locality: rayon may still run any of these tasks on any thread through work stealing.
+/// Without parallel_queries `partitioner` is ignored and the items are processed in order.
+pub fn par_for_each_with_affinity<T, P, F>(items: &[T], _partitioner: P, f: F)
+    where T: MaybeSync,
+          P: Fn(usize, usize) -> usize,
+          F: Fn(&T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_with_affinity");
+
+    #[cfg(parallel_queries)]
+    {
+        let workers = ::rayon::current_num_threads();
+        let mut partitions = vec![Vec::new(); workers];
+        for (i, item) in items.iter().enumerate() {
+            partitions[_partitioner(i, workers) % workers].push(item);
+        }
+        partitions.into_par_iter().for_each(|partition| {
+            region.task(|| partition.into_iter().for_each(&f))
+        });
+    }
+
+    #[cfg(not(parallel_queries))]
+    items.iter().for_each(|item| region.task(|| audit::task(|| f(item))));
+}
+
+/// Splits `items` into `bins` bins with roughly equal total costs, greedily putting the most
+/// expensive remaining item into the cheapest bin.
+#[cfg(any(parallel_queries, test))]
+fn partition_by_cost<T, C>(items: Vec<T>, cost: C, bins: usize) -> Vec<Vec<T>>
+    where C: Fn(&T) -> u64
+{
+    use std::collections::BinaryHeap;
+
+    let mut items: Vec<(u64, T)> = items.into_iter().map(|item| (cost(&item), item)).collect();
+    items.sort_by_key(|&(cost, _)| cmp::Reverse(cost));
+    let mut partitions: Vec<Vec<T>> = (0..bins).map(|_| Vec::new()).collect();
+    let mut totals: BinaryHeap<_> = (0..bins).map(|bin| cmp::Reverse((0, bin))).collect();
+    for (cost, item) in items {
+        let cmp::Reverse((total, bin)) = totals.pop().unwrap();
+        partitions[bin].push(item);
+        totals.push(cmp::Reverse((total + cost, bin)));
+    }
+    partitions
+}
+
+/// Calls `f` on every item, so that every worker gets items with roughly the same total cost
+/// estimated by `cost`, e.g. the size of a function to type check. The items are partitioned
+/// into one bin per worker before the pass starts, and each bin is processed by a single task.
+/// This is only a heuristic: it relies on the estimates and rayon may still run any of the bins
+/// on any thread. Without parallel_queries `cost` is ignored and the items are processed in
+/// order.
+pub fn par_for_each_weighted<T, C, F>(items: Vec<T>, _cost: C, f: F)
+    where T: MaybeSend,
+          C: Fn(&T) -> u64,
+          F: Fn(T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_weighted");
+
+    #[cfg(parallel_queries)]
+    {
+        let partitions = partition_by_cost(items, _cost, ::rayon::current_num_threads());
+        partitions.into_par_iter().for_each(|partition| {
+            region.task(|| partition.into_iter().for_each(&f))
+        });
+    }
+
+    #[cfg(not(parallel_queries))]
+    items.into_iter().for_each(|item| region.task(|| audit::task(|| f(item))));
+}
+
+/// Calls `f` on every item together with the index of the worker thread processing it, which is
+/// in `0..current_num_threads()`. This can be used to attribute work to the workers of the pool.
+/// `current_thread_index()` is `None` outside of a rayon pool, this is reported as worker 0.
+/// Without parallel_queries there is a single worker, so the index is always 0.
+#[cfg(parallel_queries)]
+pub fn par_for_each_worker<T, F>(items: T, f: F)
+    where T: IntoParallelIterator,
+          F: Fn(usize, T::Item) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_worker");
+    items.into_par_iter().for_each(|item| {
+        region.task(|| f(::rayon_core::current_thread_index().unwrap_or(0), item))
+    });
+}
+
+/// Calls `f` on the items in order with the worker index 0, since there is a single worker.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_worker<T, F>(items: T, f: F)
+    where T: IntoIterator,
+          F: Fn(usize, T::Item) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_worker");
+    items.into_iter().for_each(|item| region.task(|| audit::task(|| f(0, item))));
+}
+
+/// Builds an `IndexVec` of `count` elements, where the element at index `i` is `f(i)`.
+/// `f` is called exactly once for every index in `0..count`, possibly concurrently and in any
+/// order, but the elements are always in index order.
+pub fn par_collect_indexvec<I, T, F>(count: usize, f: F) -> IndexVec<I, T>
+    where I: Idx,
+          T: MaybeSend,
+          F: Fn(I) -> T + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_indexvec");
+
+    #[cfg(parallel_queries)]
+    let raw = (0..count).into_par_iter().map(|i| region.task(|| f(I::new(i)))).collect();
+
+    #[cfg(not(parallel_queries))]
+    let raw = (0..count).map(|i| region.task(|| audit::task(|| f(I::new(i))))).collect();
+
+    IndexVec::from_raw(raw)
+}
+
+/// Splits `items` into contiguous pieces, calls `f` on each piece and combines the results of
+/// neighbouring pieces with `reduce`, which receives the result of the left piece first. How
+/// the items are split is not specified, so `reduce` should be associative. The pieces are
+/// never empty unless `items` is, in which case `f` is called once on it. This is for divide
+/// and conquer algorithms, e.g. building a tree over the items from the subtrees of the pieces.
+/// Without parallel_queries `f` is called once on all of `items`.
+pub fn par_split_process<T, R, F, Reduce>(items: &[T], f: F, reduce: Reduce) -> R
+    where T: MaybeSync,
+          R: MaybeSend,
+          F: Fn(&[T]) -> R + MaybeSync + MaybeSend,
+          Reduce: Fn(R, R) -> R + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_split_process");
+
+    #[cfg(parallel_queries)]
+    {
+        let pieces = ::rayon::iter::split(items, |items: &[T]| {
+            if items.len() < 2 {
+                return (items, None);
+            }
+            let (left, right) = items.split_at(items.len() / 2);
+            (left, Some(right))
+        });
+        pieces.map(|piece| region.task(|| f(piece))).reduce_with(reduce).unwrap()
+    }
+
+    #[cfg(not(parallel_queries))]
+    {
+        let _ = reduce;
+        region.task(|| audit::task(|| f(items)))
+    }
+}
+
+/// Folds the items into accumulators created by `make` using `accumulate`, then combines the
+/// accumulators with `merge`. There is an accumulator for each piece of work rayon splits the
+/// items into, and neither their number nor the order they are merged in is specified, so
+/// `merge` should be associative and commutative and `make` should return its identity.
+/// Without parallel_queries all items are folded into a single accumulator.
+#[cfg(parallel_queries)]
+pub fn par_accumulate<T, A, Make, F, M>(items: T, make: Make, accumulate: F, merge: M) -> A
+    where T: IntoParallelIterator,
+          A: MaybeSend,
+          Make: Fn() -> A + MaybeSync + MaybeSend,
+          F: Fn(&mut A, T::Item) + MaybeSync + MaybeSend,
+          M: Fn(A, A) -> A + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_accumulate");
+    items.into_par_iter()
+         .fold(&make, |mut acc, item| {
+             region.task(|| accumulate(&mut acc, item));
+             acc
+         })
+         .reduce(&make, merge)
+}
+
+/// Folds all the items into a single accumulator, so `merge` is never called.
+#[cfg(not(parallel_queries))]
+pub fn par_accumulate<T, A, Make, F, M>(items: T, make: Make, accumulate: F, _merge: M) -> A
+    where T: IntoIterator,
+          A: MaybeSend,
+          Make: Fn() -> A + MaybeSync + MaybeSend,
+          F: Fn(&mut A, T::Item) + MaybeSync + MaybeSend,
+          M: Fn(A, A) -> A + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_accumulate");
+    let mut acc = make();
+    for item in items {
+        region.task(|| audit::task(|| accumulate(&mut acc, item)));
+    }
+    acc
+}
+
+/// The number of items `par_reduce_tree` folds into each accumulator.
+#[cfg(parallel_queries)]
+const REDUCE_LEAF_SIZE: usize = 256;
+
+/// Merges `accs` in a balanced tree, the first half into the left operand of `merge`.
+#[cfg(parallel_queries)]
+fn merge_tree<A, M>(mut accs: Vec<A>, merge: &M, region: &Region) -> A
+    where A: MaybeSend,
+          M: Fn(A, A) -> A + MaybeSync + MaybeSend
+{
+    if accs.len() == 1 {
+        return accs.pop().unwrap();
+    }
+    let right = accs.split_off(accs.len() / 2);
+    let (left, right) = ::rayon::join(|| merge_tree(accs, merge, region),
+                                      || merge_tree(right, merge, region));
+    region.task(|| merge(left, right))
+}
+
+/// Like `par_accumulate`, but for accumulators which are expensive to create and merge, e.g.
+/// large bit sets or maps. This is a tree reduction which moves the accumulators: each one is
+/// owned by a single task and moved into `merge`, so they are never cloned, and they are merged
+/// in a balanced tree, so no merge waits for a shared accumulator. They aren't wrapped in `Lrc`,
+/// since no accumulator is ever used by two tasks; sharing them would only add reference counts
+/// and the clones of `Lrc::make_mut`.
+///
+/// The merge tree only depends on the number of items, not on the number of threads, so the
+/// result is reproducible even if `merge` isn't commutative. The items are split in order into
+/// leaves of `REDUCE_LEAF_SIZE` items, which are folded into their own accumulators. Then the
+/// accumulators of the first half of the leaves and those of the second half are merged
+/// recursively, and the two results are passed to `merge` in order. `merge` must still be
+/// associative and `make` should return its identity, since without parallel_queries all items
+/// are folded into a single accumulator.
+#[cfg(parallel_queries)]
+pub fn par_reduce_tree<T, A, Make, F, M>(items: Vec<T>, make: Make, fold: F, merge: M) -> A
+    where T: MaybeSend,
+          A: MaybeSend,
+          Make: Fn() -> A + MaybeSync + MaybeSend,
+          F: Fn(&mut A, T) + MaybeSync + MaybeSend,
+          M: Fn(A, A) -> A + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_reduce_tree");
+    if items.is_empty() {
+        return make();
+    }
+    let mut leaves = Vec::with_capacity((items.len() + REDUCE_LEAF_SIZE - 1) / REDUCE_LEAF_SIZE);
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        leaves.push(items.by_ref().take(REDUCE_LEAF_SIZE).collect::<Vec<T>>());
+    }
+    let accs = leaves.into_par_iter().map(|leaf| region.task(|| {
+        let mut acc = make();
+        for item in leaf {
+            fold(&mut acc, item);
+        }
+        acc
+    })).collect();
+    merge_tree(accs, &merge, &region)
+}
+
+/// Folds all the items into a single accumulator, so `merge` is never called.
+#[cfg(not(parallel_queries))]
+pub fn par_reduce_tree<T, A, Make, F, M>(items: Vec<T>, make: Make, fold: F, _merge: M) -> A
+    where T: MaybeSend,
+          A: MaybeSend,
+          Make: Fn() -> A + MaybeSync + MaybeSend,
+          F: Fn(&mut A, T) + MaybeSync + MaybeSend,
+          M: Fn(A, A) -> A + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_reduce_tree");
+    let mut acc = make();
+    for item in items {
+        region.task(|| audit::task(|| fold(&mut acc, item)));
+    }
+    acc
+}
+
+/// Counts the items in each of `buckets` buckets, where `bucket_of` returns the bucket of an
+/// item. Each piece of work rayon splits the items into counts into its own array, and the
+/// arrays are added up at the end. Panics if `bucket_of` returns an index of `buckets` or more.
+pub fn par_bincount<T, F>(items: Vec<T>, buckets: usize, bucket_of: F) -> Vec<usize>
+    where T: MaybeSend,
+          F: Fn(&T) -> usize + MaybeSync + MaybeSend
+{
+    par_accumulate(items,
+                   || vec![0; buckets],
+                   |counts, item| {
+                       let bucket = bucket_of(&item);
+                       assert!(bucket < buckets,
+                               "bucket {} is out of range for {} buckets", bucket, buckets);
+                       counts[bucket] += 1;
+                   },
+                   |mut a, b| {
+                       for (a, b) in a.iter_mut().zip(b) {
+                           *a += b;
+                       }
+                       a
+                   })
+}
+
+/// Returns the set of the indices of the items for which `pred` returns `true`. Each piece of
+/// work rayon splits the items into sets the bits in its own set, and the sets are merged at the
+/// end.
+pub fn par_matching_indices<T, I, F>(items: &[T], pred: F) -> BitSet<I>
+    where T: MaybeSync,
+          I: Idx + MaybeSend,
+          F: Fn(&T) -> bool + MaybeSync + MaybeSend
+{
+    par_accumulate(0..items.len(),
+                   || BitSet::new_empty(items.len()),
+                   |set, i| {
+                       if pred(&items[i]) {
+                           set.insert(I::new(i));
+                       }
+                   },
+                   |mut a, b| {
+                       a.union(&b);
+                       a
+                   })
+}
+
+/// Maps every item to a pair with `f` and returns the first and the second elements of the pairs
+/// in two vectors, e.g. keys and values. Both vectors are in the order of `items`.
+#[cfg(parallel_queries)]
+pub fn par_unzip<T, A, B, F>(items: Vec<T>, f: F) -> (Vec<A>, Vec<B>)
+    where T: MaybeSend,
+          A: MaybeSend,
+          B: MaybeSend,
+          F: Fn(T) -> (A, B) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_unzip");
+    let (mut a, mut b) = (Vec::new(), Vec::new());
+    items.into_par_iter()
+         .map(|item| region.task(|| f(item)))
+         .unzip_into_vecs(&mut a, &mut b);
+    (a, b)
+}
+
+/// Maps the items in order.
+#[cfg(not(parallel_queries))]
+pub fn par_unzip<T, A, B, F>(items: Vec<T>, f: F) -> (Vec<A>, Vec<B>)
+    where T: MaybeSend,
+          A: MaybeSend,
+          B: MaybeSend,
+          F: Fn(T) -> (A, B) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_unzip");
+    items.into_iter()
+         .map(|item| region.task(|| audit::task(|| f(item))))
+         .unzip()
+}
+
+/// Maps every item with `f` and returns the results sorted, like mapping with `par_iter` and
+/// then sorting, but in one parallel region. The sort is unstable, so results which compare
+/// equal may end up in any order; use `par_collect_sorted_stable` to keep them in the order
+/// of their items.
+#[cfg(parallel_queries)]
+pub fn par_collect_sorted<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+    where T: MaybeSend,
+          U: Ord + MaybeSend,
+          F: Fn(T) -> U + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_sorted");
+    let mut results: Vec<U> = items.into_par_iter().map(|item| region.task(|| f(item))).collect();
+    results.par_sort_unstable();
+    results
+}
+
+/// Maps the items in order and then sorts the results.
+#[cfg(not(parallel_queries))]
+pub fn par_collect_sorted<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+    where T: MaybeSend,
+          U: Ord + MaybeSend,
+          F: Fn(T) -> U + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_sorted");
+    let mut results: Vec<U> =
+        items.into_iter().map(|item| region.task(|| audit::task(|| f(item)))).collect();
+    results.sort_unstable();
+    results
+}
+
+/// Like `par_collect_sorted`, but results which compare equal stay in the order of their items.
+#[cfg(parallel_queries)]
+pub fn par_collect_sorted_stable<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+    where T: MaybeSend,
+          U: Ord + MaybeSend,
+          F: Fn(T) -> U + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_sorted_stable");
+    let mut results: Vec<U> = items.into_par_iter().map(|item| region.task(|| f(item))).collect();
+    results.par_sort();
+    results
+}
+
+/// Maps the items in order and then sorts the results with a stable sort.
+#[cfg(not(parallel_queries))]
+pub fn par_collect_sorted_stable<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+    where T: MaybeSend,
+          U: Ord + MaybeSend,
+          F: Fn(T) -> U + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_sorted_stable");
+    let mut results: Vec<U> =
+        items.into_iter().map(|item| region.task(|| audit::task(|| f(item)))).collect();
+    results.sort();
+    results
+}
+
+/// Maps every item with `f` and sends the results to the returned receiver, so the results can
+/// be consumed while the other items are still being processed. The work is spawned on the
+/// thread pool and this returns immediately. The order of the results is not specified. The
+/// channel is disconnected once all items are done. A panic in `f` is handled like those of
+/// `rayon::spawn`, which aborts the process unless the pool has a panic handler.
+#[cfg(parallel_queries)]
+pub fn par_for_each_send<T, U, F>(items: T, f: F) -> Receiver<U>
+    where T: IntoParallelIterator + MaybeSend + 'static,
+          U: MaybeSend + 'static,
+          F: Fn(T::Item) -> U + MaybeSync + MaybeSend + 'static
+{
+    let (sender, receiver) = mpsc::channel();
+    ::rayon::spawn(move || {
+        let region = Region::enter("par_for_each_send");
+        items.into_par_iter().for_each_with(sender, |sender, item| {
+            let result = region.task(|| f(item));
+            // The receiver may have been dropped, then nobody wants the remaining results.
+            let _ = sender.send(result);
+        });
+    });
+    receiver
+}
+
+/// Processes all the items in order before returning, so the channel is already disconnected.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_send<T, U, F>(items: T, f: F) -> Receiver<U>
+    where T: IntoIterator + MaybeSend + 'static,
+          U: MaybeSend + 'static,
+          F: Fn(T::Item) -> U + MaybeSync + MaybeSend + 'static
+{
+    let region = Region::enter("par_for_each_send");
+    let (sender, receiver) = mpsc::channel();
+    for item in items {
+        // `receiver` is still alive, so this can't fail.
+        sender.send(region.task(|| audit::task(|| f(item)))).unwrap();
+    }
+    receiver
+}
+
+/// Extends `target` with the items, e.g. a `&Lock<Vec<T>>`. Each piece of work rayon splits
+/// the items into is buffered and then added to `target` with a single call to `extend`, so a
+/// lock is acquired once per piece instead of once per item. The order of the items in
+/// `target` is not specified. Without parallel_queries `target` is extended once.
+#[cfg(parallel_queries)]
+pub fn par_collect_into<T, C>(items: T, target: C)
+    where T: IntoParallelIterator,
+          C: Extend<T::Item> + Copy + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_into");
+    items.into_par_iter()
+         .fold(Vec::new, |mut buffer, item| {
+             region.task(|| buffer.push(item));
+             buffer
+         })
+         .for_each(|buffer| {
+             let mut target = target;
+             target.extend(buffer);
+         });
+}
+
+/// Extends `target` once with all the items.
+#[cfg(not(parallel_queries))]
+pub fn par_collect_into<T, C>(items: T, target: C)
+    where T: IntoIterator,
+          C: Extend<T::Item> + Copy + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_collect_into");
+    let mut target = target;
+    let mut buffer = Vec::new();
+    for item in items {
+        region.task(|| audit::task(|| buffer.push(item)));
+    }
+    target.extend(buffer);
+}
+
+/// Calls `f` on every item until `token` is cancelled, possibly by `f` itself. Items which
+/// were not started before the token was cancelled are skipped, while the calls which already
+/// started run to completion. Returns `Err(Cancelled)` if the token was cancelled, even if all
+/// items were processed.
+#[cfg(parallel_queries)]
+pub fn par_for_each_until<T, F>(items: T, token: &CancellationToken, f: F)
+                                -> Result<(), Cancelled>
+    where T: IntoParallelIterator,
+          F: Fn(T::Item) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_until");
+    items.into_par_iter().for_each(|item| {
+        if !token.is_cancelled() {
+            region.task(|| f(item))
+        }
+    });
+    token.check()
+}
+
+/// Calls `f` on the items in order until `token` is cancelled.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_until<T, F>(items: T, token: &CancellationToken, f: F)
+                                -> Result<(), Cancelled>
+    where T: IntoIterator,
+          F: Fn(T::Item) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_until");
+    for item in items {
+        token.check()?;
+        region.task(|| audit::task(|| f(item)));
+    }
+    token.check()
+}
+
+/// Calls `f` on every item and returns all the errors it returned, in the order of the items.
+/// Unlike `?` on the first error, this processes every item, so all the errors can be reported.
+#[cfg(parallel_queries)]
+pub fn par_for_each_collect_errors<T, E, F>(items: T, f: F) -> Vec<E>
+    where T: IntoParallelIterator,
+          E: MaybeSend,
+          F: Fn(T::Item) -> Result<(), E> + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_collect_errors");
+    items.into_par_iter()
+         .filter_map(|item| region.task(|| f(item)).err())
+         .collect()
+}
+
+/// Calls `f` on the items in order.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_collect_errors<T, E, F>(items: T, f: F) -> Vec<E>
+    where T: IntoIterator,
+          E: MaybeSend,
+          F: Fn(T::Item) -> Result<(), E> + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_collect_errors");
+    items.into_iter()
+         .filter_map(|item| region.task(|| audit::task(|| f(item))).err())
+         .collect()
+}
+
+/// Splits the results of `par_map_collect_partial` into the values and the indexed errors.
+fn split_results<U, E, I>(results: I) -> (Vec<U>, Vec<(usize, E)>)
+    where I: IntoIterator<Item = Result<U, E>>
+{
+    let mut values = Vec::new();
+    let mut errors = Vec::new();
+    for (i, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(value) => values.push(value),
+            Err(err) => errors.push((i, err)),
+        }
+    }
+    (values, errors)
+}
+
+/// Maps every item with `f` and returns the values of the items it succeeded on, along with
+/// the errors of the other items paired with their indices, so a pass can go on with the good
+/// items while reporting the bad ones. Both are in the order of the items. Failed items leave
+/// no gap in the values, so the `n`th value belongs to the `n`th item which isn't in the
+/// errors.
+#[cfg(parallel_queries)]
+pub fn par_map_collect_partial<T, U, E, F>(items: Vec<T>, f: F) -> (Vec<U>, Vec<(usize, E)>)
+    where T: MaybeSend,
+          U: MaybeSend,
+          E: MaybeSend,
+          F: Fn(T) -> Result<U, E> + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_map_collect_partial");
+    let results: Vec<_> = items.into_par_iter().map(|item| region.task(|| f(item))).collect();
+    split_results(results)
+}
+
+/// Maps the items in order.
+#[cfg(not(parallel_queries))]
+pub fn par_map_collect_partial<T, U, E, F>(items: Vec<T>, f: F) -> (Vec<U>, Vec<(usize, E)>)
+    where T: MaybeSend,
+          U: MaybeSend,
+          E: MaybeSend,
+          F: Fn(T) -> Result<U, E> + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_map_collect_partial");
+    split_results(items.into_iter().map(|item| region.task(|| audit::task(|| f(item)))))
+}
+
+/// Calls `f` on every item, starting the items with higher priorities first, e.g. the items on
+/// the critical path of later work. The items are sorted by decreasing priority and then
+/// processed like `par_iter().for_each()`, so each thread works through a range of the sorted
+/// items in order. This is only a hint, items with lower priorities may still start before
+/// some of the higher ones finished or even started. Items with equal priorities start in
+/// their original order.
+#[cfg(parallel_queries)]
+pub fn par_for_each_prioritized<T, P, F>(mut items: Vec<T>, priority: P, f: F)
+    where T: MaybeSend,
+          P: Fn(&T) -> u32 + MaybeSync + MaybeSend,
+          F: Fn(T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_prioritized");
+    items.sort_by_key(|item| cmp::Reverse(priority(item)));
+    items.into_par_iter().for_each(|item| region.task(|| f(item)));
+}
+
+/// Calls `f` on the items in order of decreasing priority.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_prioritized<T, P, F>(mut items: Vec<T>, priority: P, f: F)
+    where T: MaybeSend,
+          P: Fn(&T) -> u32 + MaybeSync + MaybeSend,
+          F: Fn(T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_prioritized");
+    items.sort_by_key(|item| cmp::Reverse(priority(item)));
+    items.into_iter().for_each(|item| region.task(|| audit::task(|| f(item))));
+}
+
+/// Derives the seed of the item at `index` from `base_seed` with the finalizer of SplitMix64, so
+/// neighbouring indices get unrelated seeds.
+fn item_seed(base_seed: u64, index: usize) -> u64 {
+    let mut z = base_seed.wrapping_add((index as u64).wrapping_add(1)
+                                                    .wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Calls `f` on every item with a seed for a random number generator, which is derived from
+/// `base_seed` and the index of the item. So randomized passes are reproducible, independent
+/// of the number of threads and the order the items run in.
+#[cfg(parallel_queries)]
+pub fn par_for_each_seeded<T, F>(items: Vec<T>, base_seed: u64, f: F)
+    where T: MaybeSend,
+          F: Fn(u64, T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_seeded");
+    items.into_par_iter()
+         .enumerate()
+         .for_each(|(i, item)| region.task(|| f(item_seed(base_seed, i), item)));
+}
+
+/// Calls `f` on the items in order, with the same seeds as the parallel version.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_seeded<T, F>(items: Vec<T>, base_seed: u64, f: F)
+    where T: MaybeSend,
+          F: Fn(u64, T) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_seeded");
+    for (i, item) in items.into_iter().enumerate() {
+        region.task(|| audit::task(|| f(item_seed(base_seed, i), item)));
+    }
+}
+
+/// The number of items `par_for_each_yielding` processes between yields.
+#[cfg(parallel_queries)]
+const YIELD_INTERVAL: usize = 256;
+
+/// Calls `f` on every item like `par_iter().for_each()`, but yields after every
+/// `YIELD_INTERVAL` items processed by a task, so a very long pass doesn't starve
+/// latency-sensitive work sharing its threads. Yielding may reduce throughput slightly.
+/// rustc-rayon 0.1 has no `yield_now` which runs other jobs of the pool, so this yields the
+/// thread to the OS scheduler instead.
+#[cfg(parallel_queries)]
+pub fn par_for_each_yielding<T, F>(items: T, f: F)
+    where T: IntoParallelIterator,
+          F: Fn(T::Item) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_yielding");
+    items.into_par_iter()
+        .fold(|| 0, |processed, item| {
+            region.task(|| f(item));
+            if processed + 1 == YIELD_INTERVAL {
+                ::std::thread::yield_now();
+                0
+            } else {
+                processed + 1
+            }
+        })
+        .for_each(|_| {});
+}
+
+/// A plain loop, since there is nothing to yield to.
+#[cfg(not(parallel_queries))]
+pub fn par_for_each_yielding<T, F>(items: T, f: F)
+    where T: IntoIterator,
+          F: Fn(T::Item) + MaybeSync + MaybeSend
+{
+    let region = Region::enter("par_for_each_yielding");
+    for item in items {
+        region.task(|| audit::task(|| f(item)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fx::FxHashMap;
+    use sync::{Lock, in_pool};
+
+    #[test]
+    fn test_par_for_each_window() {
+        let items = [1, 2, 3, 4, 5];
+        let seen = Lock::new(Vec::new());
+        par_for_each_window(&items, 3, |w| seen.lock().push(w.to_vec()));
+        let mut seen = seen.into_inner();
+        seen.sort();
+        assert_eq!(seen, vec![vec![1, 2, 3], vec![2, 3, 4], vec![3, 4, 5]]);
+    }
+
+    #[test]
+    fn test_par_for_each_window_larger_than_slice() {
+        let items = [1, 2];
+        let seen = Lock::new(0);
+        par_for_each_window(&items, 3, |_| *seen.lock() += 1);
+        assert_eq!(seen.into_inner(), 0);
+    }
+
+    #[test]
+    fn test_par_map_in_place() {
+        let mut items: Vec<u32> = (0..100).collect();
+        let ptr = items.as_ptr();
+        par_map_in_place(&mut items, |item| *item *= 2);
+        assert_eq!(items, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(items.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_par_for_each_with_affinity() {
+        use std::thread;
+
+        let items: Vec<usize> = (0..64).collect();
+        let seen = Lock::new(Vec::new());
+        par_for_each_with_affinity(&items,
+                                   |i, workers| i % 4 % workers,
+                                   |&item| seen.lock().push((item, thread::current().id())));
+        let mut seen = seen.into_inner();
+        seen.sort_by_key(|&(item, _)| item);
+        assert_eq!(seen.iter().map(|&(item, _)| item).collect::<Vec<_>>(), items);
+        // Items assigned the same worker are processed by the same thread.
+        for &(item, thread) in &seen {
+            assert_eq!(thread, seen[item % 4].1);
+        }
+    }
+
+    #[test]
+    fn test_par_for_each_worker() {
+        #[cfg(parallel_queries)]
+        let workers = ::rayon::current_num_threads();
+        #[cfg(not(parallel_queries))]
+        let workers = 1;
+
+        let seen = Lock::new(Vec::new());
+        par_for_each_worker(0..100, |worker, item| seen.lock().push((item, worker)));
+        let mut seen = seen.into_inner();
+        seen.sort();
+        assert_eq!(seen.iter().map(|&(item, _)| item).collect::<Vec<_>>(),
+                   (0..100).collect::<Vec<_>>());
+        assert!(seen.iter().all(|&(_, worker)| worker < workers), "{:?}", seen);
+    }
+
+    #[test]
+    fn test_par_collect_indexvec() {
+        let calls = Lock::new(Vec::new());
+        let squares: IndexVec<u32, u64> = par_collect_indexvec(1000, |i: u32| {
+            calls.lock().push(i);
+            u64::from(i) * u64::from(i)
+        });
+        let expected: IndexVec<u32, u64> = (0..1000u64).map(|i| i * i).collect();
+        assert_eq!(squares, expected);
+
+        let mut calls = calls.into_inner();
+        calls.sort();
+        assert_eq!(calls, (0..1000).collect::<Vec<_>>());
+        assert!(par_collect_indexvec::<u32, u64, _>(0, |_| unreachable!()).is_empty());
+    }
+
+    /// A segment tree answering queries for the sums of ranges of items.
+    enum SumTree {
+        Leaf(Vec<u64>),
+        /// The sum and number of the items, followed by the subtrees.
+        Node(u64, usize, Box<SumTree>, Box<SumTree>),
+    }
+
+    impl SumTree {
+        fn leaf(items: &[u64]) -> Self {
+            SumTree::Leaf(items.to_vec())
+        }
+
+        fn node(left: SumTree, right: SumTree) -> Self {
+            SumTree::Node(left.total() + right.total(), left.len() + right.len(),
+                          Box::new(left), Box::new(right))
+        }
+
+        fn len(&self) -> usize {
+            match *self {
+                SumTree::Leaf(ref items) => items.len(),
+                SumTree::Node(_, len, ..) => len,
+            }
+        }
+
+        fn total(&self) -> u64 {
+            self.sum(0, self.len())
+        }
+
+        fn depth(&self) -> usize {
+            match *self {
+                SumTree::Leaf(_) => 0,
+                SumTree::Node(_, _, ref left, ref right) => 1 + left.depth().max(right.depth()),
+            }
+        }
+
+        /// Returns the sum of the items in `start..end`.
+        fn sum(&self, start: usize, end: usize) -> u64 {
+            match *self {
+                SumTree::Leaf(ref items) => items[start..end].iter().sum(),
+                SumTree::Node(sum, len, _, _) if start == 0 && end == len => sum,
+                SumTree::Node(_, _, ref left, ref right) => {
+                    let mid = left.len();
+                    let mut sum = 0;
+                    if start < mid {
+                        sum += left.sum(start, mid.min(end));
+                    }
+                    if end > mid {
+                        sum += right.sum(start.max(mid) - mid, end - mid);
+                    }
+                    sum
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_par_split_process_segment_tree() {
+        let items: Vec<u64> = (0..200).map(|i| i * 7 % 13).collect();
+        let build = || par_split_process(&items, SumTree::leaf, SumTree::node);
+
+        let tree = in_pool(4, build);
+        #[cfg(parallel_queries)]
+        assert!(tree.depth() > 0);
+        // `f` is called once on all the items.
+        #[cfg(not(parallel_queries))]
+        assert_eq!(tree.depth(), 0);
+
+        assert_eq!(tree.len(), items.len());
+        for &(start, end) in &[(0, 200), (0, 1), (5, 6), (17, 123), (100, 200), (199, 200)] {
+            assert_eq!(tree.sum(start, end), items[start..end].iter().sum::<u64>());
+        }
+
+        let empty = par_split_process(&[] as &[u64], SumTree::leaf, SumTree::node);
+        assert_eq!(empty.len(), 0);
+    }
+
+    #[test]
+    fn test_par_accumulate_histogram() {
+        let words = vec!["a", "b", "a", "c", "b", "a"];
+        let histogram = par_accumulate(words,
+                                       FxHashMap::default,
+                                       |counts, word| *counts.entry(word).or_insert(0) += 1,
+                                       |mut a, b| {
+                                           for (word, count) in b {
+                                               *a.entry(word).or_insert(0) += count;
+                                           }
+                                           a
+                                       });
+        let mut histogram: Vec<_> = histogram.into_iter().collect();
+        histogram.sort();
+        assert_eq!(histogram, vec![("a", 3), ("b", 2), ("c", 1)]);
+    }
+
+    #[test]
+    fn test_par_collect_into() {
+        let target = Lock::new(Vec::new());
+        let collect = || par_collect_into(0..1000u32, &target);
+
+        // Error checking makes contended acquisitions panic, so use a single worker with it.
+        in_pool(if cfg!(feature = "lock-error-checking") { 1 } else { 4 }, collect);
+
+        // The lock is acquired once per piece of work, not once per item.
+        #[cfg(all(feature = "lock-metrics", parallel_queries))]
+        assert!(target.acquisitions() < 1000, "{}", target.acquisitions());
+        #[cfg(all(feature = "lock-metrics", not(parallel_queries)))]
+        assert_eq!(target.acquisitions(), 1);
+
+        let mut items = target.into_inner();
+        items.sort();
+        assert_eq!(items, (0..1000).collect::<Vec<_>>());
+
+        let map = Lock::new(FxHashMap::default());
+        par_collect_into((0..100u32).map(|i| (i, i * 2)).collect::<Vec<_>>(), &map);
+        let map = map.into_inner();
+        assert_eq!(map.len(), 100);
+        assert!(map.iter().all(|(&k, &v)| v == k * 2));
+    }
+
+    #[test]
+    fn test_par_for_each_until() {
+        use std::sync::atomic::Ordering;
+        use sync::AtomicUsize;
+
+        let token = CancellationToken::new();
+        let sum = AtomicUsize::new(0);
+        let add = |i| { sum.fetch_add(i, Ordering::SeqCst); };
+        assert_eq!(par_for_each_until(0..10usize, &token, add), Ok(()));
+        assert_eq!(sum.load(Ordering::SeqCst), 45);
+
+        // Cancelling the parent stops the work using the child token.
+        let child = token.child();
+        let calls = AtomicUsize::new(0);
+        let result = par_for_each_until(0..1000usize, &child, |i| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            if i == 0 {
+                token.cancel();
+            }
+        });
+        assert_eq!(result, Err(Cancelled));
+        #[cfg(not(parallel_queries))]
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(calls.load(Ordering::SeqCst) < 1000);
+        assert_eq!(par_for_each_until(0..10, &child, |_| panic!("cancelled")), Err(Cancelled));
+    }
+
+    #[test]
+    fn test_par_argmin_argmax() {
+        let items = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 9, 1];
+        let check = || {
+            assert_eq!(par_argmin(&items, |&x| x), Some(1));
+            assert_eq!(par_argmax(&items, |&x| x), Some(5));
+            // Every key is equal, so the first item wins.
+            assert_eq!(par_argmin(&items, |_| ()), Some(0));
+            assert_eq!(par_argmax(&items, |_| ()), Some(0));
+            assert_eq!(par_argmax(&items, |&x| x % 5), Some(2));
+            assert_eq!(par_argmin(&[] as &[u32], |&x| x), None);
+        };
+        check();
+        #[cfg(parallel_queries)]
+        for &threads in &[1, 2, 4] {
+            in_pool(threads, check);
+        }
+    }
+
+    #[test]
+    fn test_par_unzip() {
+        let items: Vec<u32> = (0..1000).collect();
+        let (squares, names) = par_unzip(items.clone(), |i| (i * i, i.to_string()));
+        let expected: (Vec<u32>, Vec<String>) =
+            items.into_iter().map(|i| (i * i, i.to_string())).unzip();
+        assert_eq!((squares, names), expected);
+    }
+
+    #[test]
+    fn test_par_collect_sorted() {
+        let items: Vec<u64> = (0..10000).collect();
+        let f = |i: u64| i * 7919 % 1000;
+        let mut expected: Vec<u64> = items.iter().cloned().map(f).collect();
+        expected.sort();
+        assert_eq!(par_collect_sorted(items.clone(), f), expected);
+        assert_eq!(par_collect_sorted_stable(items, f), expected);
+        assert!(par_collect_sorted(Vec::<u64>::new(), f).is_empty());
+    }
+
+    /// An item which is compared by its key only, but remembers the index it was created from.
+    #[derive(Debug)]
+    struct Keyed(u32, usize);
+
+    impl PartialEq for Keyed {
+        fn eq(&self, other: &Keyed) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Keyed {}
+
+    impl PartialOrd for Keyed {
+        fn partial_cmp(&self, other: &Keyed) -> Option<cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Keyed {
+        fn cmp(&self, other: &Keyed) -> cmp::Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn test_par_collect_sorted_stable() {
+        let items: Vec<usize> = (0..10000).collect();
+        let f = |i: usize| Keyed((i % 10) as u32, i);
+        let mut expected: Vec<Keyed> = items.iter().cloned().map(f).collect();
+        expected.sort();
+        let sorted = par_collect_sorted_stable(items, f);
+        // Results with equal keys are still in the order of their items.
+        assert_eq!(sorted.iter().map(|k| (k.0, k.1)).collect::<Vec<_>>(),
+                   expected.iter().map(|k| (k.0, k.1)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_par_for_each_collect_errors() {
+        use std::sync::atomic::Ordering;
+        use sync::AtomicUsize;
+
+        let processed = AtomicUsize::new(0);
+        let errors = par_for_each_collect_errors(0..1000u32, |i| {
+            processed.fetch_add(1, Ordering::SeqCst);
+            if i % 100 == 7 { Err(format!("item {}", i)) } else { Ok(()) }
+        });
+        assert_eq!(processed.load(Ordering::SeqCst), 1000);
+        let expected: Vec<_> = (0..10).map(|i| format!("item {}", i * 100 + 7)).collect();
+        assert_eq!(errors, expected);
+        assert!(par_for_each_collect_errors(0..10, |_| Ok::<(), ()>(())).is_empty());
+    }
+
+    #[test]
+    fn test_par_for_each_prioritized() {
+        let run = || {
+            let order = Lock::new(Vec::new());
+            let items = vec![(1, 'a'), (5, 'b'), (1, 'c'), (9, 'd'), (5, 'e')];
+            par_for_each_prioritized(items, |&(priority, _)| priority,
+                                     |(_, name)| order.lock().push(name));
+            order.into_inner()
+        };
+
+        // With one thread the items are processed in the order they start in.
+        let order = in_pool(1, run);
+        assert_eq!(order, ['d', 'b', 'e', 'a', 'c']);
+    }
+
+    #[test]
+    fn test_par_for_each_seeded() {
+        use sync::AtomicU64;
+        use std::sync::atomic::Ordering;
+
+        let seeds = |base_seed| {
+            let seeds: Vec<AtomicU64> = (0..1000).map(|_| AtomicU64::new(0)).collect();
+            par_for_each_seeded((0..1000).collect(), base_seed,
+                                |seed, i: usize| seeds[i].store(seed, Ordering::Relaxed));
+            seeds.into_iter().map(|seed| seed.into_inner()).collect::<Vec<u64>>()
+        };
+
+        let expected = seeds(42);
+        let mut unique = expected.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 1000);
+        assert_ne!(seeds(43), expected);
+
+        // The seeds don't depend on the number of threads.
+        #[cfg(parallel_queries)]
+        {
+            let workers = if cfg!(feature = "lock-error-checking") { 1 } else { 4 };
+            for &threads in &[1, workers] {
+                assert_eq!(in_pool(threads, || seeds(42)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_par_for_each_send() {
+        let receiver = par_for_each_send((0..1000u32).collect::<Vec<_>>(), |i| i * 2);
+        let mut results: Vec<u32> = receiver.iter().collect();
+        results.sort();
+        assert_eq!(results, (0..1000).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_par_for_each_yielding() {
+        let seen = Lock::new(vec![0; 1000]);
+        par_for_each_yielding(0..1000usize, |i| seen.lock()[i] += 1);
+        assert!(seen.into_inner().iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn test_par_bincount() {
+        let items: Vec<u32> = (0..1000).map(|i| i * 7 % 31).collect();
+        let mut expected = vec![0; 10];
+        for item in &items {
+            expected[*item as usize % 10] += 1;
+        }
+
+        let count = || par_bincount(items.clone(), 10, |&item| item as usize % 10);
+        assert_eq!(in_pool(4, count), expected);
+
+        assert_eq!(par_bincount(Vec::<u32>::new(), 3, |_| 0), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_par_matching_indices() {
+        let items: Vec<u32> = (0..1000).map(|i| i * 7 % 31).collect();
+        let mut expected = BitSet::new_empty(items.len());
+        for (i, item) in items.iter().enumerate() {
+            if item % 3 == 0 {
+                expected.insert(i);
+            }
+        }
+
+        let matching = || par_matching_indices(&items, |item| item % 3 == 0);
+        let set: BitSet<usize> = in_pool(4, matching);
+        assert_eq!(set.words(), expected.words());
+        assert_eq!(set.domain_size(), 1000);
+
+        let none: BitSet<usize> = par_matching_indices(&[1, 2], |_| false);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_par_map_collect_partial() {
+        let parse = || par_map_collect_partial((0..1000u32).collect(), |i| {
+            if i % 7 == 3 { Err(format!("bad {}", i)) } else { Ok(i * 2) }
+        });
+        let (values, errors) = in_pool(4, parse);
+        assert_eq!(values, (0..1000).filter(|i| i % 7 != 3).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(errors,
+                   (0..1000).filter(|i| i % 7 == 3)
+                            .map(|i| (i as usize, format!("bad {}", i)))
+                            .collect::<Vec<_>>());
+
+        let (values, errors) = par_map_collect_partial(vec![1, 2], |i| Err::<u32, _>(i));
+        assert!(values.is_empty());
+        assert_eq!(errors, [(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn test_partition_by_cost() {
+        // A few huge items and many small ones, in an order which is bad for uniform splitting.
+        let items: Vec<u64> = (0..4).map(|_| 1000).chain((0..400).map(|i| i % 10 + 1)).collect();
+        let total: u64 = items.iter().sum();
+        let bins = partition_by_cost(items, |&cost| cost, 4);
+        assert_eq!(bins.iter().map(|bin| bin.len()).sum::<usize>(), 404);
+        let totals: Vec<u64> = bins.iter().map(|bin| bin.iter().sum()).collect();
+        for &bin_total in &totals {
+            // Each bin gets one huge item and a quarter of the rest, within the cost of the
+            // largest small item.
+            assert!(bin_total * 4 >= total - 4 * 10 && bin_total * 4 <= total + 4 * 10,
+                    "{:?}", totals);
+        }
+
+        assert_eq!(partition_by_cost(vec![5, 1], |&cost| cost, 3), [vec![5], vec![1], vec![]]);
+    }
+
+    #[test]
+    fn test_par_for_each_weighted() {
+        let seen = Lock::new(vec![0; 1000]);
+        let run = || par_for_each_weighted((0..1000usize).collect(), |&i| (i % 17) as u64,
+                                           |i| seen.lock()[i] += 1);
+        // Use several workers so there are several bins. Error checking makes contended
+        // acquisitions panic, so use a single worker with it.
+        in_pool(if cfg!(feature = "lock-error-checking") { 1 } else { 4 }, run);
+        assert!(seen.into_inner().iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn test_par_reduce_tree() {
+        let items: Vec<usize> = (0..10000).map(|i| i * 7919 % 20000).collect();
+        let mut expected = BitSet::new_empty(20000);
+        for &item in &items {
+            expected.insert(item);
+        }
+
+        let reduce = || {
+            let set: BitSet<usize> = par_reduce_tree(items.clone(),
+                                                     || BitSet::new_empty(20000),
+                                                     |set, item| { set.insert(item); },
+                                                     |mut left, right| {
+                                                         left.union(&right);
+                                                         left
+                                                     });
+            // Concatenation isn't commutative, so this checks the order of the merges.
+            let order = par_reduce_tree(items.clone(),
+                                        Vec::new,
+                                        |order, item| order.push(item),
+                                        |mut left, right| {
+                                            left.extend(right);
+                                            left
+                                        });
+            (set, order)
+        };
+        // Use several workers so the leaves are folded concurrently.
+        let (set, order) = in_pool(4, reduce);
+        assert_eq!(set.words(), expected.words());
+        assert_eq!(order, items);
+
+        assert_eq!(par_reduce_tree(Vec::<u32>::new(), || 7, |_, _| {}, |a, b| a + b), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket 3 is out of range for 3 buckets")]
+    fn test_par_bincount_out_of_range() {
+        par_bincount(vec![1, 2, 3], 3, |&item| item);
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A pool of scratch objects which are expensive to allocate, e.g. large buffers, so hot
+//! parallel passes can reuse them instead of allocating a new one per task.
+//!
+//! `Pool::get` takes an object from the free-list, or creates one with the factory if the
+//! free-list is empty. The object is put back when the returned `PoolGuard` is dropped. The
+//! free-list is a `Lock<Vec<T>>`, which is a `RefCell` without cfg!(parallel_queries).
+
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use super::{Lock, Send, Sync};
+
+pub struct Pool<T> {
+    free: Lock<Vec<T>>,
+    create: Box<dyn Fn() -> T + Send + Sync>,
+}
+
+impl<T> Pool<T> {
+    /// Creates an empty pool which creates objects with `create`.
+    pub fn new<F: Fn() -> T + Send + Sync + 'static>(create: F) -> Self {
+        Pool {
+            free: Lock::new(Vec::new()),
+            create: Box::new(create),
+        }
+    }
+
+    /// Returns an object from the pool, or a new one if the pool is empty. Objects are not
+    /// reset when they are returned; the caller must clear any state left by its last user.
+    pub fn get(&self) -> PoolGuard<T> {
+        // The lock is released before calling the factory.
+        let recycled = self.free.lock().pop();
+        let value = recycled.unwrap_or_else(|| (self.create)());
+        PoolGuard {
+            pool: self,
+            value: Some(value),
+        }
+    }
+
+    /// Returns the number of objects in the free-list.
+    pub fn free_len(&self) -> usize {
+        self.free.lock().len()
+    }
+
+    /// Removes the objects from the free-list and returns them.
+    pub fn clear(&self) -> Vec<T> {
+        mem::replace(&mut *self.free.lock(), Vec::new())
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool").field("free", &self.free_len()).finish()
+    }
+}
+
+/// An object of a `Pool`, which is put back into the pool when this is dropped.
+pub struct PoolGuard<'a, T: 'a> {
+    pool: &'a Pool<T>,
+    /// This is only `None` after `detach`.
+    value: Option<T>,
+}
+
+impl<'a, T> PoolGuard<'a, T> {
+    /// Takes the object out of the pool permanently.
+    pub fn detach(mut self) -> T {
+        self.value.take().unwrap()
+    }
+}
+
+impl<'a, T> Deref for PoolGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for PoolGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for PoolGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.pool.free.lock().push(value);
+        }
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for PoolGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_pool() -> (Pool<Vec<u8>>, Arc<AtomicUsize>) {
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = created.clone();
+        let pool = Pool::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Vec::with_capacity(1024)
+        });
+        (pool, created)
+    }
+
+    #[test]
+    fn test_objects_are_reused() {
+        let (pool, created) = counting_pool();
+        let first = {
+            let mut buffer = pool.get();
+            buffer.push(1);
+            buffer.as_ptr()
+        };
+        assert_eq!(pool.free_len(), 1);
+        for _ in 0..10 {
+            let mut buffer = pool.get();
+            // This is the same allocation and it keeps its contents.
+            assert_eq!(buffer.as_ptr(), first);
+            assert_eq!(*buffer, [1]);
+            buffer.clear();
+            buffer.push(1);
+        }
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+
+        // Objects in use are not handed out twice.
+        let (a, b) = (pool.get(), pool.get());
+        assert_ne!(a.as_ptr(), b.as_ptr());
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+        drop((a, b));
+        assert_eq!(pool.free_len(), 2);
+    }
+
+    #[test]
+    fn test_detach() {
+        let (pool, created) = counting_pool();
+        let buffer = pool.get().detach();
+        assert_eq!(buffer.capacity(), 1024);
+        assert_eq!(pool.free_len(), 0);
+        drop(pool.get());
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.clear().len(), 1);
+        assert_eq!(pool.free_len(), 0);
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_pool_in_parallel_tasks() {
+        use sync::{in_pool, par_iter};
+        use rayon::iter::ParallelIterator;
+
+        let (pool, created) = counting_pool();
+        let pool_ref = &pool;
+        in_pool(4, || {
+            par_iter(0..1000).for_each(|i| {
+                let mut buffer = pool_ref.get();
+                buffer.clear();
+                buffer.push(i as u8);
+            });
+        });
+        // At most one object per thread is in use at once.
+        assert!(created.load(Ordering::SeqCst) <= 4);
+        assert_eq!(pool.free_len(), created.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Raw locks implementing the `lock_api` traits in both builds.
+//!
+//! `RawLock` and `RawRwLock` are the raw locks of `parking_lot` if cfg!(parallel_queries) is
+//! true. Otherwise they are flags in a `Cell`, which panic instead of blocking like `RefCell`.
+//! `Lock` and `RwLock` wrap the `lock_api` locks built on them, so without the `lock-metrics`
+//! feature their guards are the `lock_api` guards in both builds, and code which is generic
+//! over `lock_api::RawMutex` or `lock_api::RawRwLock` works with them. With `lock-metrics` the
+//! guards are wrapped to report how long the lock was held, which can't be done by the raw
+//! lock itself since it must have a constant initial value.
+
+use lock_api;
+
+cfg_if! {
+    if #[cfg(parallel_queries)] {
+        pub use parking_lot::RawMutex as RawLock;
+        pub use parking_lot::RawRwLock;
+    } else {
+        use std::cell::Cell;
+        use lock_api::GuardNoSend;
+
+        /// A raw mutex which panics if it is already locked.
+        pub struct RawLock {
+            locked: Cell<bool>,
+        }
+
+        unsafe impl lock_api::RawMutex for RawLock {
+            const INIT: RawLock = RawLock { locked: Cell::new(false) };
+
+            type GuardMarker = GuardNoSend;
+
+            #[inline]
+            fn lock(&self) {
+                if !self.try_lock() {
+                    panic!("already borrowed");
+                }
+            }
+
+            #[inline]
+            fn try_lock(&self) -> bool {
+                !self.locked.replace(true)
+            }
+
+            #[inline]
+            fn unlock(&self) {
+                self.locked.set(false);
+            }
+        }
+
+        /// The state of a `RawRwLock` which is locked exclusively.
+        const EXCLUSIVE: usize = !0;
+
+        /// A raw read-write lock which panics if it can't be locked.
+        pub struct RawRwLock {
+            /// The number of shared locks, including an upgradable one, or `EXCLUSIVE`.
+            state: Cell<usize>,
+            /// Whether one of the shared locks is upgradable.
+            upgradable: Cell<bool>,
+        }
+
+        unsafe impl lock_api::RawRwLock for RawRwLock {
+            const INIT: RawRwLock = RawRwLock {
+                state: Cell::new(0),
+                upgradable: Cell::new(false),
+            };
+
+            type GuardMarker = GuardNoSend;
+
+            #[inline]
+            fn lock_shared(&self) {
+                if !self.try_lock_shared() {
+                    panic!("already mutably borrowed");
+                }
+            }
+
+            #[inline]
+            fn try_lock_shared(&self) -> bool {
+                let state = self.state.get();
+                if state >= EXCLUSIVE - 1 {
+                    return false;
+                }
+                self.state.set(state + 1);
+                true
+            }
+
+            #[inline]
+            fn unlock_shared(&self) {
+                self.state.set(self.state.get() - 1);
+            }
+
+            #[inline]
+            fn lock_exclusive(&self) {
+                if !self.try_lock_exclusive() {
+                    panic!("already borrowed");
+                }
+            }
+
+            #[inline]
+            fn try_lock_exclusive(&self) -> bool {
+                if self.state.get() != 0 {
+                    return false;
+                }
+                self.state.set(EXCLUSIVE);
+                true
+            }
+
+            #[inline]
+            fn unlock_exclusive(&self) {
+                self.state.set(0);
+            }
+        }
+
+        unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
+            #[inline]
+            fn lock_upgradable(&self) {
+                if !self.try_lock_upgradable() {
+                    panic!("already borrowed");
+                }
+            }
+
+            #[inline]
+            fn try_lock_upgradable(&self) -> bool {
+                if self.upgradable.get() || !lock_api::RawRwLock::try_lock_shared(self) {
+                    return false;
+                }
+                self.upgradable.set(true);
+                true
+            }
+
+            #[inline]
+            fn unlock_upgradable(&self) {
+                self.upgradable.set(false);
+                lock_api::RawRwLock::unlock_shared(self);
+            }
+
+            /// Panics if there are other readers, since they can't release the lock while
+            /// this waits for them.
+            #[inline]
+            fn upgrade(&self) {
+                if !self.try_upgrade() {
+                    panic!("already borrowed");
+                }
+            }
+
+            #[inline]
+            fn try_upgrade(&self) -> bool {
+                if self.state.get() != 1 {
+                    return false;
+                }
+                self.upgradable.set(false);
+                self.state.set(EXCLUSIVE);
+                true
+            }
+        }
+    }
+}
+
+pub type InnerLock<T> = lock_api::Mutex<RawLock, T>;
+pub type RawLockGuard<'a, T> = lock_api::MutexGuard<'a, RawLock, T>;
+pub type RawMappedLockGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawLock, T>;
+
+pub type InnerRwLock<T> = lock_api::RwLock<RawRwLock, T>;
+pub type RawReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawRwLock, T>;
+pub type RawMappedReadGuard<'a, T> = lock_api::MappedRwLockReadGuard<'a, RawRwLock, T>;
+pub type RawWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawRwLock, T>;
+pub type RawMappedWriteGuard<'a, T> = lock_api::MappedRwLockWriteGuard<'a, RawRwLock, T>;
+pub type RawUpgradableReadGuard<'a, T> = lock_api::RwLockUpgradableReadGuard<'a, RawRwLock, T>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lock_api::{MutexGuard, RawMutex, RwLockReadGuard, RwLockWriteGuard};
+    use lock_api::RwLockUpgradableReadGuard;
+
+    fn push_twice<R: RawMutex>(mut guard: MutexGuard<R, Vec<u32>>, value: u32) -> usize {
+        guard.push(value);
+        let mut guard = MutexGuard::map(guard, |v| v);
+        guard.push(value);
+        guard.len()
+    }
+
+    fn sum<R: lock_api::RawRwLock>(a: RwLockReadGuard<R, Vec<u32>>,
+                                   b: RwLockReadGuard<R, Vec<u32>>) -> u32 {
+        assert!(RwLockReadGuard::rwlock(&a).try_write().is_none());
+        a.iter().chain(b.iter()).sum()
+    }
+
+    fn push<R: lock_api::RawRwLock>(mut guard: RwLockWriteGuard<R, Vec<u32>>, value: u32) {
+        guard.push(value);
+        assert!(RwLockWriteGuard::rwlock(&guard).try_read().is_none());
+    }
+
+    #[test]
+    fn test_generic_lock() {
+        let lock = InnerLock::new(vec![1]);
+        assert_eq!(push_twice(lock.lock(), 2), 3);
+        {
+            let _guard: RawLockGuard<_> = lock.lock();
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(lock.into_inner(), [1, 2, 2]);
+    }
+
+    #[test]
+    fn test_generic_rw_lock() {
+        let lock = InnerRwLock::new(vec![1, 2]);
+        assert_eq!(sum(lock.read(), lock.try_read().unwrap()), 6);
+        push(lock.write(), 3);
+        let guard: RawReadGuard<_> = lock.read();
+        assert_eq!(*guard, [1, 2, 3]);
+    }
+
+    // With `lock-metrics` the guards are wrapped to report hold times.
+    #[cfg(not(feature = "lock-metrics"))]
+    #[test]
+    fn test_generic_guards_of_sync_locks() {
+        use sync;
+
+        let lock = sync::Lock::new(vec![1]);
+        assert_eq!(push_twice(lock.lock(), 2), 3);
+        assert_eq!(push_twice(lock.try_lock().unwrap(), 3), 5);
+        assert_eq!(lock.into_inner(), [1, 2, 2, 3, 3]);
+
+        let lock = sync::RwLock::new(vec![1, 2]);
+        push(lock.write(), 3);
+        assert_eq!(sum(lock.read(), lock.try_read().unwrap()), 12);
+    }
+
+    #[test]
+    fn test_upgradable_read() {
+        let lock: InnerRwLock<u32> = InnerRwLock::new(1);
+        let guard = lock.upgradable_read();
+        // Readers can share the lock with an upgradable reader, but not another one.
+        assert_eq!(*lock.try_read().unwrap(), 1);
+        assert!(lock.try_upgradable_read().is_none());
+        assert!(lock.try_write().is_none());
+        let guard = {
+            let _reader = lock.read();
+            RwLockUpgradableReadGuard::try_upgrade(guard).err().unwrap()
+        };
+        let mut guard = RwLockUpgradableReadGuard::upgrade(guard);
+        *guard += 1;
+        assert!(lock.try_read().is_none());
+        drop(guard);
+        assert_eq!(*lock.try_upgradable_read().unwrap(), 2);
+        assert!(lock.try_write().is_some());
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn test_serial_relock_panics() {
+        let lock: InnerLock<u32> = InnerLock::new(0);
+        let _guard = lock.lock();
+        let _relocked = lock.lock();
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[test]
+    #[should_panic(expected = "already mutably borrowed")]
+    fn test_serial_read_while_written_panics() {
+        let lock: InnerRwLock<u32> = InnerRwLock::new(0);
+        let _guard = lock.write();
+        let _read = lock.read();
+    }
+
+    #[cfg(not(parallel_queries))]
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn test_serial_upgrade_with_readers_panics() {
+        let lock: InnerRwLock<u32> = InnerRwLock::new(0);
+        let guard = lock.upgradable_read();
+        let _read = lock.read();
+        let _write = RwLockUpgradableReadGuard::upgrade(guard);
+    }
+}
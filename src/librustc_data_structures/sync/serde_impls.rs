@@ -0,0 +1,181 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `serde` support for the `sync` types, enabled by the `serde` feature.
+//!
+//! `Lock`, `RwLock` and `MTLock` serialize as the value they contain and `Once` serializes as
+//! an `Option` of its value. Serializing one of these holds its lock (a read lock for `RwLock`)
+//! while the value is serialized, like the `Mutex` impls of other crates. So the value must
+//! not lead back to the same lock, e.g. through an `Lrc` cycle, or serialization panics
+//! without cfg!(parallel_queries) and deadlocks with it. Serializing a structure containing
+//! these does not give a consistent snapshot if other threads modify it concurrently, since
+//! each lock is only held while its own value is serialized.
+//!
+//! The atomics of the parallel build are the ones in `std`, which `serde` has no impls for.
+//! Fields of an atomic type need `#[serde(with = "rustc_data_structures::sync::atomic")]`
+//! instead, which serializes the value the atomic had at that point. This works in both builds.
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use super::{Lock, RwLock, Once, MTLock};
+
+impl<T: Serialize> Serialize for Lock<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.lock().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Lock<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Lock::new)
+    }
+}
+
+impl<T: Serialize> Serialize for RwLock<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.read().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for RwLock<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(RwLock::new)
+    }
+}
+
+impl<T: Serialize> Serialize for MTLock<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        T::serialize(&*self.lock(), serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for MTLock<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(MTLock::new)
+    }
+}
+
+impl<T: Serialize> Serialize for Once<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.try_get().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Once<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let once = Once::new();
+        if let Some(value) = Option::deserialize(deserializer)? {
+            once.set(value);
+        }
+        Ok(once)
+    }
+}
+
+/// Serializes the atomics as their current value, for use with `#[serde(with = "...")]`.
+pub mod atomic {
+    use std::sync::atomic::Ordering;
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use sync::{AtomicUsize, AtomicBool, AtomicU32, AtomicU64};
+
+    /// An atomic which can be serialized with this module.
+    pub trait SerdeAtomic {
+        type Value;
+
+        fn snapshot(&self) -> Self::Value;
+        fn from_value(value: Self::Value) -> Self;
+    }
+
+    macro_rules! impl_serde_atomic {
+        ($($atomic:ident($value:ty)),*) => {
+            $(
+                impl SerdeAtomic for $atomic {
+                    type Value = $value;
+
+                    #[inline]
+                    fn snapshot(&self) -> $value {
+                        self.load(Ordering::SeqCst)
+                    }
+
+                    #[inline]
+                    fn from_value(value: $value) -> Self {
+                        $atomic::new(value)
+                    }
+                }
+            )*
+        }
+    }
+
+    impl_serde_atomic!(AtomicUsize(usize), AtomicBool(bool), AtomicU32(u32), AtomicU64(u64));
+
+    pub fn serialize<A, S>(atomic: &A, serializer: S) -> Result<S::Ok, S::Error>
+        where A: SerdeAtomic,
+              A::Value: Serialize,
+              S: Serializer
+    {
+        atomic.snapshot().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, A, D>(deserializer: D) -> Result<A, D::Error>
+        where A: SerdeAtomic,
+              A::Value: Deserialize<'de>,
+              D: Deserializer<'de>
+    {
+        A::Value::deserialize(deserializer).map(A::from_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use sync::{Lock, RwLock, Once, MTLock, AtomicUsize, AtomicBool};
+    use std::sync::atomic::Ordering;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrappers {
+        lock: Lock<Vec<u32>>,
+        rw_lock: RwLock<String>,
+        once: Once<u64>,
+        unset_once: Once<u64>,
+        mt_lock: MTLock<(bool, u8)>,
+        #[serde(with = "::sync::atomic")]
+        counter: AtomicUsize,
+        #[serde(with = "::sync::atomic")]
+        flag: AtomicBool,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let wrappers = Wrappers {
+            lock: Lock::new(vec![1, 2]),
+            rw_lock: RwLock::new("rw".to_string()),
+            once: Once::new(),
+            unset_once: Once::new(),
+            mt_lock: MTLock::new((true, 3)),
+            counter: AtomicUsize::new(0),
+            flag: AtomicBool::new(false),
+        };
+        wrappers.once.set(4);
+        wrappers.counter.fetch_add(5, Ordering::SeqCst);
+        wrappers.flag.store(true, Ordering::SeqCst);
+
+        let json = serde_json::to_string(&wrappers).unwrap();
+        assert_eq!(json,
+                   r#"{"lock":[1,2],"rw_lock":"rw","once":4,"unset_once":null,"#.to_string() +
+                   r#""mt_lock":[true,3],"counter":5,"flag":true}"#);
+
+        let wrappers: Wrappers = serde_json::from_str(&json).unwrap();
+        assert_eq!(*wrappers.lock.lock(), [1, 2]);
+        assert_eq!(*wrappers.rw_lock.read(), "rw");
+        assert_eq!(wrappers.once.try_get(), Some(&4));
+        assert_eq!(wrappers.unset_once.try_get(), None);
+        assert_eq!(wrappers.mt_lock.into_inner(), (true, 3));
+        assert_eq!(wrappers.counter.load(Ordering::SeqCst), 5);
+        assert!(wrappers.flag.load(Ordering::SeqCst));
+    }
+}
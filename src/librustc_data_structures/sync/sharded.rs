@@ -0,0 +1,240 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A value split into several shards, each with its own `Lock`, so threads using different
+//! shards rarely contend. Values are assigned to shards by their `FxHasher` hash. Without
+//! cfg!(parallel_queries) there is a single shard.
+//!
+//! Once a sharded table becomes read-only, e.g. after the parallel phase which filled it,
+//! `freeze` drops the locks. The shards of the returned `FrozenSharded` can be read by any
+//! number of threads without synchronization. Freezing panics if a shard is still locked,
+//! which is only possible if a guard was leaked.
+
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+use fx::{FxHashMap, FxHasher};
+use super::Lock;
+
+#[cfg(parallel_queries)]
+const SHARDS: usize = 32;
+#[cfg(not(parallel_queries))]
+const SHARDS: usize = 1;
+
+fn shard_index<K: Hash + ?Sized>(value: &K) -> usize {
+    let mut hasher = FxHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+pub struct Sharded<T> {
+    shards: Vec<Lock<T>>,
+}
+
+impl<T> Sharded<T> {
+    /// Creates the shards with `value`.
+    pub fn new<F: FnMut() -> T>(mut value: F) -> Self {
+        Sharded {
+            shards: (0..SHARDS).map(|_| Lock::new(value())).collect(),
+        }
+    }
+
+    /// Same as `new`, but gives the locks of the shards a name, see `Lock::named`.
+    pub fn named<F: FnMut() -> T>(name: &'static str, mut value: F) -> Self {
+        Sharded {
+            shards: (0..SHARDS).map(|_| Lock::named(name, value())).collect(),
+        }
+    }
+
+    /// Returns the shard of `value`.
+    #[inline]
+    pub fn get_shard_by_value<K: Hash + ?Sized>(&self, value: &K) -> &Lock<T> {
+        &self.shards[shard_index(value)]
+    }
+
+    #[inline]
+    pub fn shards(&self) -> &[Lock<T>] {
+        &self.shards
+    }
+
+    /// Drops the locks of the shards, so they can be read without synchronization.
+    /// Panics with the index of the shard if one of them is locked.
+    pub fn freeze(self) -> FrozenSharded<T> {
+        for (i, shard) in self.shards.iter().enumerate() {
+            if shard.try_lock().is_none() {
+                panic!("shard {} is locked while freezing a `Sharded`", i);
+            }
+        }
+        FrozenSharded {
+            shards: self.shards.into_iter().map(|shard| shard.into_inner()).collect(),
+        }
+    }
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Sharded::new(T::default)
+    }
+}
+
+/// The shards of a `Sharded` after `Sharded::freeze`, which have no locks.
+pub struct FrozenSharded<T> {
+    shards: Vec<T>,
+}
+
+impl<T> FrozenSharded<T> {
+    /// Returns the shard of `value`.
+    #[inline]
+    pub fn get_shard_by_value<K: Hash + ?Sized>(&self, value: &K) -> &T {
+        &self.shards[shard_index(value)]
+    }
+
+    #[inline]
+    pub fn shards(&self) -> &[T] {
+        &self.shards
+    }
+}
+
+pub type ShardedHashMap<K, V> = Sharded<FxHashMap<K, V>>;
+
+impl<K: Eq + Hash, V> ShardedHashMap<K, V> {
+    /// Returns the number of entries. This locks each shard in turn, so the result may be
+    /// outdated if other threads insert or remove entries meanwhile.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Inserts an entry into the shard of `key`, returning the previous value of the key.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.get_shard_by_value(&key).lock().insert(key, value)
+    }
+}
+
+pub type FrozenShardedHashMap<K, V> = FrozenSharded<FxHashMap<K, V>>;
+
+impl<K: Eq + Hash, V> FrozenShardedHashMap<K, V> {
+    #[inline]
+    pub fn get<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q> {
+        self.get_shard_by_value(key).get(key)
+    }
+
+    #[inline]
+    pub fn contains_key<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q> {
+        self.get(key).is_some()
+    }
+
+    /// Iterates over the entries, shard by shard.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use std::panic;
+
+    fn filled() -> ShardedHashMap<u32, String> {
+        let map = ShardedHashMap::default();
+        for i in 0..100 {
+            map.insert(i, i.to_string());
+        }
+        map
+    }
+
+    #[test]
+    fn test_freeze() {
+        let map = filled();
+        assert_eq!(map.insert(7, "seven".to_string()), Some("7".to_string()));
+        assert_eq!(map.len(), 100);
+        let before: FxHashMap<u32, String> = map.shards()
+                                                .iter()
+                                                .flat_map(|shard| shard.lock().clone())
+                                                .collect();
+        let frozen = map.freeze();
+        assert_eq!(frozen.len(), 100);
+        for (key, value) in &before {
+            assert_eq!(frozen.get(key), Some(value));
+        }
+        assert_eq!(frozen.get(&100), None);
+        let mut entries: Vec<_> = frozen.iter().map(|(&key, value)| (key, value.clone())).collect();
+        entries.sort();
+        let mut expected: Vec<_> = before.into_iter().collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_freeze_locked_shard_panics() {
+        let map = filled();
+        let index = shard_index(&42);
+        mem::forget(map.get_shard_by_value(&42).lock());
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| map.freeze()));
+        let message = result.err().unwrap();
+        assert_eq!(message.downcast_ref::<String>(),
+                   Some(&format!("shard {} is locked while freezing a `Sharded`", index)));
+    }
+
+    #[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+    #[test]
+    fn test_freeze_under_read_load() {
+        use std::sync::Arc;
+        use std::thread;
+        use rayon::prelude::*;
+        use sync::in_pool;
+
+        let map = Arc::new(filled());
+        let readers: Vec<_> = (0..4).map(|_| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let key = i % 100;
+                    let value = map.get_shard_by_value(&key).lock().get(&key).cloned();
+                    assert_eq!(value, Some(key.to_string()));
+                }
+            })
+        }).collect();
+        // Freeze the map as soon as the last reader is done with it.
+        let mut map = map;
+        let map = loop {
+            match Arc::try_unwrap(map) {
+                Ok(map) => break map,
+                Err(shared) => {
+                    map = shared;
+                    thread::yield_now();
+                }
+            }
+        };
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let frozen = map.freeze();
+        // The frozen map is read by the workers without any locks.
+        let found = in_pool(4, || {
+            (0..1000u32).into_par_iter().filter(|i| {
+                frozen.get(&(i % 100)).map(|value| *value == (i % 100).to_string()) == Some(true)
+            }).count()
+        });
+        assert_eq!(found, 1000);
+        assert_eq!(frozen.len(), 100);
+    }
+}
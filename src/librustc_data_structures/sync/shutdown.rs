@@ -0,0 +1,176 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An explicit order for tearing down shared structures at the end of a session.
+//!
+//! Dropping a web of `Lrc`s runs the destructors in whatever order the last references go
+//! away, possibly on a thread of the pool, so a destructor may lock something which was
+//! already destroyed. `ShutdownOrder` instead runs registered callbacks in the order of their
+//! keys, on the calling thread, once the thread pool is gone. Use `drain_pool_then` to run
+//! the shutdown after all threads of the pool have exited.
+
+use std::mem;
+use super::{Lock, MaybeSend, Send};
+
+struct Callback {
+    key: u32,
+    name: &'static str,
+    /// A `FnOnce` can't be called in a box, so this panics if it is called twice.
+    run: Box<dyn FnMut() + Send>,
+}
+
+/// Callbacks which run at the end of a session, in the order of their keys.
+pub struct ShutdownOrder {
+    callbacks: Lock<Vec<Callback>>,
+}
+
+impl ShutdownOrder {
+    pub fn new() -> Self {
+        ShutdownOrder {
+            callbacks: Lock::new(Vec::new()),
+        }
+    }
+
+    /// Registers `callback` to run in `run_shutdown`. Callbacks with lower keys run first,
+    /// callbacks with the same key run in the order they were registered. `name` is logged
+    /// when the callback runs.
+    pub fn on_shutdown<F>(&self, key: u32, name: &'static str, callback: F)
+        where F: FnOnce() + MaybeSend + 'static
+    {
+        let mut callback = Some(callback);
+        self.callbacks.lock().push(Callback {
+            key,
+            name,
+            run: Box::new(move || (callback.take().expect("shutdown callback called twice"))()),
+        });
+    }
+
+    /// Runs the callbacks in the order of their keys on the current thread. Callbacks may
+    /// register more callbacks, which run after the ones registered before.
+    ///
+    /// Panics if this is called on a thread of a thread pool, since the threads of the pool may
+    /// still use the structures being torn down, and if the `sync-debug` feature is enabled and
+    /// a guard of a named lock is held.
+    pub fn run_shutdown(self) {
+        assert_pool_stopped();
+        #[cfg(feature = "sync-debug")]
+        super::assert_no_guards_held();
+        loop {
+            let mut callbacks = mem::replace(&mut *self.callbacks.lock(), Vec::new());
+            if callbacks.is_empty() {
+                return;
+            }
+            // This is a stable sort, so callbacks with the same key keep their order.
+            callbacks.sort_by_key(|callback| callback.key);
+            for mut callback in callbacks {
+                debug!("running shutdown callback `{}`", callback.name);
+                (callback.run)();
+            }
+        }
+    }
+}
+
+impl Default for ShutdownOrder {
+    fn default() -> Self {
+        ShutdownOrder::new()
+    }
+}
+
+#[cfg(parallel_queries)]
+fn assert_pool_stopped() {
+    assert!(::rayon_core::current_thread_index().is_none(),
+            "`run_shutdown` called on a thread of a thread pool");
+}
+
+#[cfg(not(parallel_queries))]
+fn assert_pool_stopped() {}
+
+/// Runs `work` in a new thread pool with `threads` threads, waits until all threads of the
+/// pool have exited and then passes the result to `then` on the current thread, e.g. to call
+/// `ShutdownOrder::run_shutdown`. Without parallel_queries `threads` is ignored and both
+/// closures run on the current thread.
+#[cfg(parallel_queries)]
+pub fn drain_pool_then<W, R, F, S>(threads: usize, work: W, then: F) -> S
+    where W: FnOnce() -> R + Send,
+          R: Send,
+          F: FnOnce(R) -> S
+{
+    let builder = ::rayon_core::ThreadPoolBuilder::new().num_threads(threads);
+    // `scoped_pool` waits for the threads to stop before it returns.
+    let result = ::rayon_core::ThreadPool::scoped_pool(builder,
+                                                       |worker: &mut dyn FnMut()| worker(),
+                                                       |pool| pool.install(work));
+    then(result.expect("failed to create the thread pool"))
+}
+
+/// Runs both closures on the current thread, `threads` is ignored.
+#[cfg(not(parallel_queries))]
+pub fn drain_pool_then<W, R, F, S>(_threads: usize, work: W, then: F) -> S
+    where W: FnOnce() -> R + Send,
+          R: Send,
+          F: FnOnce(R) -> S
+{
+    then(work())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "sync-debug"))]
+    use sync::Lrc;
+
+    // Tests running concurrently may hold guards of named locks, which `run_shutdown` rejects
+    // with the `sync-debug` feature.
+    #[cfg(not(feature = "sync-debug"))]
+    #[test]
+    fn test_shutdown_order() {
+        let order = ShutdownOrder::new();
+        let ran = Lrc::new(Lock::new(Vec::new()));
+        for &(key, name) in &[(30, "c"), (10, "a1"), (20, "b"), (10, "a2")] {
+            let ran = ran.clone();
+            order.on_shutdown(key, name, move || ran.lock().push(name));
+        }
+        order.run_shutdown();
+        assert_eq!(*ran.lock(), ["a1", "a2", "b", "c"]);
+    }
+
+    #[cfg(not(feature = "sync-debug"))]
+    #[test]
+    fn test_drain_pool_then() {
+        let order = ShutdownOrder::new();
+        let ran = Lrc::new(Lock::new(Vec::new()));
+        let result = drain_pool_then(2, || 1 + 1, |result| {
+            let ran = ran.clone();
+            order.on_shutdown(0, "check", move || ran.lock().push(result));
+            order.run_shutdown();
+            result * 10
+        });
+        assert_eq!(result, 20);
+        assert_eq!(*ran.lock(), [2]);
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    #[should_panic(expected = "`run_shutdown` called on a thread of a thread pool")]
+    fn test_shutdown_in_pool() {
+        use sync::in_pool;
+
+        in_pool(1, || ShutdownOrder::new().run_shutdown());
+    }
+
+    #[cfg(feature = "sync-debug")]
+    #[test]
+    #[should_panic(expected = "guards are still held")]
+    fn test_shutdown_with_held_lock() {
+        let lock = Lock::named("test_shutdown_with_held_lock", 0);
+        let _guard = lock.lock();
+        ShutdownOrder::new().run_shutdown();
+    }
+}
@@ -0,0 +1,1832 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::*;
+use std::cell::Cell;
+use std::env;
+#[cfg(any(parallel_queries, strict_sync_check))]
+use std::marker::PhantomData;
+use std::mem;
+use std::panic;
+use std::process;
+#[cfg(any(parallel_queries, strict_sync_check))]
+use std::rc::Rc;
+use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static_assert_send!(Lrc<Lock<Vec<u32>>>);
+static_assert_sync!(RwLock<String>);
+static_assert_sync!(Once<Lrc<u32>>);
+
+// The wrappers must stay usable from several threads, for representative inner types.
+static_assert_send!(Lock<Vec<u32>>);
+static_assert_sync!(Lock<Vec<u32>>);
+static_assert_sync!(Lock<Cell<u32>>);
+static_assert_send!(RwLock<Vec<u32>>);
+static_assert_sync!(RwLock<Vec<u32>>);
+static_assert_send!(Once<String>);
+static_assert_sync!(Once<String>);
+static_assert_send!(MTLock<Vec<u32>>);
+static_assert_sync!(MTLock<Cell<u32>>);
+static_assert_sync!(WorkerLocal<Cell<u32>>);
+static_assert_send!(OneThread<*const ()>);
+static_assert_sync!(OneThread<*const ()>);
+static_assert_send!(UnsafeSend<*const ()>);
+
+/// Tells whether `T` implements the std `Send` and `Sync` traits, which `assert_send` and
+/// `assert_sync` can't check the absence of. The inherent constants are only found if their
+/// bounds hold, otherwise the defaults of the traits are used.
+#[cfg(any(parallel_queries, strict_sync_check))]
+struct Implements<T: ?Sized>(PhantomData<T>);
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+trait NotSend {
+    const SEND: bool = false;
+}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+trait NotSync {
+    const SYNC: bool = false;
+}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+impl<T: ?Sized> NotSend for Implements<T> {}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+impl<T: ?Sized> NotSync for Implements<T> {}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+#[allow(dead_code)]
+impl<T: ?Sized + Send> Implements<T> {
+    const SEND: bool = true;
+}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+#[allow(dead_code)]
+impl<T: ?Sized + Sync> Implements<T> {
+    const SYNC: bool = true;
+}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+#[test]
+fn test_not_send_sync() {
+    // Sanity checks of `Implements` itself.
+    assert!(Implements::<Vec<u32>>::SEND && Implements::<Vec<u32>>::SYNC);
+    assert!(!Implements::<*const ()>::SEND && !Implements::<*const ()>::SYNC);
+
+    // The wrappers must not make values usable from several threads which aren't `Send`.
+    assert!(!Implements::<Lock<Rc<u32>>>::SEND && !Implements::<Lock<Rc<u32>>>::SYNC);
+    assert!(!Implements::<RwLock<Rc<u32>>>::SYNC);
+    assert!(!Implements::<MTLock<Rc<u32>>>::SYNC);
+    // Readers share the value, so `RwLock` and `Once` also need it to be `Sync`.
+    assert!(Implements::<Lock<Cell<u32>>>::SYNC);
+    assert!(!Implements::<RwLock<Cell<u32>>>::SYNC);
+    assert!(!Implements::<Once<Cell<u32>>>::SYNC);
+    assert!(Implements::<Once<Cell<u32>>>::SEND);
+
+    // `OneThread` checks the thread at runtime instead, so it is always `Send` and `Sync`.
+    // Every thread gets its own value of a `WorkerLocal`, so it is always `Sync` too.
+    assert!(Implements::<OneThread<*const ()>>::SEND);
+    assert!(Implements::<OneThread<Rc<u32>>>::SYNC);
+    assert!(Implements::<WorkerLocal<Rc<u32>>>::SYNC);
+    // `UnsafeSend` only makes its value `Send`.
+    assert!(Implements::<UnsafeSend<Rc<u32>>>::SEND && !Implements::<UnsafeSend<Rc<u32>>>::SYNC);
+}
+
+#[test]
+fn test_mt_rw_lock_concurrent_reads() {
+    let lock = MTRwLock::new(vec![1, 2, 3]);
+    let held = lock.read();
+    let (len, first) = join(|| lock.read().len(), || lock.read()[0]);
+    assert_eq!((len, first), (3, 1));
+    assert_eq!(held[2], 3);
+}
+
+#[test]
+fn test_mt_rw_lock_write() {
+    // `write` only takes `&mut self` if cfg!(parallel_queries) is false.
+    #[allow(unused_mut)]
+    let mut lock = MTRwLock::new(0);
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 1);
+    assert_eq!(lock.into_inner(), 1);
+}
+
+fn panic_message<F: FnOnce()>(f: F) -> String {
+    let payload = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(f)).unwrap_err();
+    payload.downcast_ref::<String>()
+           .cloned()
+           .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+           .unwrap()
+}
+
+#[test]
+fn test_once_double_set_panics() {
+    let once = Once::new();
+    once.set(1);
+    assert!(panic_message(|| once.set(2)).starts_with("Once already initialized"));
+    assert_eq!(once.try_set_same(1), Some(1));
+    assert_eq!(*once.get(), 1);
+}
+
+#[cfg(feature = "sync-debug")]
+#[test]
+fn test_once_double_set_reports_first_location() {
+    let once = Once::new();
+    let line = line!() + 1;
+    once.set_at(1, sync_location!());
+    let first = format!("{}:{}:", file!(), line);
+
+    let msg = panic_message(|| once.set(2));
+    assert!(msg.starts_with("Once already initialized at "), "{}", msg);
+    assert!(msg.contains(&first), "{}", msg);
+
+    let msg = panic_message(|| { once.try_set_same(2); });
+    assert!(msg.contains(&first), "{}", msg);
+    assert!(msg.ends_with("with a different value"), "{}", msg);
+}
+
+#[test]
+fn test_lazy_shared_concurrent_get() {
+    let lazy = LazyShared::new();
+    let inits = AtomicUsize::new(0);
+    let get = || lazy.get(|| {
+        inits.fetch_add(1, Ordering::SeqCst);
+        Vec::new()
+    });
+    let (a, b) = join(|| get(), || get());
+    assert!(Lrc::ptr_eq(&a, &b));
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+
+    a.write().push(1);
+    assert_eq!(*lazy.try_get().unwrap().read(), [1]);
+}
+
+#[test]
+fn test_once_init_parallel_runs_closure_once() {
+    let once = Once::new();
+    let inits = AtomicUsize::new(0);
+    let init = || {
+        let values = par_iter(0..8).map(|_| {
+            *once.init_parallel(|| {
+                inits.fetch_add(1, Ordering::SeqCst);
+                // Give the other callers time to block, and parallelize the closure itself.
+                thread::sleep(Duration::from_millis(20));
+                let (a, b) = join(|| 20, || 22);
+                a + b
+            })
+        }).collect::<Vec<u32>>();
+        assert_eq!(values, [42; 8]);
+    };
+
+    // Use several workers so the callers are concurrent. Error checking makes contended
+    // acquisitions panic, so use a single worker with it.
+    in_pool(if cfg!(feature = "lock-error-checking") { 1 } else { 4 }, init);
+
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+    assert_eq!(once.init_parallel(|| panic!("already initialized")), &42);
+}
+
+#[test]
+fn test_once_init_macro() {
+    let once = Once::new();
+    let inits = AtomicUsize::new(0);
+    let get = || *once_init!(once, {
+        inits.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(10));
+        vec![1, 2]
+    }).last().unwrap();
+    let (a, b) = join(|| get(), || get());
+    assert_eq!((a, b, get()), (2, 2, 2));
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+
+    // The `Once` may be behind a pointer, and isn't initialized again.
+    let shared = Lrc::new(Once::new());
+    assert_eq!(*once_init!(*shared.clone(), 7), 7);
+    assert_eq!(*once_init!(*shared, panic!("already initialized")), 7);
+}
+
+#[test]
+fn test_once_init_fast_path_does_not_lock() {
+    let once = Once::new();
+    once.set(1);
+    // Locking the `Once` again would panic or deadlock, so finding the value set only reads
+    // the `initialized` flag.
+    let _lock = once.lock.lock();
+    assert_eq!(*once_init!(once, panic!("already initialized")), 1);
+    assert_eq!(once.try_get(), Some(&1));
+    assert_eq!(once.get_or_init(|| 2), &1);
+    assert_eq!(once.init_nonlocking(|| 2), None);
+    assert_eq!(format!("{:?}", once), "Once { data: 1 }");
+}
+
+#[test]
+fn test_once_get_or_init() {
+    let once = Once::new();
+    let inits = AtomicUsize::new(0);
+    let get = || once.get_or_init(|| {
+        inits.fetch_add(1, Ordering::SeqCst);
+        vec![1, 2]
+    });
+    let first = get();
+    for _ in 0..10 {
+        assert!(ptr::eq(get(), first));
+    }
+    let (a, b) = join(|| get().len(), || get().len());
+    assert_eq!((a, b), (2, 2));
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+    assert_eq!(once.try_get(), Some(&vec![1, 2]));
+}
+
+#[test]
+fn test_once_lock_set_and_get() {
+    let cell = OnceLock::new();
+    assert_eq!(cell.get(), None);
+    assert_eq!(cell.set(92), Ok(()));
+    assert_eq!(cell.set(62), Err(62));
+    assert_eq!(cell.get(), Some(&92));
+    assert_eq!(cell.into_inner(), Some(92));
+}
+
+#[test]
+fn test_once_lock_get_or_init() {
+    let cell = OnceLock::new();
+    let inits = AtomicUsize::new(0);
+    let init = || cell.get_or_init(|| inits.fetch_add(1, Ordering::SeqCst) + 92);
+    let (a, b) = join(|| *init(), || *init());
+    assert_eq!((a, b), (92, 92));
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+    // The closure is not called once the value is initialized.
+    assert_eq!(*cell.get_or_init(|| unreachable!()), 92);
+    assert_eq!(cell.set(1), Err(1));
+}
+
+#[test]
+fn test_once_lock_drops_value() {
+    let value = Lrc::new(());
+    let cell = OnceLock::default();
+    cell.set(value.clone()).unwrap();
+    assert_eq!(Lrc::strong_count(&value), 2);
+    drop(cell);
+    assert_eq!(Lrc::strong_count(&value), 1);
+
+    // A conflicting value is returned to the caller without being stored.
+    let cell = OnceLock::new();
+    cell.set(Lrc::new(())).unwrap();
+    let rejected = cell.set(value.clone()).unwrap_err();
+    assert!(Lrc::ptr_eq(&rejected, &value));
+}
+
+#[test]
+fn test_once_as_once_lock() {
+    let once = Once::new();
+    assert_eq!(once.as_once_lock().get(), None);
+    assert_eq!(once.as_once_lock().set(1), Ok(()));
+    assert_eq!(*once.get(), 1);
+    assert_eq!(once.as_once_lock().set(2), Err(2));
+    assert!(panic_message(|| once.set(3)).starts_with("Once already initialized"));
+}
+
+#[cfg(all(parallel_queries, feature = "lock-error-checking"))]
+#[test]
+fn test_already_held_reports_holder() {
+    let lock = Lock::new(0);
+    let line = line!() + 1;
+    let _guard = lock.lock_at(sync_location!());
+    let holder = format!("last acquired at {}:{}:", file!(), line);
+
+    let msg = panic_message(|| drop(lock.lock()));
+    assert!(msg.starts_with("lock was already held"), "{}", msg);
+    assert!(msg.contains(&holder), "{}", msg);
+    assert!(msg.contains("on thread `"), "{}", msg);
+
+    let rw_lock = RwLock::new(0);
+    let _read_guard = rw_lock.read_at(sync_location!());
+    let msg = panic_message(|| drop(rw_lock.write()));
+    assert!(msg.contains(&format!("{}:{}:", file!(), line!() - 2)), "{}", msg);
+}
+
+#[cfg(feature = "lock-error-checking")]
+#[test]
+fn test_holder_backtrace() {
+    let holder = error_checking::Holder::capture(Some(sync_location!()), true);
+    let description = holder.to_string();
+    assert!(description.contains("backtrace of the acquisition:"), "{}", description);
+}
+
+#[test]
+fn test_lock_option_get_or_init() {
+    let lock = Lock::new(None);
+    assert_eq!(*lock.get_or_init(|| 1), 1);
+    *lock.get_or_init(|| unreachable!()) += 1;
+    assert_eq!(*lock.lock(), Some(2));
+
+    *lock.lock() = None;
+    assert_eq!(*lock.get_or_init(|| 3), 3);
+}
+
+#[cfg(any(parallel_queries, strict_sync_check))]
+#[test]
+fn test_unsafe_send() {
+    use std::sync::mpsc;
+
+    let (to_worker, from_main) = mpsc::channel::<UnsafeSend<Rc<Vec<u32>>>>();
+    let (to_main, from_worker) = mpsc::channel();
+    let worker = thread::spawn(move || {
+        let mut value = from_main.recv().unwrap().into_inner();
+        Rc::get_mut(&mut value).unwrap().push(3);
+        // There are no other clones of the `Rc`, so it can be handed back.
+        to_main.send(unsafe { UnsafeSend::new(value) }).unwrap();
+    });
+    // This is the only clone of the `Rc`, and it isn't used until the worker returns it.
+    to_worker.send(unsafe { UnsafeSend::new(Rc::new(vec![1, 2])) }).unwrap();
+    let value = from_worker.recv().unwrap().into_inner();
+    worker.join().unwrap();
+    assert_eq!(*value, [1, 2, 3]);
+    assert_eq!(Rc::strong_count(&value), 1);
+}
+
+#[test]
+fn test_cache_padded() {
+    let expected_align = if cfg!(parallel_queries) { 64 } else { mem::align_of::<u64>() };
+    assert_eq!(mem::align_of::<CachePadded<AtomicU64>>(), expected_align);
+    assert_eq!(mem::align_of::<CachePadded<u8>>(), if cfg!(parallel_queries) { 64 } else { 1 });
+
+    let counter = CachePadded::new(AtomicU64::new(0));
+    counter.fetch_add(2, Ordering::Relaxed);
+    assert_eq!(counter.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_lock_is_locked() {
+    let lock = Lock::new(0);
+    assert!(!lock.is_locked());
+    {
+        let _guard = lock.lock();
+        assert!(lock.is_locked());
+        assert!(lock.is_locked());
+    }
+    // The probes released the lock.
+    assert!(!lock.is_locked());
+    *lock.lock() += 1;
+    assert!(!lock.is_locked());
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test]
+fn test_lock_timeout() {
+    let lock = Lock::new(0);
+    {
+        let _guard = lock.lock();
+        assert!(lock.try_lock_for(Duration::from_millis(10)).is_none());
+    }
+    *lock.try_lock_for(Duration::from_millis(10)).unwrap() += 1;
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_lock_timeout_waits_for_release() {
+    let lock = Lrc::new(Lock::new(0));
+    let guard = lock.lock();
+    let waiter = {
+        let lock = lock.clone();
+        thread::spawn(move || lock.try_lock_for(Duration::from_secs(10)).map(|value| *value))
+    };
+    thread::sleep(Duration::from_millis(20));
+    drop(guard);
+    assert_eq!(waiter.join().unwrap(), Some(0));
+}
+
+#[test]
+fn test_lock_contention() {
+    let lock = Lock::new(1);
+    assert_eq!(lock.with_lock_contention(|value| { *value += 1; *value }), (2, false));
+    let rw_lock = RwLock::new(1);
+    assert_eq!(rw_lock.with_read_lock_contention(|value| *value), (1, false));
+    assert_eq!(rw_lock.with_write_lock_contention(|value| { *value += 1; *value }), (2, false));
+    // Readers don't contend with each other.
+    let _read = rw_lock.read();
+    assert_eq!(rw_lock.with_read_lock_contention(|value| *value), (2, false));
+}
+
+// Error checking makes contended acquisitions panic.
+#[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+#[test]
+fn test_held_lock_reports_contention() {
+    let lock = Lrc::new(Lock::new(1));
+    let guard = lock.lock();
+    let waiter = {
+        let lock = lock.clone();
+        thread::spawn(move || lock.with_lock_contention(|value| *value))
+    };
+    thread::sleep(Duration::from_millis(20));
+    drop(guard);
+    assert_eq!(waiter.join().unwrap(), (1, true));
+
+    let rw_lock = Lrc::new(RwLock::new(1));
+    let read = rw_lock.read();
+    let writer = {
+        let rw_lock = rw_lock.clone();
+        thread::spawn(move || rw_lock.with_write_lock_contention(|value| { *value += 1; *value }))
+    };
+    thread::sleep(Duration::from_millis(20));
+    drop(read);
+    assert_eq!(writer.join().unwrap(), (2, true));
+
+    let write = rw_lock.write();
+    let reader = {
+        let rw_lock = rw_lock.clone();
+        thread::spawn(move || rw_lock.with_read_lock_contention(|value| *value))
+    };
+    thread::sleep(Duration::from_millis(20));
+    drop(write);
+    assert_eq!(reader.join().unwrap(), (2, true));
+}
+
+#[test]
+fn test_rw_lock_upgradable_read() {
+    let counter = RwLock::new(0);
+    let increment_if_even = || {
+        let guard = counter.upgradable_read();
+        if *guard % 2 != 0 {
+            return false;
+        }
+        let mut guard = UpgradableReadGuard::upgrade(guard);
+        *guard += 1;
+        assert!(counter.try_write().is_err());
+        true
+    };
+    assert!(increment_if_even());
+    assert!(!increment_if_even());
+    assert_eq!(*counter.read(), 1);
+    *counter.write() += 1;
+    assert!(increment_if_even());
+    assert_eq!(counter.into_inner(), 3);
+}
+
+// Error checking makes contended acquisitions panic.
+#[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+#[test]
+fn test_rw_lock_upgradable_read_in_parallel() {
+    let counter = RwLock::new(0);
+    {
+        // Upgradable reads don't block plain readers, but writers.
+        let guard = counter.upgradable_read();
+        assert_eq!(*counter.read(), 0);
+        assert!(counter.try_write().is_err());
+        drop(guard);
+    }
+    let increment = || for _ in 0..100 {
+        let guard = counter.upgradable_read();
+        let value = *guard;
+        // No other thread can modify the value until the guard is dropped.
+        let mut guard = UpgradableReadGuard::upgrade(guard);
+        assert_eq!(*guard, value);
+        *guard = value + 1;
+    };
+    in_pool(4, || par_iter(0..4).for_each(|_| increment()));
+    assert_eq!(counter.into_inner(), 400);
+}
+
+#[test]
+fn test_rw_lock_try_read() {
+    let lock = RwLock::new(1);
+    {
+        let read = lock.try_read().unwrap();
+        // Readers don't exclude each other.
+        assert_eq!((*read, *lock.try_read().unwrap()), (1, 1));
+    }
+    {
+        let mut write = lock.write();
+        assert!(lock.try_read().is_err());
+        *write += 1;
+    }
+    assert_eq!(*lock.try_read().unwrap(), 2);
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_rw_lock_try_read_contended_by_other_thread() {
+    let lock = Lrc::new(RwLock::new(1));
+    use std::sync::Barrier;
+
+    let (locked, release) = (Lrc::new(Barrier::new(2)), Lrc::new(Barrier::new(2)));
+    let writer = {
+        let (lock, locked, release) = (lock.clone(), locked.clone(), release.clone());
+        thread::spawn(move || {
+            let mut write = lock.write();
+            locked.wait();
+            release.wait();
+            *write += 1;
+        })
+    };
+    locked.wait();
+    assert!(lock.try_read().is_err());
+    release.wait();
+    writer.join().unwrap();
+    assert_eq!(*lock.try_read().unwrap(), 2);
+}
+
+#[test]
+fn test_rw_lock_timeouts() {
+    let lock = RwLock::new(0);
+    {
+        let _write = lock.write();
+        assert!(lock.try_read_for(Duration::from_millis(10)).is_none());
+        assert!(lock.try_write_for(Duration::from_millis(10)).is_none());
+    }
+    {
+        let _read = lock.try_read_for(Duration::from_millis(10)).unwrap();
+        assert!(lock.try_read_for(Duration::from_millis(10)).is_some());
+        assert!(lock.try_write_for(Duration::from_millis(10)).is_none());
+    }
+    *lock.try_write_for(Duration::from_millis(10)).unwrap() += 1;
+    assert_eq!(*lock.read(), 1);
+}
+
+/// Set in the environment of the child processes started by `assert_aborts_in_child`.
+const ABORT_TEST_CHILD: &str = "RUSTC_DATA_STRUCTURES_ABORT_TEST_CHILD";
+
+/// Returns whether this process is a child started by `assert_aborts_in_child`, which should
+/// run the part of the test that aborts.
+fn in_abort_test_child() -> bool {
+    env::var_os(ABORT_TEST_CHILD).is_some()
+}
+
+/// Runs the test `name` of this module again in a child process, which takes the aborting
+/// path since `in_abort_test_child` returns true there, and checks that it aborted after
+/// printing `message` to stderr. Aborts can't be observed in the process of the tests itself.
+fn assert_aborts_in_child(name: &str, message: &str) {
+    let output = process::Command::new(env::current_exe().unwrap())
+        .args(&["--exact", &format!("sync::test::{}", name), "--nocapture", "--test-threads=1"])
+        .env(ABORT_TEST_CHILD, "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "the child process didn't abort: {}", stderr);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(output.status.signal(), Some(6), "{}", stderr);
+    }
+    assert!(stderr.contains(message), "{}", stderr);
+}
+
+#[test]
+fn test_abort_on_drop() {
+    if in_abort_test_child() {
+        let _result = panic::catch_unwind(|| {
+            let _bomb = AbortOnDrop("test_abort_on_drop");
+            panic!("interrupted");
+        });
+        return;
+    }
+    // Dropping the bomb without panicking does nothing.
+    drop(AbortOnDrop("test_abort_on_drop"));
+    assert_aborts_in_child("test_abort_on_drop",
+                           "fatal error: panicked in the critical section `test_abort_on_drop`");
+}
+
+#[test]
+fn test_with_lock_no_unwind() {
+    let lock = Lock::new(vec![1]);
+    if in_abort_test_child() {
+        let _result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            lock.with_lock_no_unwind(|v| {
+                v.push(3);
+                panic!("interrupted");
+            })
+        }));
+        return;
+    }
+    assert_eq!(lock.with_lock_no_unwind(|v| { v.push(2); v.len() }), 2);
+    assert_eq!(*lock.lock(), [1, 2]);
+    assert_aborts_in_child("test_with_lock_no_unwind",
+                           "panicked in the critical section `Lock::with_lock_no_unwind`");
+}
+
+#[test]
+fn test_work_queue_batches() {
+    let queue = WorkQueue::new();
+    assert!(queue.drain_batch(4).is_empty());
+    for i in 0..10 {
+        queue.push(i);
+    }
+    assert_eq!(queue.pop(), Some(0));
+    assert_eq!(queue.drain_batch(4), [1, 2, 3, 4]);
+    assert_eq!(queue.len(), 5);
+    assert_eq!(queue.drain_batch(100), [5, 6, 7, 8, 9]);
+    assert!(queue.is_empty());
+    assert_eq!(queue.pop(), None);
+}
+
+#[test]
+fn test_work_queue_concurrent_consumers() {
+    // Every item below 1000 produces the item 100 higher, so the items 0..1000
+    // must each be processed exactly once.
+    let queue = WorkQueue::new();
+    for i in 0..100 {
+        queue.push(i);
+    }
+    let processed = Lock::new(Vec::new());
+    let consume = || loop {
+        let batch = queue.drain_batch(8);
+        if batch.is_empty() {
+            break;
+        }
+        for item in batch {
+            if item + 100 < 1000 {
+                queue.push(item + 100);
+            }
+            processed.lock().push(item);
+        }
+    };
+    join(&consume, || join(&consume, &consume));
+
+    let mut processed = processed.into_inner();
+    processed.sort();
+    assert_eq!(processed, (0..1000).collect::<Vec<_>>());
+    assert!(queue.into_inner().is_empty());
+}
+
+#[test]
+fn test_hash_stable_ignores_lock_instance() {
+    use stable_hasher::{HashStable, StableHasher};
+
+    fn hash<T: HashStable<()>>(value: &T) -> u64 {
+        let mut hasher = StableHasher::new();
+        value.hash_stable(&mut (), &mut hasher);
+        hasher.finish()
+    }
+
+    let (a, b) = (Lock::new(vec![1u32, 2]), Lock::new(vec![1u32, 2]));
+    assert_eq!(hash(&a), hash(&b));
+    assert_eq!(hash(&a), hash(&vec![1u32, 2]));
+    assert_ne!(hash(&a), hash(&Lock::new(vec![2u32, 1])));
+
+    assert_eq!(hash(&RwLock::new(3u8)), hash(&RwLock::new(3u8)));
+    assert_eq!(hash(&MTLock::new(3u8)), hash(&3u8));
+
+    let once = Once::new();
+    assert_eq!(hash(&once), hash(&None::<u8>));
+    once.set(3u8);
+    assert_eq!(hash(&once), hash(&Some(3u8)));
+}
+
+#[test]
+fn test_atomic_orderings() {
+    let counter = AtomicU32::new(1);
+    assert_eq!(counter.load_relaxed(), 1);
+    counter.store_relaxed(2);
+    assert_eq!(counter.load_acquire(), 2);
+    counter.store_release(3);
+    assert_eq!(counter.load_seqcst(), 3);
+    counter.store_seqcst(4);
+    assert_eq!(counter.into_inner(), 4);
+
+    let flag = AtomicBool::new(false);
+    flag.store_release(true);
+    assert!(flag.load_acquire());
+}
+
+#[test]
+fn test_atomic_saturating() {
+    let counter = AtomicU32::new(u32::max_value() - 2);
+    assert_eq!(counter.fetch_add_saturating(1, Ordering::Relaxed), u32::max_value() - 2);
+    assert_eq!(counter.fetch_add_saturating(5, Ordering::AcqRel), u32::max_value() - 1);
+    assert_eq!(counter.fetch_add_saturating(1, Ordering::SeqCst), u32::max_value());
+    assert_eq!(counter.load_seqcst(), u32::max_value());
+
+    let counter = AtomicU64::new(3);
+    assert_eq!(counter.fetch_sub_saturating(2, Ordering::Release), 3);
+    assert_eq!(counter.fetch_sub_saturating(2, Ordering::Acquire), 1);
+    assert_eq!(counter.fetch_sub_saturating(1, Ordering::SeqCst), 0);
+    assert_eq!(counter.into_inner(), 0);
+
+    // Concurrent additions don't wrap either.
+    let counter = AtomicU32::new(0);
+    let add = || for _ in 0..1000 {
+        counter.fetch_add_saturating(u32::max_value() / 1000, Ordering::SeqCst);
+    };
+    join(add, add);
+    assert_eq!(counter.load_seqcst(), u32::max_value());
+}
+
+#[test]
+fn test_atomic_fetch_sub() {
+    let counter = super::AtomicUsize::new(2);
+    assert_eq!(counter.fetch_sub(1, Ordering::SeqCst), 2);
+    assert_eq!(counter.fetch_sub(1, Ordering::SeqCst), 1);
+    assert_eq!(counter.load(Ordering::SeqCst), 0);
+
+    // Decrementing below zero wraps around in both builds, like the atomics of `std`.
+    assert_eq!(counter.fetch_sub(1, Ordering::SeqCst), 0);
+    assert_eq!(counter.into_inner(), usize::max_value());
+    let counter = AtomicU32::new(1);
+    assert_eq!(counter.fetch_sub(3, Ordering::Relaxed), 1);
+    assert_eq!(counter.load_seqcst(), u32::max_value() - 1);
+    let counter = AtomicU64::new(0);
+    assert_eq!(counter.fetch_sub(u64::max_value(), Ordering::Relaxed), 0);
+    assert_eq!(counter.into_inner(), 1);
+
+    // Incrementing past the maximum wraps around as well.
+    let counter = super::AtomicUsize::new(usize::max_value());
+    assert_eq!(counter.fetch_add(1, Ordering::SeqCst), usize::max_value());
+    assert_eq!(counter.load(Ordering::SeqCst), 0);
+    let counter = AtomicU32::new(u32::max_value() - 1);
+    assert_eq!(counter.fetch_add(3, Ordering::Relaxed), u32::max_value() - 1);
+    assert_eq!(counter.load_seqcst(), 1);
+    let counter = AtomicU64::new(1);
+    assert_eq!(counter.fetch_add(u64::max_value(), Ordering::Relaxed), 1);
+    assert_eq!(counter.into_inner(), 0);
+}
+
+#[test]
+fn test_atomic_get_mut() {
+    let mut counter = AtomicU32::new(1);
+    *counter.get_mut() += 2;
+    assert_eq!(counter.load(Ordering::SeqCst), 3);
+    counter.store(5, Ordering::SeqCst);
+    assert_eq!(*counter.get_mut(), 5);
+    assert_eq!(counter.into_inner(), 5);
+}
+
+/// A `Copy` type which doesn't implement `PartialEq`.
+#[cfg(not(parallel_queries))]
+#[derive(Clone, Copy, Debug)]
+enum Phase {
+    Start,
+    Done,
+}
+
+#[cfg(not(parallel_queries))]
+#[test]
+fn test_atomic_without_eq() {
+    let mut phase = Atomic::new(Phase::Start);
+    match phase.swap(Phase::Done, Ordering::SeqCst) {
+        Phase::Start => {}
+        Phase::Done => panic!("expected `Phase::Start`"),
+    }
+    *phase.get_mut() = Phase::Start;
+    phase.store(Phase::Done, Ordering::SeqCst);
+    match (phase.load(Ordering::SeqCst), phase.into_inner()) {
+        (Phase::Done, Phase::Done) => {}
+        other => panic!("unexpected phases {:?}", other),
+    }
+}
+
+/// Runs the same sequence of bitwise operations on `$atomic`, checking the previous values,
+/// so both builds are checked against the same expectations.
+macro_rules! check_atomic_bits {
+    ($atomic:ty, $zero:expr, $a:expr, $b:expr) => {{
+        let (zero, a, b) = ($zero, $a, $b);
+        let atomic = <$atomic>::new(zero);
+        assert_eq!(atomic.fetch_or(a, Ordering::SeqCst), zero);
+        assert_eq!(atomic.fetch_or(b, Ordering::Relaxed), a);
+        assert_eq!(atomic.fetch_and(b, Ordering::AcqRel), a | b);
+        assert_eq!(atomic.fetch_xor(a, Ordering::Release), (a | b) & b);
+        assert_eq!(atomic.fetch_nand(a, Ordering::Acquire), ((a | b) & b) ^ a);
+        assert_eq!(atomic.load(Ordering::SeqCst), !((((a | b) & b) ^ a) & a));
+    }}
+}
+
+#[test]
+fn test_atomic_bitwise() {
+    check_atomic_bits!(super::AtomicUsize, 0, 0b1100, 0b1010);
+    check_atomic_bits!(AtomicU32, 0, 0xf0f0_0000, 0x0ff0_0001);
+    check_atomic_bits!(AtomicU64, 0, 1 << 63, 0xffff_ffff);
+    check_atomic_bits!(AtomicBool, false, true, false);
+    check_atomic_bits!(AtomicBool, false, false, true);
+
+    // A set of flags.
+    let flags = AtomicU64::new(0);
+    for &bit in &[3, 17, 42] {
+        assert_eq!(flags.fetch_or(1u64 << bit, Ordering::AcqRel) & (1 << bit), 0);
+    }
+    assert_eq!(flags.fetch_and(!(1 << 17), Ordering::AcqRel), (1 << 3) | (1 << 17) | (1 << 42));
+    assert_eq!(flags.into_inner(), (1 << 3) | (1 << 42));
+}
+
+#[test]
+fn test_atomic_max_min() {
+    let depth = AtomicU64::new(3);
+    assert_eq!(depth.fetch_max(5, Ordering::Relaxed), 3);
+    assert_eq!(depth.fetch_max(4, Ordering::Relaxed), 5);
+    assert_eq!(depth.fetch_min(2, Ordering::Relaxed), 5);
+    assert_eq!(depth.fetch_min(7, Ordering::Relaxed), 2);
+    assert_eq!(depth.into_inner(), 2);
+
+    // Only raising the mark counts as a change.
+    let mark = super::AtomicUsize::new(0);
+    assert!(mark.update_max(4, Ordering::Relaxed));
+    assert!(!mark.update_max(4, Ordering::Relaxed));
+    assert!(!mark.update_max(1, Ordering::Relaxed));
+    assert!(mark.update_max(9, Ordering::Relaxed));
+    assert!(mark.update_min(3, Ordering::Relaxed));
+    assert!(!mark.update_min(3, Ordering::Relaxed));
+    assert_eq!(mark.load(Ordering::Relaxed), 3);
+
+    // Concurrent updates keep the maximum, and exactly one of the updates to it changes it.
+    let mark = AtomicU32::new(0);
+    let changes = AtomicUsize::new(0);
+    let update = |offset: u32| for i in 0..1000 {
+        if mark.update_max(i * 2 + offset, Ordering::SeqCst) && i * 2 + offset == 1999 {
+            changes.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+    join(|| update(0), || update(1));
+    assert_eq!(mark.load_seqcst(), 1999);
+    assert_eq!(changes.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+#[allow(unstable_name_collisions)]
+fn test_atomic_fetch_update() {
+    let counter = AtomicU64::new(7);
+    let double_below_100 = |v: u64| if v < 100 { Some(v * 2) } else { None };
+    assert_eq!(counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, double_below_100),
+               Ok(7));
+    assert_eq!(counter.load_seqcst(), 14);
+    // Returning `None` aborts the update.
+    counter.store(100, Ordering::SeqCst);
+    assert_eq!(counter.fetch_update(Ordering::AcqRel, Ordering::Acquire, double_below_100),
+               Err(100));
+    assert_eq!(counter.into_inner(), 100);
+
+    let flag = AtomicBool::new(false);
+    assert_eq!(flag.fetch_update(Ordering::SeqCst, Ordering::Relaxed, |v| Some(!v)), Ok(false));
+    assert_eq!(flag.fetch_update(Ordering::SeqCst, Ordering::Relaxed, |_| None), Err(true));
+
+    // Racing updates are retried, so none of them is lost.
+    let counter = AtomicU32::new(0);
+    let increments = AtomicUsize::new(0);
+    let increment = || for _ in 0..1000 {
+        let result = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+            if v < 1500 { Some(v + 1) } else { None }
+        });
+        if result.is_ok() {
+            increments.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+    join(increment, increment);
+    assert_eq!(counter.load_seqcst(), 1500);
+    assert_eq!(increments.load(Ordering::SeqCst), 1500);
+}
+
+#[test]
+fn test_atomic_release_acquire_publishes() {
+    let data = AtomicU64::new(0);
+    let ready = AtomicBool::new(false);
+    join(|| {
+        data.store_relaxed(42);
+        ready.store_release(true);
+    }, || {
+        if ready.load_acquire() {
+            assert_eq!(data.load_relaxed(), 42);
+        }
+    });
+    assert!(ready.load_seqcst());
+    assert_eq!(data.load_seqcst(), 42);
+}
+
+#[test]
+fn test_send_sync_box_erases_payload() {
+    use std::any::Any;
+
+    assert_erased_send_sync(&vec![1u8]);
+    let payload: SendSyncBox<dyn Any> = SendSyncBox::new(vec![1u32, 2]);
+    let (len, sum) = join(|| payload.downcast_ref::<Vec<u32>>().unwrap().len(),
+                          || payload.downcast_ref::<Vec<u32>>().unwrap().iter().sum::<u32>());
+    assert_eq!((len, sum), (2, 3));
+    let mut payload = payload.into_box().downcast::<Vec<u32>>().unwrap();
+    payload.push(3);
+    assert_eq!(*payload, [1, 2, 3]);
+
+    let mut boxed: SendSyncBox<[u32]> = SendSyncBox::from_box(vec![4, 5].into_boxed_slice());
+    boxed[0] = 6;
+    assert_eq!(&*boxed, &[6, 5][..]);
+    assert_eq!(format!("{:?}", boxed), "[6, 5]");
+}
+
+#[test]
+fn test_lock_from_iterator() {
+    let lock: Lock<Vec<u32>> = (0..3).collect();
+    assert_eq!(lock.into_inner(), [0, 1, 2]);
+    let lock: RwLock<String> = vec!["a", "b"].into_iter().collect();
+    assert_eq!(*lock.read(), "ab");
+}
+
+#[test]
+fn test_lock_extend_locks_once() {
+    let items = Lock::new(vec![0]);
+    // The lock isn't held while the iterator runs.
+    (&items).extend((1..4).map(|i| {
+        assert!(items.try_lock().is_some());
+        i
+    }));
+    #[cfg(feature = "lock-metrics")]
+    let acquisitions = items.acquisitions();
+    (&items).extend(vec![4, 5]);
+    #[cfg(feature = "lock-metrics")]
+    assert_eq!(items.acquisitions(), acquisitions + 1);
+    assert_eq!(items.into_inner(), [0, 1, 2, 3, 4, 5]);
+
+    let map = Lock::new(HashMap::new());
+    (&map).extend(vec![(1, "a"), (2, "b")]);
+    (&map).extend(vec![(1, "c")]);
+    #[cfg(feature = "lock-metrics")]
+    assert_eq!(map.acquisitions(), 2);
+    let map = map.into_inner();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map[&1], "c");
+    assert_eq!(map[&2], "b");
+}
+
+#[test]
+fn test_rw_lock_read_many() {
+    let (a, b, c) = (RwLock::new(1), RwLock::new(2), RwLock::new(3));
+    {
+        let guards = RwLock::read_many(&[&a, &b, &c]);
+        assert_eq!(guards.iter().map(|guard| **guard).collect::<Vec<_>>(), [1, 2, 3]);
+        // The locks are held until the guards are dropped.
+        assert!(b.try_write().is_err());
+        assert_eq!(*c.read(), 3);
+    }
+    *b.write() = 4;
+    let guards = RwLock::read_many(&[&c, &b]);
+    assert_eq!((*guards[0], *guards[1]), (3, 4));
+    assert!(RwLock::<u32>::read_many(&[]).is_empty());
+}
+
+#[test]
+fn test_maybe_send_bounds() {
+    fn both<A, B, R>(a: A, b: B) -> (R, R)
+        where A: FnOnce() -> R + MaybeSend,
+              B: FnOnce() -> R + MaybeSend,
+              R: MaybeSend
+    {
+        join(a, b)
+    }
+
+    // A bound requiring the real `Send` of `std` next to the configuration dependent one.
+    fn on_thread<F, R>(f: F) -> R
+        where F: FnOnce() -> R + std::marker::Send + MaybeSync + 'static,
+              R: std::marker::Send + 'static
+    {
+        thread::spawn(f).join().unwrap()
+    }
+
+    assert_eq!(both(|| 1, || 2), (1, 2));
+    assert_eq!(on_thread(|| 3), 3);
+
+    // Without parallel_queries every type is `MaybeSend`.
+    #[cfg(not(parallel_queries))]
+    {
+        let shared = Lrc::new(4);
+        let (a, b) = both(|| *shared, || Lrc::strong_count(&shared));
+        assert_eq!((a, b), (4, 1));
+    }
+}
+
+static STATIC_ONCE: StaticOnce<u32> = StaticOnce::new();
+
+#[test]
+fn test_static_once() {
+    assert_eq!(STATIC_ONCE.try_get(), None);
+    let (a, b) = join(|| STATIC_ONCE.init_nonlocking_same(|| 1),
+                      || STATIC_ONCE.init_nonlocking_same(|| 1));
+    // One of the closures set the value, the other one returned its value or was not called.
+    assert!(a.is_none() || b.is_none());
+    assert_eq!(STATIC_ONCE.get(), &1);
+    assert_eq!(STATIC_ONCE.try_set_same(1), Some(1));
+    assert_eq!(STATIC_ONCE.try_set(2), Some(2));
+    assert_eq!(STATIC_ONCE.init_nonlocking(|| panic!("already initialized")), None);
+    assert_eq!(STATIC_ONCE.get_or_init(|| panic!("already initialized")), &1);
+    let msg = panic_message(|| { STATIC_ONCE.try_set_same(3); });
+    assert_eq!(msg, "StaticOnce already initialized with a different value");
+}
+
+#[test]
+fn test_static_once_get_or_init() {
+    static LAZY: StaticOnce<Vec<u32>> = StaticOnce::new();
+    assert_eq!(LAZY.get_or_init(|| vec![1, 2]), &[1, 2]);
+    assert_eq!(LAZY.init_nonlocking(|| vec![]), None);
+    let msg = panic_message(|| LAZY.set(vec![]));
+    assert_eq!(msg, "StaticOnce already initialized");
+}
+
+#[test]
+fn test_lock_default_with() {
+    struct NoDefault(u32);
+
+    let lock = Lock::default_with(|| NoDefault(1));
+    assert_eq!(lock.lock().0, 1);
+    let lock = RwLock::default_with(|| vec![NoDefault(2)]);
+    assert_eq!(lock.read()[0].0, 2);
+    assert_eq!(*Lock::<u32>::default().lock(), 0);
+}
+
+#[test]
+fn test_scope_waits_for_nested_spawns() {
+    let count = AtomicUsize::new(0);
+    scope(|s| {
+        for _ in 0..4 {
+            let count = &count;
+            s.spawn(move |s| {
+                count.fetch_add(1, Ordering::SeqCst);
+                s.spawn(move |_| {
+                    count.fetch_add(10, Ordering::SeqCst);
+                });
+            });
+        }
+    });
+    assert_eq!(count.load(Ordering::SeqCst), 44);
+}
+
+#[test]
+fn test_scope_runs_all_spawns_before_panicking() {
+    let count = AtomicUsize::new(0);
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        scope(|s| {
+            let count = &count;
+            s.spawn(|_| panic!("first spawn"));
+            s.spawn(move |_| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+            panic!("scope body");
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(not(parallel_queries))]
+#[test]
+fn test_serial_scope_order() {
+    let order = Lock::new(Vec::new());
+    let order = &order;
+    serial_scope(|s| {
+        s.spawn(move |s| {
+            s.spawn(move |_| order.lock().push("nested"));
+            order.lock().push("first");
+        });
+        s.spawn(move |_| order.lock().push("second"));
+        order.lock().push("body");
+    });
+    assert_eq!(*order.lock(), ["body", "first", "second", "nested"]);
+}
+
+/// A sink recording the items of every `extend` call separately.
+#[derive(Copy, Clone)]
+struct Batches<'a>(&'a Lock<Vec<Vec<u32>>>);
+
+impl<'a> Extend<u32> for Batches<'a> {
+    fn extend<I: IntoIterator<Item = u32>>(&mut self, iter: I) {
+        let batch = iter.into_iter().collect();
+        self.0.lock().push(batch);
+    }
+}
+
+#[test]
+fn test_buffered_worker_local() {
+    let run = || {
+        let batches = Lock::new(Vec::new());
+        let buffered = BufferedWorkerLocal::new(10, Batches(&batches));
+        par_iter((0..1005).collect::<Vec<u32>>()).for_each(|i| buffered.push(i));
+        // The buffers are only moved to the sink when they are full.
+        assert!(batches.lock().iter().all(|batch| batch.len() == 10));
+        if !cfg!(parallel_queries) {
+            assert_eq!(batches.lock().len(), 100);
+        }
+        buffered.flush_all();
+        let mut items: Vec<u32> = batches.into_inner().into_iter().flat_map(|b| b).collect();
+        items.sort();
+        assert_eq!(items, (0..1005).collect::<Vec<u32>>());
+    };
+
+    in_pool(if cfg!(feature = "lock-error-checking") { 1 } else { 4 }, run);
+}
+
+#[test]
+fn test_in_place_scope_borrows_non_send_locals() {
+    let run = || {
+        // The body uses an `Rc`, while the spawned closures only use thread safe values.
+        let names = ::std::rc::Rc::new(vec!["a", "b", "c"]);
+        let token = CancellationToken::new();
+        let sum = AtomicUsize::new(0);
+        let count = in_place_scope(|s| {
+            for i in 0..names.len() {
+                let (sum, token) = (&sum, &token);
+                s.spawn(move |_| {
+                    if token.check().is_ok() {
+                        sum.fetch_add(i, Ordering::SeqCst);
+                    }
+                });
+            }
+            names.len()
+        });
+        assert_eq!(count, 3);
+        assert_eq!(sum.load(Ordering::SeqCst), 3);
+        assert_eq!(::std::rc::Rc::strong_count(&names), 1);
+    };
+
+    in_pool(4, run);
+}
+
+#[test]
+fn test_scope_fifo_order() {
+    let run = || {
+        let started = Lock::new(Vec::new());
+        scope_fifo(|s| {
+            for i in 0..10 {
+                let started = &started;
+                s.spawn(move |s| {
+                    started.lock().push(i);
+                    if i == 0 {
+                        // This starts after the closures which were spawned before.
+                        s.spawn(move |_| started.lock().push(10));
+                    }
+                });
+            }
+        });
+        assert_eq!(started.into_inner(), (0..11).collect::<Vec<_>>());
+    };
+
+    // With a single thread the closures start and finish in the same order, so the order of
+    // their side effects shows the order they were started in.
+    in_pool(1, run);
+}
+
+#[cfg(all(parallel_queries, not(feature = "lock-error-checking")))]
+#[test]
+fn test_scope_fifo_in_parallel() {
+    let order = Lock::new(Vec::new());
+    in_pool(4, || {
+        scope_fifo(|s| {
+            for i in 0..100 {
+                let order = &order;
+                s.spawn(move |s| {
+                    order.lock().push(i);
+                    s.spawn(move |_| order.lock().push(i + 100));
+                });
+            }
+        });
+    });
+    let mut order = order.into_inner();
+    order.sort();
+    assert_eq!(order, (0..200).collect::<Vec<_>>());
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_in_place_scope_outside_of_pool() {
+    // The body runs on this thread, which isn't a worker, so it can still use an `Rc`.
+    let names = ::std::rc::Rc::new(vec!["a", "b", "c"]);
+    let sum = AtomicUsize::new(0);
+    let count = in_place_scope(|s| {
+        assert_eq!(::rayon_core::current_thread_index(), None);
+        for i in 0..names.len() {
+            let sum = &sum;
+            s.spawn(move |_| {
+                assert!(::rayon_core::current_thread_index().is_some());
+                sum.fetch_add(i, Ordering::SeqCst);
+            });
+        }
+        // The spawned closures only start after the body returned.
+        assert_eq!(sum.load(Ordering::SeqCst), 0);
+        names.len()
+    });
+    assert_eq!(count, 3);
+    assert_eq!(sum.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_thread_local_once_caches_value() {
+    let once = ThreadLocalOnce::new();
+    let inits = AtomicUsize::new(0);
+    let init = || {
+        inits.fetch_add(1, Ordering::SeqCst);
+        vec![1]
+    };
+    assert_eq!(once.get(), None);
+    assert_eq!(once.get_or_init(&init), &[1]);
+    assert_eq!(once.get_or_init(&init), &[1]);
+    assert_eq!(inits.load(Ordering::SeqCst), 1);
+    assert_eq!(once.into_inner(), Some(vec![1]));
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_thread_local_once_other_thread_panics() {
+    let once = Lrc::new(ThreadLocalOnce::new());
+    assert_eq!(*once.get_or_init(|| 1), 1);
+    let other = once.clone();
+    let result = thread::spawn(move || *other.get_or_init(|| 2)).join();
+    assert!(result.is_err());
+    assert_eq!(once.get(), Some(&1));
+}
+
+#[test]
+fn test_lock_debug() {
+    let lock = Lock::new(vec![1]);
+    assert_eq!(format!("{:?}", lock), "Lock { data: [1] }");
+    let _guard = lock.lock();
+    assert_eq!(format!("{:?}", lock), "Lock { data: <locked> }");
+}
+
+#[test]
+fn test_once_debug() {
+    let once = Once::new();
+    assert_eq!(format!("{:?}", once), "Once { data: <uninitialized> }");
+    once.init_locking(|| {
+        // The value lock is held while the value is computed.
+        assert_eq!(format!("{:?}", once), "Once { data: <locked> }");
+        vec![1]
+    });
+    assert_eq!(format!("{:?}", once), "Once { data: [1] }");
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_debug_of_lock_held_by_other_thread() {
+    let lock = Lrc::new(Lock::new(1));
+    let guard = lock.lock();
+    let printed = {
+        let lock = lock.clone();
+        thread::spawn(move || format!("{:?}", lock)).join().unwrap()
+    };
+    assert_eq!(printed, "Lock { data: <locked> }");
+    drop(guard);
+}
+
+#[test]
+fn test_rw_lock_debug() {
+    let lock = RwLock::new(vec![1]);
+    assert_eq!(format!("{:?}", lock), "RwLock { data: [1] }");
+    {
+        let _guard = lock.read();
+        assert_eq!(format!("{:?}", lock), "RwLock { data: [1] }");
+    }
+    let _guard = lock.write();
+    assert_eq!(format!("{:?}", lock), "RwLock { data: <locked> }");
+}
+
+#[cfg(feature = "lock-metrics")]
+#[test]
+fn test_named_lock_debug() {
+    let lock = Lock::named("debug_lock", 1);
+    assert_eq!(format!("{:?}", lock), "Lock { name: \"debug_lock\", data: 1 }");
+    let lock = RwLock::named("debug_rw_lock", 1);
+    let _guard = lock.write();
+    assert_eq!(format!("{:?}", lock), "RwLock { name: \"debug_rw_lock\", data: <locked> }");
+}
+
+fn hash_of<T: ::std::hash::Hash>(value: &T) -> u64 {
+    use std::hash::Hasher;
+    let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn test_lock_eq_hash() {
+    let a = Lock::new(vec![1]);
+    assert!(a == Lock::new(vec![1]));
+    assert!(a != Lock::new(vec![2]));
+    // Comparing a lock with itself only locks it once.
+    assert!(a == a);
+    assert!(Lock::new(::std::f64::NAN) != Lock::new(::std::f64::NAN));
+    let nan = Lock::new(::std::f64::NAN);
+    assert!(nan != nan);
+    assert_eq!(hash_of(&a), hash_of(&vec![1]));
+}
+
+#[test]
+fn test_rw_lock_eq_hash() {
+    let a = RwLock::new(vec![1]);
+    assert!(a == RwLock::new(vec![1]));
+    assert!(a != RwLock::new(vec![2]));
+    assert!(a == a);
+    {
+        let _guard = a.read();
+        assert!(a == a);
+    }
+    assert_eq!(hash_of(&a), hash_of(&vec![1]));
+}
+
+#[test]
+fn test_mt_lock_eq_hash() {
+    let a = MTLock::new(vec![1]);
+    assert!(a == MTLock::new(vec![1]));
+    assert!(a != MTLock::new(vec![2]));
+    assert!(a == a);
+    assert_eq!(hash_of(&a), hash_of(&MTLock::new(vec![1])));
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_lock_eq_opposite_orders() {
+    let workers = if cfg!(feature = "lock-error-checking") { 1 } else { 4 };
+    let a = Lock::new(1);
+    let b = Lock::new(1);
+    in_pool(workers, || {
+        ::rayon::scope(|s| {
+            for i in 0..100 {
+                let (a, b) = (&a, &b);
+                s.spawn(move |_| {
+                    if i % 2 == 0 {
+                        assert!(a == b);
+                    } else {
+                        assert!(b == a);
+                    }
+                });
+            }
+        })
+    });
+}
+
+#[test]
+fn test_lock_unlocked() {
+    let lock = Lock::new(vec![1]);
+    let mut guard = lock.lock();
+    let len = lock.unlocked(&mut guard, || {
+        let mut other = lock.try_lock().expect("the lock should be released");
+        other.push(2);
+        other.len()
+    });
+    assert_eq!(len, 2);
+    guard.push(3);
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert_eq!(lock.into_inner(), [1, 2, 3]);
+}
+
+#[test]
+fn test_rw_lock_unlocked() {
+    let lock = RwLock::new(1);
+    let mut guard = lock.write();
+    lock.unlocked(&mut guard, || *lock.try_write().unwrap() += 1);
+    *guard *= 10;
+    assert!(lock.try_read_raw().is_none());
+    drop(guard);
+    assert_eq!(lock.into_inner(), 20);
+}
+
+#[test]
+fn test_rw_lock_read_then_write() {
+    let lock = RwLock::new(vec![1, 2]);
+    let insert = |value: u32| lock.read_then_write(
+        |v| v.contains(&value),
+        |&present| !present,
+        |v, _| {
+            // The locks are free in between, so check again.
+            if !v.contains(&value) {
+                v.push(value);
+            }
+            v.len()
+        },
+    );
+
+    // The write phase is skipped for present values.
+    assert_eq!(insert(2), None);
+    assert_eq!(insert(3), Some(3));
+    assert_eq!(lock.into_inner(), [1, 2, 3]);
+
+    // Neither lock is held while `decide` runs.
+    let lock = RwLock::new(0);
+    let written = lock.read_then_write(|&v| v, |_| lock.try_write().is_ok(), |v, old| {
+        *v = old + 1;
+        *v
+    });
+    assert_eq!(written, Some(1));
+}
+
+#[test]
+fn test_filter_map_guards() {
+    #[derive(Debug, PartialEq)]
+    enum Entry {
+        Pending,
+        Done(Vec<u32>),
+    }
+
+    // The guards don't implement `Debug` with cfg!(parallel_queries), so the results are
+    // unwrapped with `ok` and `err`.
+    let lock = Lock::new(Entry::Done(vec![1]));
+    {
+        let mut done = Lock::filter_map(lock.lock(), |entry| match *entry {
+            Entry::Done(ref mut values) => Some(values),
+            Entry::Pending => None,
+        }).ok().unwrap();
+        done.push(2);
+    }
+    assert_eq!(*lock.lock(), Entry::Done(vec![1, 2]));
+    *lock.lock() = Entry::Pending;
+    // The original guard is returned and still holds the lock.
+    let guard = Lock::filter_map(lock.lock(), |_| None::<&mut u32>).err().unwrap();
+    assert_eq!(*guard, Entry::Pending);
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+
+    let lock = RwLock::new(Entry::Done(vec![1]));
+    {
+        let mut done = RwLock::filter_map_write(lock.write(), |entry| match *entry {
+            Entry::Done(ref mut values) => Some(values),
+            Entry::Pending => None,
+        }).ok().unwrap();
+        done.push(2);
+    }
+    {
+        let done = RwLock::filter_map_read(lock.read(), |entry| match *entry {
+            Entry::Done(ref values) => Some(&values[1..]),
+            Entry::Pending => None,
+        }).ok().unwrap();
+        assert_eq!(*done, [2]);
+        assert!(lock.try_write().is_err());
+    }
+    *lock.write() = Entry::Pending;
+    let guard = RwLock::filter_map_read(lock.read(), |_| None::<&u32>).err().unwrap();
+    assert_eq!(*guard, Entry::Pending);
+    assert!(lock.try_write().is_err());
+    drop(guard);
+    let guard = RwLock::filter_map_write(lock.write(), |_| None::<&mut u32>).err().unwrap();
+    assert_eq!(*guard, Entry::Pending);
+    assert!(lock.try_write().is_err());
+}
+
+#[test]
+fn test_unlocked_reacquires_on_panic() {
+    let lock = Lock::new(0);
+    let mut guard = lock.lock();
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        lock.unlocked(&mut guard, || panic!("waiting failed"))
+    }));
+    assert!(result.is_err());
+    *guard += 1;
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn test_unlocked_other_lock() {
+    let (a, b) = (Lock::new(0), Lock::new(0));
+    let mut guard = a.lock();
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        b.unlocked(&mut guard, || unreachable!())
+    }));
+    let err = result.err().unwrap();
+    assert_eq!(err.downcast_ref::<&str>(),
+               Some(&"the guard passed to `unlocked` is of another lock"));
+    // Nothing was released.
+    assert!(a.try_lock().is_none());
+    *guard += 1;
+    drop(guard);
+    assert_eq!(*a.lock(), 1);
+
+    let (a, b) = (RwLock::new(0), RwLock::new(0));
+    let mut guard = a.write();
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        b.unlocked(&mut guard, || unreachable!())
+    }));
+    assert!(result.is_err());
+    assert!(a.try_read().is_err());
+}
+
+/// Acquiring the lock again fails since `f` leaks a guard of it, so `unlocked` must abort
+/// instead of leaving `guard` as a guard which was already dropped.
+#[cfg(not(parallel_queries))]
+#[test]
+fn test_unlocked_reacquire_fails() {
+    if in_abort_test_child() {
+        let lock = Lock::new(0);
+        let mut stash = None;
+        let mut guard = lock.lock();
+        let _result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            lock.unlocked(&mut guard, || stash = Some(lock.lock()))
+        }));
+        return;
+    }
+    assert_aborts_in_child("test_unlocked_reacquire_fails",
+                           "critical section `acquiring a lock again after `unlocked``");
+}
+
+/// Acquiring the lock again blocks until the other thread releases it, also with error
+/// checking.
+#[cfg(parallel_queries)]
+#[test]
+fn test_unlocked_reacquire_contended() {
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    let lock = Lrc::new(Lock::new(0));
+    let held = Lrc::new(Barrier::new(2));
+    let mut guard = lock.lock();
+    let other = lock.unlocked(&mut guard, || {
+        let (other_lock, other_held) = (lock.clone(), held.clone());
+        let other = thread::spawn(move || {
+            let mut guard = other_lock.lock();
+            other_held.wait();
+            thread::sleep(Duration::from_millis(50));
+            *guard += 1;
+        });
+        held.wait();
+        other
+    });
+    // The other thread released the lock before it was acquired again.
+    assert_eq!(*guard, 1);
+    drop(guard);
+    other.join().unwrap();
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_unlocked_other_thread() {
+    let lock = Lrc::new(Lock::new(0));
+    let mut guard = lock.lock();
+    lock.unlocked(&mut guard, || {
+        let lock = lock.clone();
+        thread::spawn(move || *lock.lock() += 1).join().unwrap();
+    });
+    assert_eq!(*guard, 1);
+    *guard += 1;
+    drop(guard);
+
+    let rw_lock = Lrc::new(RwLock::new(0));
+    let mut guard = rw_lock.write();
+    rw_lock.unlocked(&mut guard, || {
+        let rw_lock = rw_lock.clone();
+        thread::spawn(move || *rw_lock.write() += 1).join().unwrap();
+    });
+    assert_eq!(*guard, 1);
+}
+
+#[test]
+fn test_parallel_runs_all_branches() {
+    let ran = Lock::new(Vec::new());
+    let run = |i: usize| ran.lock().push(i);
+    parallel! { run(0); }
+    parallel! { run(1); run(2); run(3); }
+    parallel! { run(4); run(5); run(6); run(7); run(8); run(9); run(10); run(11); }
+    let mut ran = ran.into_inner();
+    ran.sort();
+    assert_eq!(ran, (0..12).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_parallel_propagates_panics() {
+    let ran = AtomicUsize::new(0);
+    let fail = || -> () { panic!("branch failed") };
+    let message = panic_message(|| {
+        parallel! {
+            ran.fetch_add(1, Ordering::SeqCst);
+            fail();
+            ran.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    assert_eq!(message, "branch failed");
+    // With parallel queries the branch after the panicking one runs too.
+    let expected = if cfg!(parallel_queries) { 2 } else { 1 };
+    assert_eq!(ran.load(Ordering::SeqCst), expected);
+}
+
+#[test]
+fn test_upgrade_or_init() {
+    let inits = AtomicUsize::new(0);
+    let init = || Lrc::new(inits.fetch_add(1, Ordering::SeqCst));
+    let mut weak = Weak::new();
+    let first = upgrade_or_init(&mut weak, &init);
+    assert_eq!(*first, 0);
+    // The value is alive, so it is reused.
+    let again = upgrade_or_init(&mut weak, &init);
+    assert!(Lrc::ptr_eq(&first, &again));
+
+    // Dropping all the strong references makes the next lookup create a new value.
+    drop((first, again));
+    assert!(weak.upgrade().is_none());
+    let second = upgrade_or_init(&mut weak, &init);
+    assert_eq!(*second, 1);
+    assert!(Lrc::ptr_eq(&second, &weak.upgrade().unwrap()));
+    assert_eq!(inits.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_lrc_str() {
+    let a = lrc_str("interned");
+    let b = lrc_str("interned");
+    assert_eq!(&*a, "interned");
+    // The strings are equal, but they need not share an allocation.
+    assert_eq!(a, b);
+    assert_eq!(lrc_string("interned".to_string()), a);
+    assert_eq!(&*lrc_string(String::new()), "");
+}
+
+#[test]
+fn test_try_unwrap_lock() {
+    let lock = Lrc::new(Lock::new(vec![1, 2]));
+    let other = lock.clone();
+    let lock = try_unwrap_lock(lock).err().unwrap();
+    lock.lock().push(3);
+    drop(other);
+    assert_eq!(try_unwrap_lock(lock).ok().unwrap(), [1, 2, 3]);
+
+    let lock = Lrc::new(RwLock::new(1));
+    let weak = Lrc::downgrade(&lock);
+    // Weak references don't keep the value alive.
+    assert_eq!(try_unwrap_rwlock(lock).ok().unwrap(), 1);
+    assert!(weak.upgrade().is_none());
+    let lock = Lrc::new(RwLock::new(2));
+    let other = lock.clone();
+    assert_eq!(*try_unwrap_rwlock(lock).err().unwrap().read(), 2);
+    assert_eq!(try_unwrap_rwlock(other).ok().unwrap(), 2);
+}
+
+#[test]
+fn test_try_lock_detailed_current_thread() {
+    let location = sync_location!();
+    let expected = if cfg!(feature = "lock-error-checking") {
+        TryLockError::AlreadyBorrowedByCurrentThread { location: Some(location) }
+    } else if cfg!(parallel_queries) {
+        TryLockError::WouldBlock
+    } else {
+        TryLockError::AlreadyBorrowedByCurrentThread { location: None }
+    };
+
+    let lock = Lock::new(1);
+    assert!(lock.try_lock_detailed().is_ok());
+    let _guard = lock.lock_at(location);
+    assert_eq!(lock.try_lock_detailed().err(), Some(expected));
+
+    let rw_lock = RwLock::new(2);
+    {
+        let _guard = rw_lock.write_at(location);
+        assert_eq!(rw_lock.try_read_detailed().err(), Some(expected));
+        assert_eq!(rw_lock.try_write_detailed().err(), Some(expected));
+    }
+    let _guard = rw_lock.read_at(location);
+    assert_eq!(rw_lock.try_write_detailed().err(), Some(expected));
+    assert_eq!(*rw_lock.try_read_detailed().unwrap(), 2);
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_try_lock_detailed_other_thread() {
+    use std::sync::mpsc;
+
+    let lock = Lrc::new(Lock::new(1));
+    let rw_lock = Lrc::new(RwLock::new(2));
+    let (held_tx, held_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+    let (other_lock, other_rw_lock) = (lock.clone(), rw_lock.clone());
+    let holder = thread::spawn(move || {
+        let _guard = other_lock.lock();
+        let _write_guard = other_rw_lock.write();
+        held_tx.send(()).unwrap();
+        done_rx.recv().unwrap();
+    });
+    held_rx.recv().unwrap();
+    assert_eq!(lock.try_lock_detailed().err(), Some(TryLockError::WouldBlock));
+    assert_eq!(rw_lock.try_read_detailed().err(), Some(TryLockError::WouldBlock));
+    assert_eq!(rw_lock.try_write_detailed().err(), Some(TryLockError::WouldBlock));
+    done_tx.send(()).unwrap();
+    holder.join().unwrap();
+}
+
+#[test]
+fn test_init_racy() {
+    let once = Once::new();
+    assert_eq!(once.init_racy(|| 1), RaceOutcome::Won(&1));
+    assert_eq!(once.init_racy(|| panic!("initialized")), RaceOutcome::Initialized(&1));
+
+    // Another closure initializes the value while ours runs.
+    let once = Once::new();
+    let outcome = once.init_racy(|| {
+        once.set(1);
+        2
+    });
+    assert_eq!(outcome, RaceOutcome::Lost { winner: &1, yours: 2 });
+    assert_eq!(*outcome.value(), 1);
+}
+
+#[test]
+fn test_once_generation() {
+    let mut once = Once::new();
+    assert_eq!(once.get_with_generation(), None);
+    once.set(1);
+    assert_eq!(once.get_with_generation(), Some((&1, 0)));
+    // Replacing a value with an equal one is still visible.
+    assert_eq!(once.replace(1), Some(1));
+    assert_eq!(once.get_with_generation(), Some((&1, 1)));
+    assert_eq!(once.take(), Some(1));
+    assert_eq!(once.get_with_generation(), None);
+    once.set(1);
+    assert_eq!(once.get_with_generation(), Some((&1, 2)));
+    assert_eq!(once.take(), Some(1));
+    assert_eq!(once.replace(3), None);
+    assert_eq!(once.get_with_generation(), Some((&3, 4)));
+}
+
+#[test]
+fn test_once_get_or_init_cyclic() {
+    struct Node {
+        name: &'static str,
+        this: Weak<Once<Node>>,
+    }
+
+    let cell = Lrc::new(Once::new());
+    let node = Once::get_or_init_cyclic(&cell, |this| {
+        // The value isn't set while it is computed.
+        assert!(this.upgrade().unwrap().try_get().is_none());
+        Node { name: "root", this: this.clone() }
+    });
+    assert_eq!(node.name, "root");
+    let this = node.this.upgrade().unwrap();
+    assert!(Lrc::ptr_eq(&this, &cell));
+    assert_eq!(this.get().name, "root");
+    assert_eq!(Once::get_or_init_cyclic(&cell, |_| panic!("already initialized")).name, "root");
+
+    // The back reference doesn't keep the node alive.
+    let weak = Lrc::downgrade(&cell);
+    drop((this, cell));
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_once_drop_now() {
+    struct Handle<'a>(&'static str, &'a RefCell<Vec<&'static str>>);
+
+    impl<'a> Drop for Handle<'a> {
+        fn drop(&mut self) {
+            self.1.borrow_mut().push(self.0);
+        }
+    }
+
+    let log = RefCell::new(Vec::new());
+    let mut first = Once::new();
+    let second = Once::new();
+    first.set(Handle("first", &log));
+    second.set(Handle("second", &log));
+    assert!(first.drop_now());
+    assert_eq!(*log.borrow(), ["first"]);
+    log.borrow_mut().push("teardown");
+    assert!(!first.drop_now());
+    assert!(first.try_get().is_none());
+    drop(second);
+    assert_eq!(*log.borrow(), ["first", "teardown", "second"]);
+}
+
+#[test]
+fn test_init_racy_check_eq() {
+    let once = Once::new();
+    let value = once.init_racy_check_eq(|| {
+        once.set(vec![1]);
+        vec![1]
+    });
+    assert_eq!(*value, [1]);
+
+    let once = Once::new();
+    let message = panic_message(|| {
+        once.init_racy_check_eq(|| {
+            once.set(1);
+            2
+        });
+    });
+    assert!(message.starts_with("a racing closure initialized the Once"), "{}", message);
+    assert!(message.ends_with("winner: 1\nours: 2"), "{}", message);
+}
+
+#[cfg(parallel_queries)]
+#[test]
+fn test_init_racy_threads() {
+    let workers = if cfg!(feature = "lock-error-checking") { 1 } else { 4 };
+    let once = Once::new();
+    let (won, lost) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    in_pool(workers, || {
+        ::rayon::scope(|s| {
+            for i in 0..8 {
+                let (once, won, lost) = (&once, &won, &lost);
+                s.spawn(move |_| match once.init_racy(|| i) {
+                    RaceOutcome::Won(&value) => {
+                        assert_eq!(value, i);
+                        won.fetch_add(1, Ordering::SeqCst);
+                    }
+                    RaceOutcome::Lost { winner, yours } => {
+                        assert_eq!(yours, i);
+                        assert_ne!(*winner, i);
+                        lost.fetch_add(1, Ordering::SeqCst);
+                    }
+                    RaceOutcome::Initialized(_) => {}
+                });
+            }
+        });
+    });
+    assert_eq!(won.load(Ordering::SeqCst), 1);
+    assert!(lost.load(Ordering::SeqCst) < 8);
+}
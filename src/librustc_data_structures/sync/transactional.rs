@@ -0,0 +1,186 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A `Lock` whose value is rolled back if a transaction on it fails, for speculative passes
+//! which modify shared tables and have to undo their changes on failure.
+//!
+//! `TransactionalLock::transaction` clones the value before running the transaction and puts
+//! the clone back if the transaction returns an error or panics, before the lock is released.
+//! So other threads never see the changes of a failed transaction. Nested transactions on the
+//! same lock would deadlock, so they panic instead.
+
+use std::cell::RefCell;
+use super::Lock;
+
+thread_local! {
+    /// The addresses of the `TransactionalLock`s with a transaction on the current thread.
+    static ACTIVE: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+/// Marks a lock as having a transaction on the current thread while it is alive.
+struct Active(usize);
+
+impl Active {
+    fn enter(address: usize) -> Active {
+        ACTIVE.with(|active| {
+            let mut active = active.borrow_mut();
+            if active.contains(&address) {
+                panic!("nested transaction on a `TransactionalLock`");
+            }
+            active.push(address);
+        });
+        Active(address)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.with(|active| active.borrow_mut().retain(|&address| address != self.0));
+    }
+}
+
+/// Puts `snapshot` back into `value` when dropped, unless it was taken.
+struct Rollback<'a, T: 'a> {
+    value: &'a mut T,
+    snapshot: Option<T>,
+}
+
+impl<'a, T> Drop for Rollback<'a, T> {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            *self.value = snapshot;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TransactionalLock<T> {
+    value: Lock<T>,
+}
+
+impl<T: Clone> TransactionalLock<T> {
+    pub fn new(value: T) -> Self {
+        TransactionalLock { value: Lock::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Calls `f` with the value while holding the lock.
+    pub fn with_lock<F: FnOnce(&T) -> R, R>(&self, f: F) -> R {
+        f(&*self.value.lock())
+    }
+
+    /// Calls `f` with the value while holding the lock. If `f` returns an error or panics, the
+    /// value is rolled back to its state before the call, before the lock is released. This
+    /// clones the value once per call. Panics if the current thread is already in a transaction
+    /// on this lock.
+    pub fn transaction<F, R, E>(&self, f: F) -> Result<R, E>
+        where F: FnOnce(&mut T) -> Result<R, E>
+    {
+        let _active = Active::enter(self as *const Self as usize);
+        let mut guard = self.value.lock();
+        let snapshot = guard.clone();
+        let mut rollback = Rollback { value: &mut *guard, snapshot: Some(snapshot) };
+        let result = f(&mut *rollback.value);
+        if result.is_ok() {
+            rollback.snapshot = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn test_commit() {
+        let lock = TransactionalLock::new(vec![1]);
+        let result: Result<usize, ()> = lock.transaction(|v| {
+            v.push(2);
+            Ok(v.len())
+        });
+        assert_eq!(result, Ok(2));
+        assert_eq!(lock.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_error_rolls_back() {
+        let lock = TransactionalLock::new(vec![1]);
+        let result: Result<(), &str> = lock.transaction(|v| {
+            v.push(2);
+            v[0] = 10;
+            Err("failed")
+        });
+        assert_eq!(result, Err("failed"));
+        lock.with_lock(|v| assert_eq!(*v, [1]));
+    }
+
+    #[test]
+    fn test_panic_rolls_back() {
+        let lock = TransactionalLock::new(vec![1]);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _: Result<(), ()> = lock.transaction(|v| {
+                v.clear();
+                panic!("speculation failed");
+            });
+        }));
+        assert!(result.is_err());
+        lock.with_lock(|v| assert_eq!(*v, [1]));
+        // The lock is usable after the panic.
+        assert_eq!(lock.transaction(|v| Ok::<_, ()>(v.len())), Ok(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "nested transaction on a `TransactionalLock`")]
+    fn test_nested_transaction() {
+        let lock = TransactionalLock::new(0);
+        let _: Result<(), ()> = lock.transaction(|_| lock.transaction(|_| Ok(())));
+    }
+
+    #[test]
+    fn test_transactions_on_different_locks() {
+        let (a, b) = (TransactionalLock::new(0), TransactionalLock::new(0));
+        let result: Result<(), ()> = a.transaction(|a| {
+            *a += 1;
+            let inner: Result<(), ()> = b.transaction(|b| {
+                *b += 1;
+                Err(())
+            });
+            assert!(inner.is_err());
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!((a.into_inner(), b.into_inner()), (1, 0));
+    }
+
+    #[cfg(parallel_queries)]
+    #[test]
+    fn test_concurrent_transactions() {
+        use sync::join;
+
+        let (a, b) = (TransactionalLock::new(Vec::new()), TransactionalLock::new(Vec::new()));
+        let run = |lock: &TransactionalLock<Vec<u32>>| {
+            for i in 0..100 {
+                let _ = lock.transaction(|v| {
+                    v.push(i);
+                    if i % 2 == 0 { Ok(()) } else { Err(()) }
+                });
+            }
+        };
+        join(|| run(&a), || run(&b));
+        let expected: Vec<u32> = (0..100).filter(|i| i % 2 == 0).collect();
+        assert_eq!(a.into_inner(), expected);
+        assert_eq!(b.into_inner(), expected);
+    }
+}
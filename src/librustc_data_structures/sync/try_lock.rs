@@ -0,0 +1,115 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A common interface for the `try_*` methods of `Lock`, `RwLock` and `Once`.
+//!
+//! These return an `Option` or a `Result<_, ()>`, and `Once::try_set` returns the rejected
+//! value instead of a guard. `TryLock::try_acquire` returns `Result<Guard, Contended>` for all
+//! of them, so generic code can handle a value which isn't available without waiting in one way.
+//! The trait has a lifetime parameter since the guards borrow the lock, so generic code takes
+//! `lock: &'a L` with `L: TryLock<'a>`.
+//!
+//! `TryLockError` is the error of the `try_*_detailed` methods of the locks, which tell whether
+//! the lock is held by the current thread. Without cfg!(parallel_queries) that is always the
+//! case. With it, the `lock-error-checking` feature compares the current thread with the last
+//! acquirer of the lock, and otherwise the error is always `WouldBlock`. For a `RwLock` held by
+//! several readers the last acquirer may have already dropped its guard.
+
+use super::{Location, Lock, LockGuard, Once, RwLock, WriteGuard};
+
+/// Returned by `TryLock::try_acquire` if the value can't be acquired without waiting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Contended;
+
+/// Returned by `Lock::try_lock_detailed`, `RwLock::try_read_detailed` and
+/// `RwLock::try_write_detailed` if the lock is held.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TryLockError {
+    /// Another thread holds the lock, so trying again later may succeed.
+    WouldBlock,
+    /// The current thread holds a conflicting guard, so waiting for the lock would never
+    /// succeed and is a bug. `location` is where the lock was last acquired, which is only
+    /// known with the `lock-error-checking` feature.
+    AlreadyBorrowedByCurrentThread { location: Option<Location> },
+}
+
+pub trait TryLock<'a> {
+    type Guard: 'a;
+
+    /// Acquires the value if that is possible without waiting.
+    fn try_acquire(&'a self) -> Result<Self::Guard, Contended>;
+}
+
+/// Same as `Lock::try_lock`.
+impl<'a, T: 'a> TryLock<'a> for Lock<T> {
+    type Guard = LockGuard<'a, T>;
+
+    #[inline]
+    fn try_acquire(&'a self) -> Result<LockGuard<'a, T>, Contended> {
+        self.try_lock().ok_or(Contended)
+    }
+}
+
+/// Same as `RwLock::try_write`, since an exclusive guard is what the other types hand out.
+impl<'a, T: 'a> TryLock<'a> for RwLock<T> {
+    type Guard = WriteGuard<'a, T>;
+
+    #[inline]
+    fn try_acquire(&'a self) -> Result<WriteGuard<'a, T>, Contended> {
+        self.try_write().map_err(|()| Contended)
+    }
+}
+
+/// Same as `Once::try_get`. The value isn't available until the `Once` is initialized,
+/// possibly by another thread, so an uninitialized `Once` is `Contended`.
+impl<'a, T: 'a> TryLock<'a> for Once<T> {
+    type Guard = &'a T;
+
+    #[inline]
+    fn try_acquire(&'a self) -> Result<&'a T, Contended> {
+        self.try_get().ok_or(Contended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Deref;
+
+    /// Acquires `lock` twice while holding the first guard and returns the results.
+    fn acquire_twice<'a, L>(lock: &'a L) -> (Option<u32>, Result<(), Contended>)
+        where L: TryLock<'a>,
+              L::Guard: Deref<Target = u32>
+    {
+        match lock.try_acquire() {
+            Ok(guard) => (Some(*guard), lock.try_acquire().map(|_| ())),
+            Err(Contended) => (None, lock.try_acquire().map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_lock() {
+        assert_eq!(acquire_twice(&Lock::new(1)), (Some(1), Err(Contended)));
+    }
+
+    #[test]
+    fn test_rw_lock() {
+        assert_eq!(acquire_twice(&RwLock::new(2)), (Some(2), Err(Contended)));
+    }
+
+    #[test]
+    fn test_once() {
+        let once = Once::new();
+        assert_eq!(acquire_twice(&once), (None, Err(Contended)));
+        once.set(3);
+        // The value is shared, so it can be acquired again.
+        assert_eq!(acquire_twice(&once), (Some(3), Ok(())));
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A watchdog which reports hangs of the parallel compiler.
+//!
+//! Code which makes progress calls `heartbeat`. If `install_hang_watchdog` was called and no
+//! heartbeat arrives for a whole timeout, the watchdog writes a report describing the state of
+//! the locks and the thread pool to stderr and aborts the process. This is only done if
+//! cfg!(parallel_queries) is true, a serial compiler can't hang in this way.
+//!
+//! The watchdog watches the thread pool of the thread which installs it, or the global pool
+//! if that isn't a rayon worker. The report lists the workers of that pool with the number of
+//! their last heartbeat, so the workers which stopped making progress first stand out. With
+//! the `deadlock-detection` feature it also contains the cycles of threads which parking_lot
+//! found waiting on each other's locks, with their backtraces. Only one watchdog can run at a
+//! time; installing another one before the handler of the running one returned panics.
+
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[cfg(parallel_queries)]
+use std::ptr;
+#[cfg(parallel_queries)]
+use std::sync::Arc;
+#[cfg(parallel_queries)]
+use std::sync::atomic::{AtomicBool, AtomicPtr};
+#[cfg(parallel_queries)]
+use std::thread;
+#[cfg(parallel_queries)]
+use rayon_core::Registry;
+
+static HEARTBEATS: AtomicUsize = AtomicUsize::new(0);
+
+/// The thread pool of the running watchdog, or null. These are leaked, since `heartbeat` may
+/// still use one after its watchdog stopped.
+#[cfg(parallel_queries)]
+static WATCHED: AtomicPtr<WatchedPool> = AtomicPtr::new(ptr::null_mut());
+
+/// Whether a watchdog is running.
+#[cfg(parallel_queries)]
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(parallel_queries)]
+struct WatchedPool {
+    registry: Arc<Registry>,
+    /// The number of the last heartbeat of each worker, 0 if it sent none.
+    last_heartbeats: Vec<AtomicUsize>,
+}
+
+/// Tells the watchdog that progress is being made.
+#[inline]
+pub fn heartbeat() {
+    let beat = HEARTBEATS.fetch_add(1, Ordering::Relaxed) + 1;
+    record_worker_heartbeat(beat);
+}
+
+#[cfg(not(parallel_queries))]
+#[inline]
+fn record_worker_heartbeat(_beat: usize) {}
+
+#[cfg(parallel_queries)]
+fn record_worker_heartbeat(beat: usize) {
+    let watched = WATCHED.load(Ordering::Acquire);
+    if watched.is_null() {
+        return;
+    }
+    let watched = unsafe { &*watched };
+    if let Some(index) = ::rayon_core::current_thread_index() {
+        if Registry::current().id() == watched.registry.id() {
+            watched.last_heartbeats[index].store(beat, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A callback which receives the report of the hang watchdog.
+pub type HangHandler = Box<dyn Fn(&str) + Send>;
+
+/// Aborts the process if no heartbeat arrives for `timeout`,
+/// after printing a report describing the hang to stderr.
+pub fn install_hang_watchdog(timeout: Duration) {
+    install_hang_watchdog_with(timeout, Box::new(|report| {
+        eprintln!("{}", report);
+        process::abort();
+    }));
+}
+
+/// Same as `install_hang_watchdog`, but passes the report to `handler` instead.
+/// The watchdog stops after `handler` returns.
+#[cfg(parallel_queries)]
+pub fn install_hang_watchdog_with(timeout: Duration, handler: HangHandler) {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        panic!("a hang watchdog is already running");
+    }
+    let registry = Registry::current();
+    let watched = Box::new(WatchedPool {
+        last_heartbeats: (0..registry.num_threads()).map(|_| AtomicUsize::new(0)).collect(),
+        registry,
+    });
+    let watched: &'static WatchedPool = Box::leak(watched);
+    WATCHED.store(watched as *const _ as *mut _, Ordering::Release);
+    thread::Builder::new().name("hang watchdog".to_string()).spawn(move || {
+        let mut last = HEARTBEATS.load(Ordering::Relaxed);
+        loop {
+            thread::sleep(timeout);
+            let current = HEARTBEATS.load(Ordering::Relaxed);
+            if current == last {
+                handler(&hang_report(timeout, watched));
+                WATCHED.store(ptr::null_mut(), Ordering::Release);
+                RUNNING.store(false, Ordering::SeqCst);
+                return;
+            }
+            last = current;
+        }
+    }).expect("failed to spawn the hang watchdog thread");
+}
+
+#[cfg(not(parallel_queries))]
+pub fn install_hang_watchdog_with(_timeout: Duration, _handler: HangHandler) {}
+
+#[cfg(parallel_queries)]
+fn hang_report(timeout: Duration, watched: &WatchedPool) -> String {
+    let mut report = format!("error: the compiler appears to be hung, no progress was made \
+                              for {:?}\n",
+                             timeout);
+    report.push_str(&format!("the thread pool has {} threads:\n",
+                             watched.last_heartbeats.len()));
+    for (index, last) in watched.last_heartbeats.iter().enumerate() {
+        match last.load(Ordering::Relaxed) {
+            0 => report.push_str(&format!("  worker {}: no heartbeat\n", index)),
+            last => report.push_str(&format!("  worker {}: last heartbeat #{}\n", index, last)),
+        }
+    }
+    outstanding_guards(&mut report);
+    deadlocks(&mut report);
+    report
+}
+
+#[cfg(all(parallel_queries, feature = "sync-debug"))]
+fn outstanding_guards(report: &mut String) {
+    let guards = ::sync::dump_outstanding_guards();
+    if guards.is_empty() {
+        report.push_str("no named lock is held\n");
+    } else {
+        report.push_str("held named locks:\n");
+        report.push_str(&guards);
+    }
+}
+
+#[cfg(all(parallel_queries, not(feature = "sync-debug")))]
+fn outstanding_guards(report: &mut String) {
+    report.push_str("enable the `sync-debug` feature to list the held locks\n");
+}
+
+#[cfg(all(parallel_queries, feature = "deadlock-detection"))]
+fn deadlocks(report: &mut String) {
+    let deadlocks = ::parking_lot::deadlock::check_deadlock();
+    if deadlocks.is_empty() {
+        report.push_str("parking_lot found no deadlock\n");
+    }
+    for (i, threads) in deadlocks.iter().enumerate() {
+        report.push_str(&format!("deadlock #{} between {} threads:\n", i, threads.len()));
+        for thread in threads {
+            report.push_str(&format!("thread id {}:\n{:?}\n",
+                                     thread.thread_id(),
+                                     thread.backtrace()));
+        }
+    }
+}
+
+#[cfg(all(parallel_queries, not(feature = "deadlock-detection")))]
+fn deadlocks(report: &mut String) {
+    report.push_str("enable the `deadlock-detection` feature to search for deadlocks\n");
+}
+
+#[cfg(all(test, parallel_queries, not(feature = "lock-error-checking")))]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Barrier;
+    use std::sync::mpsc::channel;
+    use sync::Lock;
+
+    #[test]
+    fn test_hang_report() {
+        let a = Arc::new(Lock::named("test_hang_report_a", ()));
+        let b = Arc::new(Lock::named("test_hang_report_b", ()));
+        let barrier = Arc::new(Barrier::new(3));
+        let lock_both = |first: Arc<Lock<()>>, second: Arc<Lock<()>>, barrier: Arc<Barrier>| {
+            thread::spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                let _second = second.lock();
+            });
+        };
+        // These threads deadlock and are leaked.
+        lock_both(a.clone(), b.clone(), barrier.clone());
+        lock_both(b, a, barrier.clone());
+        barrier.wait();
+
+        let (sender, receiver) = channel();
+        let (release, released) = channel();
+        let pool = ::rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let index = pool.install(|| {
+            install_hang_watchdog_with(Duration::from_millis(50), Box::new(move |report| {
+                sender.send(report.to_string()).unwrap();
+                // Keep the watchdog running until the test is done.
+                released.recv().unwrap();
+            }));
+            heartbeat();
+            ::rayon_core::current_thread_index().unwrap()
+        });
+        let report = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(report.contains("appears to be hung"), "{}", report);
+        // The report describes the pool which installed the watchdog.
+        assert!(report.contains("the thread pool has 3 threads"), "{}", report);
+        assert!(report.contains(&format!("worker {}: last heartbeat #", index)), "{}", report);
+        assert!(report.contains(": no heartbeat"), "{}", report);
+        if cfg!(feature = "sync-debug") {
+            assert!(report.contains("`test_hang_report_a`"), "{}", report);
+            assert!(report.contains("`test_hang_report_b`"), "{}", report);
+        }
+        if cfg!(feature = "deadlock-detection") {
+            assert!(report.contains("deadlock #0 between 2 threads"), "{}", report);
+        }
+
+        let second = panic::catch_unwind(AssertUnwindSafe(|| {
+            install_hang_watchdog_with(Duration::from_secs(60), Box::new(|_| ()));
+        }));
+        let message = second.err().unwrap();
+        assert_eq!(message.downcast_ref::<&str>(), Some(&"a hang watchdog is already running"));
+        release.send(()).unwrap();
+    }
+}
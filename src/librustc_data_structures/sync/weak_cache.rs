@@ -0,0 +1,220 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A registry of caches holding `Weak` references, so their dead entries can be removed in one
+//! place, e.g. by the driver between crates.
+//!
+//! Caches register a sweep callback with `WeakCacheRegistry::register` and keep the returned
+//! `WeakCacheRegistration` until they are dropped. `purge_weak_caches` runs the callbacks of
+//! all live registrations and adds up their statistics. `WeakHashMap` is a map of `Weak` values
+//! which registers itself.
+//!
+//! With cfg!(parallel_queries) the registry is global. Otherwise it is local to the thread,
+//! since the callbacks use `Rc`s then.
+
+use std::hash::Hash;
+use std::ops::Add;
+use fx::FxHashMap;
+use super::{Lock, Lrc, Weak, Send, Sync};
+
+type Sweep = Lrc<dyn Fn() -> PurgeStats + Send + Sync>;
+
+cfg_if! {
+    if #[cfg(parallel_queries)] {
+        use parking_lot::Mutex;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static REGISTRY: Mutex<Vec<(usize, Sweep)>> = Mutex::new(Vec::new());
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        fn next_id() -> usize {
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        }
+
+        fn with_registry<R, F: FnOnce(&mut Vec<(usize, Sweep)>) -> R>(f: F) -> R {
+            f(&mut REGISTRY.lock())
+        }
+    } else {
+        use std::cell::{Cell, RefCell};
+
+        thread_local! {
+            static REGISTRY: RefCell<Vec<(usize, Sweep)>> = RefCell::new(Vec::new());
+            static NEXT_ID: Cell<usize> = Cell::new(0);
+        }
+
+        fn next_id() -> usize {
+            NEXT_ID.with(|id| {
+                let next = id.get();
+                id.set(next + 1);
+                next
+            })
+        }
+
+        fn with_registry<R, F: FnOnce(&mut Vec<(usize, Sweep)>) -> R>(f: F) -> R {
+            REGISTRY.with(|registry| f(&mut registry.borrow_mut()))
+        }
+    }
+}
+
+/// The result of sweeping caches.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PurgeStats {
+    /// The number of caches which were swept.
+    pub caches: usize,
+    /// The number of dead entries which were removed.
+    pub removed: usize,
+    /// The number of entries which are still alive.
+    pub live: usize,
+}
+
+impl Add for PurgeStats {
+    type Output = PurgeStats;
+
+    fn add(self, other: PurgeStats) -> PurgeStats {
+        PurgeStats {
+            caches: self.caches + other.caches,
+            removed: self.removed + other.removed,
+            live: self.live + other.live,
+        }
+    }
+}
+
+pub struct WeakCacheRegistry;
+
+impl WeakCacheRegistry {
+    /// Registers `sweep` to run in `purge_weak_caches` until the returned value is dropped.
+    /// `sweep` must not register or unregister caches.
+    pub fn register(sweep: Box<dyn Fn() -> PurgeStats + Send + Sync>) -> WeakCacheRegistration {
+        let id = next_id();
+        with_registry(|registry| registry.push((id, Lrc::from(sweep))));
+        WeakCacheRegistration { id }
+    }
+}
+
+/// Unregisters a sweep callback when dropped.
+pub struct WeakCacheRegistration {
+    id: usize,
+}
+
+impl Drop for WeakCacheRegistration {
+    fn drop(&mut self) {
+        let id = self.id;
+        with_registry(|registry| registry.retain(|&(other, _)| other != id));
+    }
+}
+
+/// Runs the sweep callbacks of all registered caches and returns the sum of their statistics.
+/// The callbacks run after the registry is unlocked, so caches can be dropped concurrently.
+pub fn purge_weak_caches() -> PurgeStats {
+    let sweeps: Vec<Sweep> = with_registry(|registry| {
+        registry.iter().map(|&(_, ref sweep)| sweep.clone()).collect()
+    });
+    sweeps.iter().fold(PurgeStats::default(), |stats, sweep| stats + sweep())
+}
+
+/// A map of `Weak` values whose dead entries are removed by `sweep` and `purge_weak_caches`.
+pub struct WeakHashMap<K, V> {
+    map: Lrc<Lock<FxHashMap<K, Weak<V>>>>,
+    _registration: WeakCacheRegistration,
+}
+
+impl<K, V> WeakHashMap<K, V>
+    where K: Eq + Hash + Send + Sync + 'static,
+          V: Send + Sync + 'static
+{
+    pub fn new() -> Self {
+        let map = Lrc::new(Lock::new(FxHashMap::default()));
+        let weak = Lrc::downgrade(&map);
+        let registration = WeakCacheRegistry::register(Box::new(move || {
+            // The map may be dropped while its callback runs.
+            match weak.upgrade() {
+                Some(map) => sweep(&map),
+                None => PurgeStats::default(),
+            }
+        }));
+        WeakHashMap {
+            map,
+            _registration: registration,
+        }
+    }
+
+    /// Maps `key` to a weak reference to `value`.
+    pub fn insert(&self, key: K, value: &Lrc<V>) {
+        self.map.lock().insert(key, Lrc::downgrade(value));
+    }
+
+    /// Returns the value of `key` if it is still alive.
+    pub fn get(&self, key: &K) -> Option<Lrc<V>> {
+        self.map.lock().get(key).and_then(|value| value.upgrade())
+    }
+
+    /// Returns the number of entries, including the dead ones which weren't swept yet.
+    pub fn len(&self) -> usize {
+        self.map.lock().len()
+    }
+
+    /// Removes the dead entries.
+    pub fn sweep(&self) -> PurgeStats {
+        sweep(&self.map)
+    }
+}
+
+fn sweep<K: Eq + Hash, V>(map: &Lock<FxHashMap<K, Weak<V>>>) -> PurgeStats {
+    let mut map = map.lock();
+    let before = map.len();
+    map.retain(|_, value| value.upgrade().is_some());
+    PurgeStats {
+        caches: 1,
+        removed: before - map.len(),
+        live: map.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This is the only test creating caches, since the registry is global with parallel_queries.
+    #[test]
+    fn test_weak_caches() {
+        let map = WeakHashMap::new();
+        let (a, b) = (Lrc::new(1), Lrc::new(2));
+        map.insert(1, &a);
+        map.insert(2, &b);
+        assert_eq!(map.get(&1), Some(a.clone()));
+        drop(b);
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.sweep(), PurgeStats { caches: 1, removed: 1, live: 1 });
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&1), Some(a));
+        drop(map);
+
+        let first = WeakHashMap::new();
+        let second = WeakHashMap::new();
+        let live = Lrc::new(0);
+        for i in 0..5 {
+            first.insert(i, &Lrc::new(i));
+        }
+        first.insert(5, &live);
+        second.insert("dead", &Lrc::new(1));
+        second.insert("live", &live);
+
+        let stats = purge_weak_caches();
+        assert_eq!(stats, PurgeStats { caches: 2, removed: 6, live: 2 });
+        assert_eq!((first.len(), second.len()), (1, 1));
+        assert_eq!(first.get(&5), Some(live.clone()));
+        assert_eq!(second.get(&"live"), Some(live.clone()));
+
+        // Dropped caches are unregistered.
+        drop(first);
+        assert_eq!(purge_weak_caches(), PurgeStats { caches: 1, removed: 0, live: 1 });
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// With parallel queries `MaybeSend` and `MaybeSync` require the traits of `std`.
+// only-parallel-queries
+
+#![feature(rustc_private)]
+
+extern crate rustc_data_structures;
+
+use rustc_data_structures::sync::{MaybeSend, MaybeSync};
+use std::cell::Cell;
+use std::rc::Rc;
+
+fn send<T: MaybeSend>(_: T) {}
+fn sync<T: MaybeSync>(_: &T) {}
+
+fn main() {
+    send(Rc::new(1u32));
+    //~^ ERROR `std::rc::Rc<u32>` cannot be sent between threads safely
+    sync(&Cell::new(1u32));
+    //~^ ERROR `std::cell::Cell<u32>` cannot be shared between threads safely
+    send(vec![1u32]);
+    sync(&vec![1u32]);
+}
@@ -0,0 +1,35 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Without parallel queries every type is `MaybeSend` and `MaybeSync`.
+// compile-pass
+// ignore-parallel-queries
+
+#![feature(rustc_private)]
+
+extern crate rustc_data_structures;
+
+use rustc_data_structures::sync::{MaybeSend, MaybeSync, join, par_map_in_place};
+use std::cell::Cell;
+use std::rc::Rc;
+
+fn send<T: MaybeSend>(_: T) {}
+fn sync<T: MaybeSync>(_: &T) {}
+
+fn main() {
+    send(Rc::new(1u32));
+    sync(&Cell::new(1u32));
+
+    let counter = Cell::new(0);
+    par_map_in_place(&mut [Rc::new(1), Rc::new(2)], |item| counter.set(counter.get() + **item));
+    assert_eq!(counter.get(), 3);
+    join(|| counter.set(4), || counter.set(5));
+    assert_eq!(counter.get(), 5);
+}
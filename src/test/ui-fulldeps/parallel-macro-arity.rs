@@ -0,0 +1,25 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// `parallel!` takes at most 8 branches.
+
+#![feature(rustc_private)]
+
+#[macro_use]
+extern crate rustc_data_structures;
+
+fn too_many() {
+    parallel! {
+    //~^ ERROR parallel! takes 1 to 8 expressions
+        1; 2; 3; 4; 5; 6; 7; 8; 9;
+    }
+}
+
+fn main() {}
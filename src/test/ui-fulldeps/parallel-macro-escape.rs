@@ -0,0 +1,49 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The branches of `parallel!` can't leave the macro invocation.
+
+#![feature(rustc_private)]
+#![allow(unreachable_code)]
+
+#[macro_use]
+extern crate rustc_data_structures;
+
+fn phase() -> Result<(), ()> {
+    Ok(())
+}
+
+fn early_return() {
+    parallel! {
+        phase().unwrap();
+        return;
+        //~^ ERROR `return;` in a function whose return type is not `()`
+    }
+}
+
+fn question_mark() -> Result<(), ()> {
+    parallel! {
+        phase()?;
+        //~^ ERROR the `?` operator can only be used
+        phase().unwrap();
+    }
+    Ok(())
+}
+
+fn break_loop() {
+    loop {
+        parallel! {
+            break;
+            //~^ ERROR `break` inside of a closure
+        }
+    }
+}
+
+fn main() {}
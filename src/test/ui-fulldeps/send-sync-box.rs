@@ -0,0 +1,26 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Without parallel queries every type is `Send` and `Sync`, so this can only fail with them.
+// only-parallel-queries
+
+#![feature(rustc_private)]
+
+extern crate rustc_data_structures;
+
+use rustc_data_structures::sync::SendSyncBox;
+use std::any::Any;
+use std::rc::Rc;
+
+fn main() {
+    let _payload: SendSyncBox<dyn Any> = SendSyncBox::new(Rc::new(1u32));
+    //~^ ERROR `std::rc::Rc<u32>` cannot be sent between threads safely
+    //~| ERROR `std::rc::Rc<u32>` cannot be shared between threads safely
+}
@@ -0,0 +1,29 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Without parallel queries every type is `Send` and `Sync`, so these can only fail with them.
+// only-parallel-queries
+
+#![feature(rustc_private)]
+
+#[macro_use]
+extern crate rustc_data_structures;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+static_assert_sync!(Cell<u32>);
+//~^ ERROR `std::cell::Cell<u32>` cannot be shared between threads safely
+static_assert_send!(Rc<u32>);
+//~^ ERROR `std::rc::Rc<u32>` cannot be sent between threads safely
+static_assert_sync!(Vec<u32>);
+static_assert_send!(Cell<u32>);
+
+fn main() {}
@@ -214,6 +214,9 @@ pub struct Config {
     /// Is LLVM a system LLVM
     pub system_llvm: bool,
 
+    /// Was rustc built with `--cfg parallel_queries`
+    pub parallel_queries: bool,
+
     /// Path to the android tools
     pub android_cross_path: PathBuf,
 